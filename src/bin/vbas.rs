@@ -0,0 +1,61 @@
+#![warn(clippy::all)]
+
+use hewwo::cpu::instrs::assembler;
+use std::path::Path;
+
+/*
+    vbas: V810 assembler producing Virtual Boy ROM images.
+
+    Usage: vbas <source file> [-o <output file>]
+
+    The output defaults to the source file with a .vb extension.
+    See src/cpu/instrs/assembler.rs for the syntax.
+*/
+
+fn parse_args() -> Result<(String, String), String> {
+    let mut args = std::env::args().skip(1);
+    let mut source = None;
+    let mut output = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().ok_or("-o expects a file name")?),
+            _ if source.is_none() => source = Some(arg),
+            _ => return Err(format!("unexpected argument \"{}\"", arg)),
+        }
+    }
+
+    let source = source.ok_or("no source file given")?;
+    let output = output.unwrap_or_else(|| Path::new(&source).with_extension("vb").to_string_lossy().into_owned());
+
+    Ok((source, output))
+}
+
+fn run(source_path: &str, output_path: &str) -> Result<(), String> {
+    let source = std::fs::read_to_string(source_path).map_err(|error| format!("couldn't read {}: {}", source_path, error))?;
+
+    let rom = assembler::assemble(&source)
+        .and_then(|assembly| assembly.to_rom())
+        .map_err(|error| match error.line {
+            Some(line) => format!("{}:{}: {}", source_path, line, error.message),
+            None => format!("{}: {}", source_path, error.message),
+        })?;
+
+    std::fs::write(output_path, rom).map_err(|error| format!("couldn't write {}: {}", output_path, error))
+}
+
+fn main() {
+    let (source, output) = match parse_args() {
+        Ok(paths) => paths,
+        Err(error) => {
+            eprintln!("vbas: {}", error);
+            eprintln!("Usage: vbas <source file> [-o <output file>]");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(error) = run(&source, &output) {
+        eprintln!("vbas: {}", error);
+        std::process::exit(1);
+    }
+}
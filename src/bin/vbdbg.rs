@@ -0,0 +1,827 @@
+#![warn(clippy::all)]
+
+use hewwo::bus::{is_readable, is_writable, Bus};
+use hewwo::cpu::instrs::decoder::{self, Instr};
+use hewwo::cpu::instrs::disassembler::{self, Syntax};
+use hewwo::cpu::instrs::system_regs;
+use hewwo::symbols::Symbols;
+use hewwo::{Stop, VirtualBoy};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::panic;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/*
+    vbdbg: interactive terminal debugger.
+
+    Usage: vbdbg <ROM> [--symbols FILE] [--break LOCATION]...
+
+    Shows the disassembly around the PC, the registers with the PSW flags decoded, a hex view of memory and the breakpoints.
+    Tab switches between the disassembly, register and memory panes, and the arrow and page keys move the cursor in the active one.
+
+    s: step                 n: step over            f: run until return     c: continue
+    r: run to the cursor    b: toggle a breakpoint at the cursor            B: add a breakpoint at an address or symbol
+    g: go to an address or symbol
+    Enter: edit the register or memory under the cursor in place, typing hex digits. Enter again applies, Esc cancels
+    Esc: pause while running                        q: quit
+
+    The ROM can be a raw image or an ELF file, whose symbols get used unless --symbols is given.
+    With --symbols, names from an ELF or "address name" file (see src/symbols.rs) show up in the disassembly, breakpoints and stops,
+    and can be typed wherever an address is asked for, optionally followed by a hex offset ("update+0x1C").
+    --break adds a breakpoint before starting, and can be given several times.
+
+    Needs a Unix terminal (it's set up with stty) of at least 80x30.
+*/
+
+const USAGE: &str = "Usage: vbdbg <ROM> [--symbols FILE] [--break LOCATION]...";
+
+const DISASM_ROWS: usize = 16;
+const DISASM_WIDTH: usize = 48;
+const RUN_CHUNK: u64 = 200_000; // Cycles run between checks for keys while running
+const STEP_BUDGET: u64 = 20_000_000; // Cycles step over and run until return can take before giving up, 1 emulated second
+const REDRAW_INTERVAL: Duration = Duration::from_millis(50);
+
+// System registers that can be edited, in the order they're selected in
+const SYSTEM_REGS: [u16; 10] = [
+    system_regs::PSW, system_regs::EIPC, system_regs::EIPSW, system_regs::FEPC, system_regs::FEPSW,
+    system_regs::ECR, system_regs::CHCW, system_regs::ADTRE, system_regs::SR29, system_regs::SR31,
+];
+
+thread_local! {
+    // Message of the last panic, printed once the terminal is restored instead of over the UI
+    static PANIC_MESSAGE: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+// Puts the terminal in raw mode on the alternate screen, and restores it when dropped
+struct Terminal {
+    saved: String,
+}
+
+impl Terminal {
+    fn new() -> Result<Terminal, String> {
+        let saved = stty(&["-g"])?;
+        stty(&["-icanon", "-echo", "-isig", "min", "0", "time", "0"])?;
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush().map_err(|error| error.to_string())?;
+        Ok(Terminal { saved })
+    }
+
+    // Rows and columns. This runs stty, so it's only queried again after key presses rather than on every redraw
+    fn size() -> (usize, usize) {
+        let size = stty(&["size"]).unwrap_or_default();
+        let mut fields = size.split_whitespace().map(|field| field.parse().unwrap_or(0));
+        match (fields.next(), fields.next()) {
+            (Some(rows), Some(cols)) if rows != 0 && cols != 0 => (rows, cols),
+            _ => (30, 80),
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> Result<String, String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output().map_err(|error| format!("couldn't run stty: {}", error))?;
+    if !output.status.success() {
+        return Err("couldn't set up the terminal. Is this running in one?".to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Tab,
+    Enter,
+    Backspace,
+    Esc,
+}
+
+// Keys pressed since the last call. Doesn't block
+fn read_keys() -> Vec<Key> {
+    let mut buf = [0; 64];
+    let len = io::stdin().read(&mut buf).unwrap_or(0);
+    parse_keys(&buf[..len])
+}
+
+// Keys typed as "bytes", skipping escape sequences for keys the debugger doesn't use
+fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut bytes = bytes.iter().copied().peekable();
+    let mut keys = vec![];
+
+    while let Some(byte) = bytes.next() {
+        let key = match byte {
+            0x1B if bytes.peek() == Some(&b'[') => {
+                bytes.next();
+                match bytes.next() {
+                    Some(b'A') => Key::Up,
+                    Some(b'B') => Key::Down,
+                    Some(b'C') => Key::Right,
+                    Some(b'D') => Key::Left,
+                    Some(b'5') if bytes.next() == Some(b'~') => Key::PageUp,
+                    Some(b'6') if bytes.next() == Some(b'~') => Key::PageDown,
+                    _ => continue,
+                }
+            }
+            0x1B | 0x03 => Key::Esc, // Ctrl-C acts like Esc, as signals are off
+            b'\t' => Key::Tab,
+            b'\r' | b'\n' => Key::Enter,
+            0x7F | 0x08 => Key::Backspace,
+            0x20..=0x7E => Key::Char(byte as char),
+            _ => continue,
+        };
+        keys.push(key);
+    }
+
+    keys
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Pane {
+    Disassembly,
+    Registers,
+    Memory,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Reg {
+    Gpr(usize),
+    Pc,
+    System(u16),
+}
+
+impl Reg {
+    fn name(self) -> String {
+        match self {
+            Reg::Gpr(index) => format!("r{}", index),
+            Reg::Pc => "pc".to_string(),
+            Reg::System(id) => system_regs::name(id).map_or_else(|| format!("sr{}", id), str::to_string),
+        }
+    }
+
+    fn get(self, vb: &VirtualBoy) -> u32 {
+        let cpu = vb.cpu();
+        match self {
+            Reg::Gpr(index) => cpu.regs.gprs[index],
+            Reg::Pc => cpu.regs.pc,
+            Reg::System(id) => cpu.system_reg(id),
+        }
+    }
+
+    fn set(self, vb: &mut VirtualBoy, val: u32) {
+        let cpu = vb.cpu_mut();
+        match self {
+            Reg::Gpr(index) => cpu.regs.gprs[index] = val,
+            Reg::Pc => cpu.regs.pc = val & !1,
+            Reg::System(id) => cpu.set_system_reg(id, val),
+        }
+    }
+}
+
+// Registers in selection order. r0 is always 0, so it can't be selected
+fn editable_regs() -> Vec<Reg> {
+    (1..32).map(Reg::Gpr).chain(Some(Reg::Pc)).chain(SYSTEM_REGS.iter().map(|&id| Reg::System(id))).collect()
+}
+
+// What an address typed at the prompt is for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Prompt {
+    Goto,
+    Breakpoint,
+}
+
+// What's being run in the background, a chunk at a time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Running {
+    Continue,
+    ToCursor { addr: u32, temp_breakpoint: bool }, // Whether the breakpoint at "addr" was added just for this, and has to be removed
+}
+
+struct App {
+    vb: VirtualBoy,
+    rom: String,
+    pane: Pane,
+    cursor: u32, // Disassembly cursor address
+    top: u32,    // First address shown in the disassembly
+    regs: Vec<Reg>,
+    selected_reg: usize,
+    mem_cursor: u32,
+    mem_top: u32,
+    mem_rows: usize,
+    edit: Option<String>,   // Text typed so far while editing a register or memory in place
+    prompt: Option<(Prompt, String)>, // Text typed so far at the address prompt
+    running: Option<Running>,
+    size: (usize, usize), // Terminal rows and columns
+    cycles: u64, // Total cycles run
+    status: String,
+    quit: bool,
+}
+
+impl App {
+    fn new(vb: VirtualBoy, rom: String) -> App {
+        let pc = vb.cpu().regs.pc;
+        App {
+            vb,
+            rom,
+            pane: Pane::Disassembly,
+            cursor: pc,
+            top: pc,
+            regs: editable_regs(),
+            selected_reg: 0,
+            mem_cursor: 0x05000000,
+            mem_top: 0x05000000,
+            mem_rows: 6,
+            edit: None,
+            prompt: None,
+            running: None,
+            size: Terminal::size(),
+            cycles: 0,
+            status: "Press s to step, c to continue, q to quit".to_string(),
+            quit: false,
+        }
+    }
+
+    fn handle_key(&mut self, key: Key) {
+        if key == Key::Char('q') && self.edit.is_none() && self.prompt.is_none() {
+            self.quit = true;
+        } else if self.prompt.is_some() {
+            self.prompt_key(key);
+        } else if self.edit.is_some() {
+            self.edit_key(key);
+        } else if self.running.is_some() {
+            if key == Key::Esc {
+                self.finish_run();
+                self.status = "Paused".to_string();
+                self.follow_pc();
+            }
+        } else {
+            self.command_key(key);
+        }
+    }
+
+    fn command_key(&mut self, key: Key) {
+        match key {
+            Key::Tab => {
+                self.pane = match self.pane {
+                    Pane::Disassembly => Pane::Registers,
+                    Pane::Registers => Pane::Memory,
+                    Pane::Memory => Pane::Disassembly,
+                }
+            }
+
+            Key::Char('s') => self.step(|vb| vb.step_into()),
+            Key::Char('n') => self.step(|vb| vb.step_over(STEP_BUDGET)),
+            Key::Char('f') => self.step(|vb| vb.run_until_return(STEP_BUDGET)),
+            Key::Char('c') => {
+                self.running = Some(Running::Continue);
+                self.status = "Running, Esc to pause".to_string();
+            }
+            Key::Char('r') => {
+                let temp_breakpoint = self.vb.add_breakpoint(self.cursor);
+                self.running = Some(Running::ToCursor { addr: self.cursor, temp_breakpoint });
+                self.status = format!("Running to {:08X}, Esc to pause", self.cursor);
+            }
+            Key::Char('b') => self.toggle_breakpoint(),
+            Key::Char('B') => self.prompt = Some((Prompt::Breakpoint, String::new())),
+            Key::Char('g') => self.prompt = Some((Prompt::Goto, String::new())),
+            Key::Enter if self.pane != Pane::Disassembly => self.edit = Some(String::new()),
+
+            Key::Up | Key::Down | Key::Left | Key::Right | Key::PageUp | Key::PageDown => self.move_cursor(key),
+            _ => {}
+        }
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        if !self.vb.remove_breakpoint(self.cursor) {
+            self.vb.add_breakpoint(self.cursor);
+        }
+    }
+
+    fn move_cursor(&mut self, key: Key) {
+        match self.pane {
+            Pane::Disassembly => {
+                self.cursor = match key {
+                    Key::Up => instr_before(self.vb.bus(), self.cursor, 1),
+                    Key::Down => self.cursor.wrapping_add(instr_length(self.vb.bus(), self.cursor)),
+                    Key::PageUp => instr_before(self.vb.bus(), self.cursor, DISASM_ROWS),
+                    Key::PageDown => (0..DISASM_ROWS).fold(self.cursor, |addr, _| addr.wrapping_add(instr_length(self.vb.bus(), addr))),
+                    _ => self.cursor,
+                };
+            }
+
+            Pane::Registers => {
+                let last = self.regs.len() - 1;
+                self.selected_reg = match key {
+                    Key::Up => self.selected_reg.saturating_sub(1),
+                    Key::Down => (self.selected_reg + 1).min(last),
+                    Key::Left | Key::PageUp => self.selected_reg.saturating_sub(16),
+                    Key::Right | Key::PageDown => (self.selected_reg + 16).min(last),
+                    _ => self.selected_reg,
+                };
+            }
+
+            Pane::Memory => {
+                let page = 16 * self.mem_rows as u32;
+                self.mem_cursor = match key {
+                    Key::Up => self.mem_cursor.wrapping_sub(16),
+                    Key::Down => self.mem_cursor.wrapping_add(16),
+                    Key::Left => self.mem_cursor.wrapping_sub(1),
+                    Key::Right => self.mem_cursor.wrapping_add(1),
+                    Key::PageUp => self.mem_cursor.wrapping_sub(page),
+                    Key::PageDown => self.mem_cursor.wrapping_add(page),
+                    _ => self.mem_cursor,
+                };
+            }
+        }
+    }
+
+    fn prompt_key(&mut self, key: Key) {
+        let (prompt, text) = self.prompt.as_mut().unwrap();
+        let prompt = *prompt;
+        match key {
+            Key::Char(c) if c != ' ' => text.push(c),
+            Key::Backspace => {
+                text.pop();
+            }
+            Key::Esc => self.prompt = None,
+            Key::Enter => {
+                let text = self.prompt.take().unwrap().1;
+                match (self.vb.symbols().resolve(&text), prompt) {
+                    (Ok(addr), Prompt::Goto) if self.pane == Pane::Memory => self.mem_cursor = addr,
+                    (Ok(addr), Prompt::Goto) => {
+                        self.cursor = addr & !1;
+                        self.pane = Pane::Disassembly;
+                    }
+                    (Ok(addr), Prompt::Breakpoint) => {
+                        self.vb.add_breakpoint(addr);
+                        self.status = format!("Breakpoint added at {}", self.describe(addr));
+                    }
+                    (Err(error), _) => self.status = error,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Registers take up to 8 hex digits, memory bytes 2
+    fn edit_key(&mut self, key: Key) {
+        let max_len = if self.pane == Pane::Memory { 2 } else { 8 };
+        let text = self.edit.as_mut().unwrap();
+        match key {
+            Key::Char(c) if c.is_ascii_hexdigit() && text.len() < max_len => text.push(c.to_ascii_uppercase()),
+            Key::Backspace => {
+                text.pop();
+            }
+            Key::Esc => self.edit = None,
+            Key::Enter => {
+                let text = self.edit.take().unwrap();
+                if let Ok(val) = u32::from_str_radix(&text, 16) {
+                    self.apply_edit(val);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn apply_edit(&mut self, val: u32) {
+        if self.pane == Pane::Registers {
+            let reg = self.regs[self.selected_reg];
+            reg.set(&mut self.vb, val);
+            if reg == Reg::Pc {
+                self.follow_pc();
+            }
+            return;
+        }
+
+        let addr = self.mem_cursor;
+        if is_writable(addr) {
+            self.vb.bus_mut().write8(addr, val as u8);
+            self.mem_cursor = addr.wrapping_add(1);
+        } else {
+            self.status = format!("Can't write to {:08X}, only VIP, VSU, misc hardware and WRAM memory are writable", addr);
+        }
+    }
+
+    // Run something that stops on its own, like a step
+    fn step(&mut self, run: impl FnOnce(&mut VirtualBoy) -> (u64, Stop)) {
+        let (cycles, stop) = run(&mut self.vb);
+        self.cycles += cycles;
+        self.stopped(stop);
+        self.follow_pc();
+    }
+
+    // Run the next chunk of a continue or a run to the cursor
+    fn run_chunk(&mut self) {
+        match self.vb.debug_run(RUN_CHUNK) {
+            (cycles, Stop::OutOfCycles) => self.cycles += cycles,
+            (cycles, stop) => {
+                self.cycles += cycles;
+                let to_cursor = matches!(self.running, Some(Running::ToCursor { addr, .. }) if stop == Stop::Breakpoint(addr));
+                self.finish_run();
+                self.stopped(stop);
+                if to_cursor {
+                    self.status = String::new();
+                }
+                self.follow_pc();
+            }
+        }
+    }
+
+    fn finish_run(&mut self) {
+        if let Some(Running::ToCursor { addr, temp_breakpoint: true }) = self.running {
+            self.vb.remove_breakpoint(addr);
+        }
+        self.running = None;
+    }
+
+    fn stopped(&mut self, stop: Stop) {
+        self.status = match stop {
+            Stop::Breakpoint(addr) => format!("Breakpoint at {}", self.describe(addr)),
+            Stop::Watchpoint(hit) => {
+                let access = if hit.write { "Write" } else { "Read" };
+                format!("Watchpoint: {} of {:0width$X} at {:08X}", access, hit.value, hit.addr, width = hit.size as usize * 2)
+            }
+            Stop::Done => match self.vb.symbols().describe(self.vb.cpu().regs.pc) {
+                Some(symbol) => format!("In {}", symbol),
+                None => String::new(),
+            },
+            Stop::IllegalOpcode(addr) => format!("Illegal opcode at {}", self.describe(addr)),
+            Stop::OutOfCycles => format!("Still going after {} cycles, stopped at {:08X}", STEP_BUDGET, self.vb.cpu().regs.pc),
+        };
+    }
+
+    // "07000024 (fact+0x4)", or just the address if it has no symbol
+    fn describe(&self, addr: u32) -> String {
+        match self.vb.symbols().describe(addr) {
+            Some(symbol) => format!("{:08X} ({})", addr, symbol),
+            None => format!("{:08X}", addr),
+        }
+    }
+
+    fn follow_pc(&mut self) {
+        self.cursor = self.vb.cpu().regs.pc;
+    }
+
+    // Addresses of the instructions shown, starting from "top"
+    fn disasm_addrs(&self) -> Vec<u32> {
+        let bus = self.vb.bus();
+        let mut addr = self.top;
+        (0..DISASM_ROWS)
+            .map(|_| {
+                let current = addr;
+                addr = addr.wrapping_add(instr_length(bus, addr));
+                current
+            })
+            .collect()
+    }
+
+    // Scroll the disassembly and memory views so that their cursors are visible
+    fn scroll(&mut self) {
+        let addrs = self.disasm_addrs();
+        if !addrs[..DISASM_ROWS - 1].contains(&self.cursor) {
+            self.top = instr_before(self.vb.bus(), self.cursor, 4);
+        }
+
+        let row = self.mem_cursor & !15;
+        let rows = self.mem_rows as u32;
+        if row.wrapping_sub(self.mem_top) >= rows * 16 {
+            self.mem_top = if row.wrapping_sub(self.mem_top) as i32 > 0 { row.wrapping_sub((rows - 1) * 16) } else { row };
+        }
+    }
+
+    fn draw(&mut self) {
+        let (rows, cols) = self.size;
+        self.mem_rows = rows.saturating_sub(24).max(2);
+        self.scroll();
+
+        let mut lines = vec![];
+
+        let state = if self.running.is_some() { "running" } else { "paused" };
+        let pane = match self.pane {
+            Pane::Disassembly => "Disassembly",
+            Pane::Registers => "Registers",
+            Pane::Memory => "Memory",
+        };
+        let title = format!(" vbdbg  {}  [{}]  cycles: {}  pane: {}", self.rom, state, self.cycles, pane);
+        lines.push(reverse(&pad(&title, cols)));
+
+        let disasm = self.disasm_lines();
+        for (row, disasm) in disasm.iter().enumerate() {
+            let gprs = format!("{}  {}", self.reg_cell(Reg::Gpr(row), 3), self.reg_cell(Reg::Gpr(row + 16), 3));
+            lines.push(format!("{}  {}", disasm, gprs));
+        }
+
+        lines.push(self.psw_line());
+        lines.push([0, 1, 2, 3, 4].iter().map(|&i| self.reg_cell(Reg::System(SYSTEM_REGS[i + 1]), 0)).collect::<Vec<_>>().join("  "));
+        lines.push([6, 7, 8, 9].iter().map(|&i| self.reg_cell(Reg::System(SYSTEM_REGS[i]), 0)).collect::<Vec<_>>().join("  "));
+
+        lines.push(reverse(&pad(" Memory", cols)));
+        for row in 0..self.mem_rows {
+            lines.push(self.memory_line(self.mem_top.wrapping_add(row as u32 * 16)));
+        }
+
+        let breakpoints: Vec<String> = self.vb.breakpoints().map(|addr| self.describe(addr)).collect();
+        let breakpoints = if breakpoints.is_empty() { "none".to_string() } else { breakpoints.join(" ") };
+        lines.push(pad(&format!("Breakpoints: {}", breakpoints), cols));
+        lines.push(pad(&self.status, cols));
+
+        let help = match &self.prompt {
+            Some((Prompt::Goto, text)) => format!("Go to address or symbol: {}_", text),
+            Some((Prompt::Breakpoint, text)) => format!("Breakpoint at address or symbol: {}_", text),
+            None if self.edit.is_some() => "Type hex digits, Enter: apply  Esc: cancel".to_string(),
+            None => "s step n over f out c run r to cursor b/B bkpt g goto Enter edit Tab pane q quit".to_string(),
+        };
+        lines.push(reverse(&pad(&help, cols)));
+
+        let mut out = String::from("\x1b[H");
+        for line in lines.iter().take(rows) {
+            out += line;
+            out += "\x1b[K\r\n";
+        }
+        out += "\x1b[J";
+
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(out.trim_end_matches("\r\n").as_bytes());
+        let _ = stdout.flush();
+    }
+
+    fn disasm_lines(&self) -> Vec<String> {
+        let bus = self.vb.bus();
+        let pc = self.vb.cpu().regs.pc;
+        let breakpoints: Vec<u32> = self.vb.breakpoints().collect();
+
+        self.disasm_addrs()
+            .into_iter()
+            .map(|addr| {
+                let text = match decode(bus, addr) {
+                    Some(instr) => {
+                        let raw = if instr.len == 4 {
+                            format!("{:04X} {:04X}", instr.raw >> 16, instr.raw & 0xFFFF)
+                        } else {
+                            format!("{:04X}", instr.raw)
+                        };
+                        format!("{:<9}  {}", raw, disassembler::disassemble(&instr, addr).render_with_symbols(Syntax::Nec, self.vb.symbols()))
+                    }
+                    None => "????".to_string(),
+                };
+
+                let pc_mark = if addr == pc { '>' } else { ' ' };
+                let breakpoint_mark = if breakpoints.contains(&addr) { '*' } else { ' ' };
+                let line = pad(&format!("{}{} {:08X}  {}", pc_mark, breakpoint_mark, addr, text), DISASM_WIDTH);
+
+                if addr == self.cursor && self.pane == Pane::Disassembly { reverse(&line) } else { line }
+            })
+            .collect()
+    }
+
+    // "name value", with the name padded to "width". Highlighted if selected, or showing the value being typed in
+    fn reg_cell(&self, reg: Reg, width: usize) -> String {
+        let selected = self.pane == Pane::Registers && self.regs[self.selected_reg] == reg;
+        let value = match &self.edit {
+            Some(text) if selected => format!("{:<8}", format!("{}_", text)),
+            _ => format!("{:08X}", reg.get(&self.vb)),
+        };
+
+        let name = reg.name().to_uppercase();
+        let value = if selected { reverse(&value) } else { value };
+        format!("{:<width$} {}", name, value, width = width)
+    }
+
+    fn psw_line(&self) -> String {
+        let psw = self.vb.cpu().regs.psw;
+        let flags = [
+            ("Z", psw.zero()), ("S", psw.sign()), ("OV", psw.overflow()), ("CY", psw.carry()),
+            ("FPR", psw.fpr()), ("FUD", psw.fud()), ("FOV", psw.fov()), ("FZD", psw.fzd()), ("FIV", psw.fiv()), ("FRO", psw.fro()),
+            ("ID", psw.irqs_disabled()), ("AE", psw.addr_trap_enabled()), ("EP", psw.exception_pending()), ("NP", psw.nmi_pending()),
+        ];
+
+        // Clear flags are dimmed
+        let flags: Vec<String> = flags.iter().map(|&(name, set)| if set { name.to_string() } else { format!("\x1b[2m{}\x1b[0m", name) }).collect();
+        format!("{}  {}  {}  I={}", self.reg_cell(Reg::Pc, 0), self.reg_cell(Reg::System(system_regs::PSW), 0), flags.join(" "), psw.i())
+    }
+
+    fn memory_line(&self, addr: u32) -> String {
+        let bus = self.vb.bus();
+        let mut hex = String::new();
+        let mut ascii = String::new();
+
+        for offset in 0..16 {
+            let addr = addr.wrapping_add(offset);
+            let byte = if is_readable(addr) { Some(bus.peek8(addr)) } else { None }; // Open bus is shown as unknown
+
+            let mut cell = byte.map_or_else(|| "??".to_string(), |byte| format!("{:02X}", byte));
+            if self.pane == Pane::Memory && addr == self.mem_cursor {
+                if let Some(text) = &self.edit {
+                    cell = format!("{:_<2}", text);
+                }
+                cell = reverse(&cell);
+            }
+
+            hex += &cell;
+            hex.push(if offset == 7 { '-' } else { ' ' });
+            ascii.push(match byte {
+                Some(byte @ 0x20..=0x7E) => byte as char,
+                _ => '.',
+            });
+        }
+
+        format!("{:08X}  {} {}", addr, hex, ascii)
+    }
+}
+
+// Decode the instruction at "addr", unless it's in a region that can't be read
+fn decode(bus: &Bus, addr: u32) -> Option<Instr> {
+    if !is_readable(addr) {
+        return None;
+    }
+
+    let first = bus.peek16(addr);
+    let second = match decoder::instr_length(first) {
+        4 if is_readable(addr.wrapping_add(2)) => bus.peek16(addr.wrapping_add(2)),
+        _ => 0,
+    };
+    Some(decoder::decode(first, second))
+}
+
+fn instr_length(bus: &Bus, addr: u32) -> u32 {
+    decode(bus, addr).map_or(2, |instr| instr.len)
+}
+
+// Address of the instruction "count" instructions before "addr". Instructions can be 2 or 4 bytes long, so this looks for
+// the furthest starting point from which decoding lines up with "addr"
+fn instr_before(bus: &Bus, addr: u32, count: usize) -> u32 {
+    let max_distance = count as u32 * 4;
+
+    for distance in (2..=max_distance).rev().step_by(2) {
+        let mut addrs = vec![];
+        let mut current = addr.wrapping_sub(distance);
+        while addr.wrapping_sub(current) <= distance && current != addr {
+            addrs.push(current);
+            current = current.wrapping_add(instr_length(bus, current));
+        }
+
+        if current == addr && addrs.len() >= count {
+            return addrs[addrs.len() - count];
+        }
+    }
+
+    addr.wrapping_sub(count as u32 * 2)
+}
+
+fn pad(text: &str, width: usize) -> String {
+    format!("{:<width$.width$}", text, width = width)
+}
+
+fn reverse(text: &str) -> String {
+    format!("\x1b[7m{}\x1b[0m", text)
+}
+
+struct Options {
+    rom: String,
+    symbols: Option<String>,
+    breakpoints: Vec<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut rom = None;
+    let mut symbols = None;
+    let mut breakpoints = vec![];
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbols" => symbols = Some(args.next().ok_or("--symbols expects a file")?),
+            "--break" => breakpoints.push(args.next().ok_or("--break expects an address or symbol")?),
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("unexpected argument \"{}\"", arg)),
+        }
+    }
+
+    Ok(Options { rom: rom.ok_or("no ROM given")?, symbols, breakpoints })
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let rom = &options.rom;
+    let mut vb = VirtualBoy::load(rom).map_err(|error| format!("{}: {}", rom, error))?;
+    if let Some(path) = &options.symbols {
+        vb.set_symbols(Symbols::load(path)?);
+    }
+    for location in &options.breakpoints {
+        vb.add_breakpoint_at(location).map_err(|error| format!("--break {}: {}", location, error))?;
+    }
+
+    let rom_name = std::path::Path::new(rom).file_name().map_or_else(|| rom.to_string(), |name| name.to_string_lossy().into_owned());
+    let mut app = App::new(vb, rom_name);
+
+    let _terminal = Terminal::new()?;
+    let mut last_draw: Option<Instant> = None;
+
+    while !app.quit {
+        let keys = read_keys();
+        if !keys.is_empty() {
+            app.size = Terminal::size();
+        }
+        for &key in &keys {
+            app.handle_key(key);
+        }
+
+        if app.running.is_some() {
+            app.run_chunk();
+        } else if keys.is_empty() && last_draw.is_some() {
+            std::thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+
+        if last_draw.map_or(true, |time| time.elapsed() >= REDRAW_INTERVAL) || app.running.is_none() {
+            app.draw();
+            last_draw = Some(Instant::now());
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("vbdbg: {}", error);
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    panic::set_hook(Box::new(|info| {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => info.payload().downcast_ref::<String>().cloned().unwrap_or_default(),
+        };
+        PANIC_MESSAGE.with(|last| *last.borrow_mut() = message);
+    }));
+
+    // The terminal gets restored before reporting errors and panics
+    match panic::catch_unwind(|| run(&options)) {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => {
+            eprintln!("vbdbg: {}", error);
+            std::process::exit(1);
+        }
+        Err(_) => {
+            eprintln!("vbdbg: panicked: {}", PANIC_MESSAGE.with(|message| message.borrow().clone()));
+            std::process::exit(101);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hewwo::cpu::instrs::opcodes;
+    use hewwo::mem::Memory;
+
+    #[test]
+    fn parse_keys() {
+        assert_eq!(
+            super::parse_keys(b"\x1b[A\x1b[B\x1b[C\x1b[D\x1b[5~\x1b[6~"),
+            [Key::Up, Key::Down, Key::Right, Key::Left, Key::PageUp, Key::PageDown]
+        );
+        assert_eq!(super::parse_keys(b"s\t\r\n\x7f\x08"), [Key::Char('s'), Key::Tab, Key::Enter, Key::Enter, Key::Backspace, Key::Backspace]);
+        assert_eq!(super::parse_keys(b"\x1b\x03"), [Key::Esc, Key::Esc]);
+
+        // Unknown sequences and control characters are skipped
+        assert_eq!(super::parse_keys(b"\x1b[Zq\x1b[5xc\x01"), [Key::Char('q'), Key::Char('c')]);
+        assert_eq!(super::parse_keys(b"\x1b["), []);
+    }
+
+    #[test]
+    fn instr_before() {
+        // MOVHI, MOV, MOVEA and HALT: 4, 2, 4 and 2 bytes long
+        let code = [opcodes::MOVHI << 10, 0, opcodes::MOV_REG << 10, opcodes::MOVEA << 10, 0, opcodes::HALT << 10];
+        let mut rom = vec![0; 0x100];
+        for (i, halfword) in code.iter().enumerate() {
+            rom[i * 2..i * 2 + 2].copy_from_slice(&halfword.to_le_bytes());
+        }
+        let bus = Bus::from_memory(Memory::from_rom(rom).unwrap());
+
+        assert_eq!(super::instr_before(&bus, 0x0700000A, 1), 0x07000006);
+        assert_eq!(super::instr_before(&bus, 0x0700000A, 2), 0x07000004);
+        assert_eq!(super::instr_before(&bus, 0x0700000A, 3), 0x07000000);
+        assert_eq!(super::instr_before(&bus, 0x07000006, 1), 0x07000004);
+
+        // Before ROM, addresses can't be read, so they're taken as 2 bytes long
+        assert_eq!(super::instr_before(&bus, 0x07000000, 2), 0x06FFFFFC);
+        assert_eq!(super::instr_before(&bus, 0x0700000A, 4), 0x06FFFFFE);
+    }
+}
@@ -0,0 +1,480 @@
+#![warn(clippy::all)]
+
+use hewwo::cpu::exceptions::codes;
+use hewwo::cpu::instrs::decoder::{self, Instr, Op};
+use hewwo::cpu::instrs::disassembler::{self, Syntax};
+use hewwo::mem::Memory;
+use hewwo::symbols::Symbols;
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+
+/*
+    vbdis: Virtual Boy ROM disassembler.
+
+    Usage: vbdis <ROM file> [--follow] [--syntax nec|mednafen] [--symbols FILE] [range...]
+
+    Ranges are given in hex as START-END (END excluded) or START+LENGTH, and can use any mirror of ROM,
+    so addresses from a crash log can be used as-is. Without any range, the whole ROM is disassembled.
+
+    By default every halfword is assumed to be code. With --follow, code is told apart from data by following
+    the flow of execution recursively from the reset, exception and interrupt vectors instead, and anything never reached is shown as .dh data.
+    Targets of JMP are only known when the register was loaded with a constant (MOVHI/MOVEA and friends) in the same stretch of code.
+
+    The ROM can also be an ELF file from the homebrew toolchains, which gets laid out the way objcopy would (see src/mem.rs).
+
+    With --symbols, names from an ELF or "address name" file (see src/symbols.rs) label the addresses they start at,
+    and branch targets get the symbol they're in appended. ELF ROMs use their own symbols unless --symbols is given.
+*/
+
+const ROM_BASE: u32 = 0x07000000;
+const DATA_PER_LINE: u32 = 4; // In halfwords
+
+// Handlers of every exception and interrupt, which the CPU jumps to directly
+const VECTORS: &[(u16, &str)] = &[
+    (codes::INTERRUPT_BASE, "Game pad interrupt"),
+    (codes::INTERRUPT_BASE | 0x10, "Timer interrupt"),
+    (codes::INTERRUPT_BASE | 0x20, "Game pak interrupt"),
+    (codes::INTERRUPT_BASE | 0x30, "Communication interrupt"),
+    (codes::INTERRUPT_BASE | 0x40, "VIP interrupt"),
+    (codes::FP_RESERVED_OPERAND, "Floating point exception"),
+    (codes::ZERO_DIVISION, "Zero division exception"),
+    (codes::ILLEGAL_OPCODE, "Illegal opcode exception"),
+    (codes::TRAP_BASE, "TRAP 0x00-0x0F"),
+    (codes::TRAP_BASE + 0x10, "TRAP 0x10-0x1F"),
+    (codes::ADDRESS_TRAP, "Address trap"),
+    (codes::DUPLEXED, "Duplexed exception/NMI"),
+    (codes::RESET, "Reset"),
+];
+
+// What each halfword of ROM holds, as found by --follow
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Data,
+    Code,             // First halfword of an instruction
+    CodeContinuation, // Second halfword of a 32-bit instruction
+}
+
+struct Rom {
+    memory: Memory,
+}
+
+impl Rom {
+    // Address of "addr" in the 0x07000000 mirror of ROM
+    fn canonical(&self, addr: u32) -> u32 {
+        ROM_BASE | (addr & self.memory.rom_mask as u32)
+    }
+
+    fn index(&self, addr: u32) -> usize {
+        (addr as usize & self.memory.rom_mask) >> 1
+    }
+
+    fn read8(&self, addr: u32) -> u8 {
+        self.memory.rom[addr as usize & self.memory.rom_mask]
+    }
+
+    fn read16(&self, addr: u32) -> u16 {
+        u16::from_le_bytes([self.read8(addr), self.read8(addr.wrapping_add(1))])
+    }
+
+    fn decode(&self, addr: u32) -> Instr {
+        let first = self.read16(addr);
+        let second = if decoder::instr_length(first) == 4 { self.read16(addr.wrapping_add(2)) } else { 0 };
+        decoder::decode(first, second)
+    }
+}
+
+fn is_rom(addr: u32) -> bool {
+    addr >> 24 & 7 == 7
+}
+
+// Follow execution from every vector, marking the halfwords of every instruction that can be reached
+fn follow(rom: &Rom) -> Vec<Kind> {
+    let mut kinds = vec![Kind::Data; rom.memory.rom.len() / 2];
+    let mut pending: Vec<u32> = VECTORS.iter().map(|&(code, _)| rom.canonical(0xFFFF0000 | code as u32)).collect();
+
+    while let Some(start) = pending.pop() {
+        let mut pc = start;
+        let mut constants: HashMap<usize, u32> = HashMap::new(); // Registers known to hold a constant at this point
+
+        while kinds[rom.index(pc)] == Kind::Data {
+            let instr = rom.decode(pc);
+            if instr.len == 4 && kinds[rom.index(pc + 2)] != Kind::Data {
+                break; // Would overlap with code that's already been found
+            }
+
+            kinds[rom.index(pc)] = Kind::Code;
+            if instr.len == 4 {
+                kinds[rom.index(pc + 2)] = Kind::CodeContinuation;
+            }
+
+            let next = pc.wrapping_add(instr.len);
+            let mut branch_to = |target: u32| {
+                if is_rom(target) {
+                    pending.push(rom.canonical(target));
+                }
+            };
+
+            match instr.op {
+                Op::Bcond if instr.cond == 0xD => {} // NOP
+                Op::Bcond if instr.cond == 0x5 => { // BR
+                    branch_to(instr.branch_target(pc));
+                    break;
+                }
+                Op::Bcond => branch_to(instr.branch_target(pc)),
+                Op::Jr => {
+                    branch_to(instr.branch_target(pc));
+                    break;
+                }
+                Op::Jal => {
+                    branch_to(instr.branch_target(pc));
+                    constants.clear();
+                    constants.insert(31, next);
+                }
+                Op::Jmp => {
+                    if let Some(&target) = constants.get(&instr.reg1) {
+                        branch_to(target & !1);
+                    }
+                    break;
+                }
+                Op::Reti | Op::Illegal => break,
+                _ => track_constants(&mut constants, &instr),
+            }
+
+            pc = next;
+            if !is_rom(pc) {
+                break;
+            }
+        }
+    }
+
+    kinds
+}
+
+// Keep track of registers loaded with constants, to find where JMPs go
+fn track_constants(constants: &mut HashMap<usize, u32>, instr: &Instr) {
+    let known = |reg: usize| if reg == 0 { Some(0) } else { constants.get(&reg).copied() };
+
+    let value = match instr.op {
+        Op::MovImm => Some(instr.imm),
+        Op::MovReg => known(instr.reg1),
+        Op::AddImm => known(instr.reg2).map(|val| val.wrapping_add(instr.imm)),
+        Op::Movhi | Op::Movea | Op::Addi => known(instr.reg1).map(|val| val.wrapping_add(instr.imm)),
+        Op::Ori => known(instr.reg1).map(|val| val | instr.imm),
+
+        // Instructions that don't write general purpose registers
+        Op::StB | Op::StH | Op::StW | Op::OutB | Op::OutH | Op::OutW |
+        Op::CmpReg | Op::CmpImm | Op::CmpfS | Op::Ldsr | Op::Cli | Op::Sei | Op::Trap | Op::Halt | Op::Bcond => return,
+
+        _ => {
+            // Anything else might write more than reg2 (multiplications, divisions, bit strings...)
+            constants.clear();
+            return;
+        }
+    };
+
+    match value {
+        Some(value) if instr.reg2 != 0 => constants.insert(instr.reg2, value),
+        _ => constants.remove(&instr.reg2),
+    };
+}
+
+fn print_instr(out: &mut impl Write, rom: &Rom, addr: u32, syntax: Syntax, symbols: &Symbols) -> io::Result<u32> {
+    let instr = rom.decode(addr);
+    let bytes: Vec<String> = (0..instr.len).map(|i| format!("{:02X}", rom.read8(addr.wrapping_add(i)))).collect();
+    let disassembly = disassembler::disassemble(&instr, addr);
+
+    writeln!(out, "{:08X}  {:<24}{}", addr, bytes.join(" "), disassembly.render_with_symbols(syntax, symbols))?;
+    Ok(instr.len)
+}
+
+fn print_data(out: &mut impl Write, rom: &Rom, addr: u32, halfwords: u32) -> io::Result<()> {
+    let bytes: Vec<String> = (0..halfwords * 2).map(|i| format!("{:02X}", rom.read8(addr.wrapping_add(i)))).collect();
+    let data: Vec<String> = (0..halfwords).map(|i| format!("{:#06X}", rom.read16(addr.wrapping_add(i * 2)))).collect();
+
+    writeln!(out, "{:08X}  {:<24}.dh {}", addr, bytes.join(" "), data.join(", "))
+}
+
+// Print [start, end). Addresses are 64-bit here so that ranges can reach the very end of the address space
+fn print_range(out: &mut impl Write, rom: &Rom, kinds: Option<&[Kind]>, start: u64, end: u64, syntax: Syntax, symbols: &Symbols) -> io::Result<()> {
+    let mut next = start & !1;
+
+    while next < end {
+        let addr = next as u32;
+        let kind = kinds.map_or(Kind::Code, |kinds| kinds[rom.index(addr)]);
+
+        let vector = VECTORS.iter().find(|&&(code, _)| rom.canonical(0xFFFF0000 | code as u32) == rom.canonical(addr));
+        match (vector, symbols.name_at(addr)) {
+            (Some(&(_, vector)), label) if kind == Kind::Code => {
+                writeln!(out, "\n; {}", vector)?;
+                if let Some(label) = label {
+                    writeln!(out, "{}:", label)?;
+                }
+            }
+            (_, Some(label)) => writeln!(out, "\n{}:", label)?,
+            _ => {}
+        }
+
+        match kind {
+            Kind::Code => {
+                next += print_instr(out, rom, addr, syntax, symbols)? as u64;
+            }
+
+            // Stray halves of instructions at the start of a range
+            Kind::CodeContinuation => {
+                print_data(out, rom, addr, 1)?;
+                next += 2;
+            }
+
+            Kind::Data => {
+                let mut halfwords = 1;
+                while halfwords < DATA_PER_LINE
+                    && next + halfwords as u64 * 2 < end
+                    && symbols.name_at(addr.wrapping_add(halfwords * 2)).is_none()
+                    && kinds.is_some_and(|kinds| kinds[rom.index(addr.wrapping_add(halfwords * 2))] == Kind::Data)
+                {
+                    halfwords += 1;
+                }
+
+                print_data(out, rom, addr, halfwords)?;
+                next += halfwords as u64 * 2;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_addr(text: &str) -> Result<u32, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(digits, 16).map_err(|_| format!("invalid address \"{}\"", text))
+}
+
+// Parses START-END or START+LENGTH
+fn parse_range(text: &str) -> Result<(u64, u64), String> {
+    if let Some((start, end)) = text.split_once('-') {
+        Ok((parse_addr(start)? as u64, parse_addr(end)? as u64))
+    } else if let Some((start, len)) = text.split_once('+') {
+        let start = parse_addr(start)? as u64;
+        Ok((start, start + parse_addr(len)? as u64))
+    } else {
+        Err(format!("invalid range \"{}\", expected START-END or START+LENGTH", text))
+    }
+}
+
+struct Options {
+    rom_path: String,
+    follow: bool,
+    syntax: Syntax,
+    symbols_path: Option<String>,
+    ranges: Vec<(u64, u64)>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options { rom_path: String::new(), follow: false, syntax: Syntax::Nec, symbols_path: None, ranges: vec![] };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--follow" => options.follow = true,
+            "--syntax" => {
+                options.syntax = match args.next().as_deref() {
+                    Some("nec") => Syntax::Nec,
+                    Some("mednafen") => Syntax::Mednafen,
+                    _ => return Err("--syntax expects nec or mednafen".to_string()),
+                }
+            }
+            "--symbols" => options.symbols_path = Some(args.next().ok_or("--symbols expects a file")?),
+            _ if options.rom_path.is_empty() => options.rom_path = arg,
+            _ => options.ranges.push(parse_range(&arg)?),
+        }
+    }
+
+    if options.rom_path.is_empty() {
+        return Err("no ROM file given".to_string());
+    }
+
+    Ok(options)
+}
+
+fn run(options: Options, memory: Memory, symbols: Symbols) -> io::Result<()> {
+    let rom = Rom { memory };
+    let kinds = if options.follow { Some(follow(&rom)) } else { None };
+
+    let mut ranges = options.ranges;
+    if ranges.is_empty() {
+        ranges.push((ROM_BASE as u64, ROM_BASE as u64 + rom.memory.rom.len() as u64));
+    }
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    for (start, end) in ranges {
+        if !is_rom(start as u32) || end <= start {
+            eprintln!("vbdis: skipping {:08X}-{:08X}: not a ROM range", start, end);
+            continue;
+        }
+
+        // Ranges are shown at the mirror they were given in, but can't go past the end of it
+        let end = end.min((start | rom.memory.rom_mask as u64) + 1);
+        print_range(&mut out, &rom, kinds.as_deref(), start, end, options.syntax, &symbols)?;
+    }
+
+    out.flush()
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("vbdis: {}", error);
+            eprintln!("Usage: vbdis <ROM file> [--follow] [--syntax nec|mednafen] [--symbols FILE] [START-END | START+LENGTH]...");
+            std::process::exit(1);
+        }
+    };
+
+    let memory = match Memory::load(&options.rom_path) {
+        Ok(memory) => memory,
+        Err(error) => {
+            eprintln!("vbdis: {}", error);
+            std::process::exit(1);
+        }
+    };
+
+    let symbols = match &options.symbols_path {
+        Some(path) => Symbols::load(path).unwrap_or_else(|error| {
+            eprintln!("vbdis: {}", error);
+            std::process::exit(1);
+        }),
+        None => memory.symbols.clone(),
+    };
+
+    if let Err(error) = run(options, memory, symbols) {
+        if error.kind() != io::ErrorKind::BrokenPipe { // Output cut short by something like "head"
+            eprintln!("vbdis: {}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hewwo::cpu::instrs::opcodes;
+
+    fn format_i(opcode: u16, reg1: u16, reg2: u16) -> Vec<u16> {
+        vec![opcode << 10 | reg2 << 5 | reg1]
+    }
+
+    fn format_v(opcode: u16, imm: u16, reg1: u16, reg2: u16) -> Vec<u16> {
+        vec![opcode << 10 | reg2 << 5 | reg1, imm]
+    }
+
+    // A 64KB ROM with the given instructions at each address, and RETI at every vector
+    fn build_rom(code: &[(u32, Vec<u16>)]) -> Rom {
+        let mut rom = vec![0; 0x10000];
+        let mut place = |addr: u32, halfwords: &[u16]| {
+            for (i, halfword) in halfwords.iter().enumerate() {
+                let offset = (addr as usize + i * 2) & (rom.len() - 1);
+                rom[offset..offset + 2].copy_from_slice(&halfword.to_le_bytes());
+            }
+        };
+
+        for &(code, _) in VECTORS {
+            place(0xFFFF0000 | code as u32, &format_i(opcodes::RETI, 0, 0));
+        }
+        for (addr, halfwords) in code {
+            place(*addr, halfwords);
+        }
+
+        Rom { memory: Memory::from_rom(rom).unwrap() }
+    }
+
+    #[test]
+    fn follow_finds_code() {
+        let main = 0x07000000;
+        let unreached = main + 0x0A;
+        let handler = unreached + 4;
+        let after_jmp = handler + 0x0E;
+        let function = after_jmp + 2;
+        let unknown = function + 0x0C;
+        let far = 0x07000100;
+
+        let rom = build_rom(&[
+            // main: JMP to handler after MOVHI and MOVEA
+            (main, format_v(opcodes::MOVHI, 0x0700, 0, 1)),
+            (main + 4, format_v(opcodes::MOVEA, handler as u16, 1, 1)),
+            (main + 8, format_i(opcodes::JMP, 1, 0)),
+            (unreached, vec![0x1234, 0x5678]),
+            // handler: JAL to function, then JMP to far after MOVHI and ORI
+            (handler, vec![opcodes::JAL << 10, (function - handler) as u16]),
+            (handler + 4, format_v(opcodes::MOVHI, 0x0700, 0, 2)),
+            (handler + 8, format_v(opcodes::ORI, far as u16, 2, 2)),
+            (handler + 12, format_i(opcodes::JMP, 2, 0)),
+            (after_jmp, vec![0xABCD]),
+            // function: r3 holds unknown, but MUL might write any register so it isn't known anymore
+            (function, format_v(opcodes::MOVHI, 0x0700, 0, 3)),
+            (function + 4, format_v(opcodes::MOVEA, unknown as u16, 3, 3)),
+            (function + 8, format_i(opcodes::MUL, 4, 5)),
+            (function + 10, format_i(opcodes::JMP, 3, 0)),
+            (unknown, vec![0x4321]),
+            // far: HALT, then BR far
+            (far, format_i(opcodes::HALT, 0, 0)),
+            (far + 2, vec![opcodes::BCOND_START << 10 | 0x5 << 9 | 0x1FE]),
+            // Reset: JMP to main
+            (0xFFFFFFF0, format_v(opcodes::MOVHI, 0x0700, 0, 1)),
+            (0xFFFFFFF4, format_i(opcodes::JMP, 1, 0)),
+        ]);
+        let kinds = follow(&rom);
+        let kind = |addr: u32| kinds[rom.index(addr)];
+
+        assert_eq!(kind(main), Kind::Code);
+        assert_eq!(kind(main + 2), Kind::CodeContinuation);
+        assert_eq!(kind(unreached), Kind::Data);
+        assert_eq!(kind(unreached + 2), Kind::Data);
+        assert_eq!(kind(handler), Kind::Code); // JMP after MOVHI and MOVEA
+        assert_eq!(kind(function), Kind::Code); // JAL
+        assert_eq!(kind(far), Kind::Code); // JMP after MOVHI and ORI
+        assert_eq!(kind(far + 2), Kind::Code);
+        assert_eq!(kind(after_jmp), Kind::Data);
+        assert_eq!(kind(unknown), Kind::Data);
+        assert_eq!(kind(0xFFFFFFF0), Kind::Code);
+        assert_eq!(kind(0xFFFFFE10), Kind::Code);
+        assert_eq!(kind(0xFFFFFE12), Kind::Data);
+    }
+
+    // Track constants through every instruction, placed at the start of ROM
+    fn constants_after(code: &[Vec<u16>]) -> HashMap<usize, u32> {
+        let halfwords: Vec<u16> = code.concat();
+        let rom = build_rom(&[(ROM_BASE, halfwords.clone())]);
+        let end = ROM_BASE + halfwords.len() as u32 * 2;
+        let mut constants = HashMap::new();
+        let mut addr = ROM_BASE;
+
+        while addr < end {
+            let instr = rom.decode(addr);
+            super::track_constants(&mut constants, &instr);
+            addr += instr.len;
+        }
+
+        constants
+    }
+
+    #[test]
+    fn track_constants() {
+        let constants = constants_after(&[
+            format_v(opcodes::MOVHI, 0x0700, 0, 1),
+            format_v(opcodes::MOVEA, 0x8000, 1, 1),
+            format_i(opcodes::MOV_IMM, 4, 2),
+            format_i(opcodes::ADDI_SHORT, 1, 3),
+            format_v(opcodes::ORI, 0xF0, 2, 4),
+            format_v(opcodes::ST_WORD, 0, 2, 3),
+            format_i(opcodes::MOV_REG, 1, 5),
+        ]);
+        assert_eq!(constants.get(&1), Some(&0x06FF8000));
+        assert_eq!(constants.get(&2), Some(&4));
+        assert_eq!(constants.get(&3), None); // r3 wasn't known to begin with
+        assert_eq!(constants.get(&4), Some(&0xF4));
+        assert_eq!(constants.get(&5), Some(&0x06FF8000));
+
+        // Multiplications also write r30, so nothing is known afterwards
+        assert!(constants_after(&[format_i(opcodes::MOV_IMM, 4, 2), format_i(opcodes::MUL, 2, 1)]).is_empty());
+    }
+}
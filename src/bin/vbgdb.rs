@@ -0,0 +1,67 @@
+#![warn(clippy::all)]
+
+use hewwo::gdb::{self, End};
+use hewwo::VirtualBoy;
+use std::net::TcpListener;
+
+/*
+    vbgdb: runs a ROM under a GDB remote stub.
+
+    Usage: vbgdb <ROM> [--port <port>]
+
+    Listens on 127.0.0.1 (port 2345 by default) and waits for GDB, then connect to it with "target remote :2345".
+    The ROM can be a raw image or the ELF file it's built from, which saves converting it for every run.
+    The emulator keeps its state between sessions, so GDB can detach and connect again. Killing the target from GDB exits.
+    See src/gdb.rs for what's supported.
+*/
+
+const DEFAULT_PORT: u16 = 2345;
+
+fn parse_args() -> Result<(String, u16), String> {
+    let mut args = std::env::args().skip(1);
+    let mut rom = None;
+    let mut port = DEFAULT_PORT;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => {
+                let value = args.next().ok_or("--port expects a port number")?;
+                port = value.parse().map_err(|_| format!("invalid port \"{}\"", value))?;
+            }
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("unexpected argument \"{}\"", arg)),
+        }
+    }
+
+    Ok((rom.ok_or("no ROM given")?, port))
+}
+
+fn run(rom: &str, port: u16) -> Result<(), String> {
+    let mut vb = VirtualBoy::load(rom).map_err(|error| format!("{}: {}", rom, error))?;
+    let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|error| format!("couldn't listen on port {}: {}", port, error))?;
+
+    loop {
+        eprintln!("vbgdb: waiting for GDB on 127.0.0.1:{}", port);
+        match gdb::serve(&mut vb, &listener) {
+            Ok(End::Detached) => eprintln!("vbgdb: GDB detached"),
+            Ok(End::Killed) => return Ok(()),
+            Err(error) => eprintln!("vbgdb: connection lost: {}", error),
+        }
+    }
+}
+
+fn main() {
+    let (rom, port) = match parse_args() {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("vbgdb: {}", error);
+            eprintln!("Usage: vbgdb <ROM> [--port <port>]");
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(error) = run(&rom, port) {
+        eprintln!("vbgdb: {}", error);
+        std::process::exit(1);
+    }
+}
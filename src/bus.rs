@@ -1,4 +1,6 @@
 use crate::mem::Memory;
+use crate::symbols::Symbols;
+use std::cell::Cell;
 
 /*
     The Virtual Boy memory bus is 27 bits wide and is organized by hardware component:
@@ -12,20 +14,184 @@ use crate::mem::Memory;
     0x06000000 - 0x06FFFFFF	Game Pak RAM
     0x07000000 - 0x07FFFFFF	Game Pak ROM
     0x08000000 - 0xFFFFFFFF	Mirroring of memory map
+
+    Regions nothing is emulated for yet (the game pak expansion and RAM, and the unmapped region) read as all ones like an open bus,
+    and writes to them, or to ROM, are dropped. That way no access a game or a fuzzed ROM makes can bring the emulator down.
 */
 
+/*
+    Watchpoints make the bus record the first read or write that touches a watched address range, optionally only when
+    the value read or written is a specific one. Reads and writes made by instructions are watched,
+    while peeks (used for instruction fetches, the cache and debugging tools) aren't.
+    Addresses are compared after masking them to 27 bits. Mirrors within a region aren't folded together,
+    so watching 0x05000000 doesn't catch accesses to 0x05010000.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access, // Reads and writes
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u32, // First watched address
+    pub end: u32,   // Last watched address
+    pub kind: WatchKind,
+    pub value: Option<u32>, // If set, only accesses reading or writing this value trigger the watchpoint
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub addr: u32, // Address of the access, masked to 27 bits and aligned
+    pub size: u32, // In bytes
+    pub value: u32,
+    pub write: bool,
+}
+
+// WRAM is split in pages of 256 bytes to track which parts of it hold code decoded by the cached interpreter
+pub const WRAM_PAGE_SHIFT: u32 = 8;
+const WRAM_PAGE_COUNT: usize = 0x10000 >> WRAM_PAGE_SHIFT;
+
+#[derive(Clone)]
 pub struct Bus {
     memory: Memory,
+    wram_code_pages: [bool; WRAM_PAGE_COUNT], // Pages the CPU has cached code from
+    written_code_pages: Vec<usize>,           // Pages with cached code that got written since the CPU last checked
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>, // First watchpoint hit since the last take_watch_hit
 }
 
 impl Bus {
     pub fn new(rom_path: &str) -> Bus {
+        Bus::load(rom_path).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    // Same as new, but returns an error instead of panicking when the ROM can't be used. Takes a raw ROM image or an ELF file
+    pub fn load(rom_path: &str) -> Result<Bus, String> {
+        Ok(Bus::from_memory(Memory::load(rom_path)?))
+    }
+
+    pub fn from_memory(memory: Memory) -> Bus {
         Bus {
-            memory: Memory::new(rom_path),
+            memory,
+            wram_code_pages: [false; WRAM_PAGE_COUNT],
+            written_code_pages: vec![],
+            watchpoints: vec![],
+            watch_hit: Cell::new(None),
+        }
+    }
+
+    // Whether both buses hold the same WRAM and hardware state. Used to check the dynarec against the interpreter
+    pub fn same_memory(&self, other: &Bus) -> bool {
+        let (a, b) = (&self.memory, &other.memory);
+        a.ram == b.ram && a.vip_memory_stub == b.vip_memory_stub && a.vsu_memory_stub == b.vsu_memory_stub
+            && a.misc_hw_memory_stub == b.misc_hw_memory_stub
+    }
+
+    // Symbols of the ELF file the ROM was loaded from. Empty for raw ROM images
+    pub fn symbols(&self) -> &Symbols {
+        &self.memory.symbols
+    }
+
+    // Called by the cached interpreter when it decodes code from the WRAM page containing "addr",
+    // so that writes to that page get reported by take_written_code_pages
+    pub fn mark_wram_code(&mut self, addr: u32) {
+        self.wram_code_pages[wram_page(addr)] = true;
+    }
+
+    pub fn code_written(&self) -> bool {
+        !self.written_code_pages.is_empty()
+    }
+
+    // Returns the WRAM pages holding cached code that were written to since the last call.
+    // Those pages stop being tracked until they're marked again
+    pub fn take_written_code_pages(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.written_code_pages)
+    }
+
+    fn wram_written(&mut self, addr: u32) {
+        let page = wram_page(addr);
+        if self.wram_code_pages[page] {
+            self.wram_code_pages[page] = false;
+            self.written_code_pages.push(page);
         }
     }
 
-    pub fn read8(&self, mut addr: u32) -> u8 {
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    // Returns false if there was no such watchpoint
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        match self.watchpoints.iter().position(|wp| wp == watchpoint) {
+            Some(index) => {
+                self.watchpoints.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    // Returns the first watchpoint hit since the last call, if any
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    #[inline]
+    fn watch(&self, addr: u32, size: u32, value: u32, write: bool) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, size, value, write);
+        }
+    }
+
+    #[inline(never)]
+    fn check_watchpoints(&self, addr: u32, size: u32, value: u32, write: bool) {
+        let addr = addr & 0x07FF_FFFF & !(size - 1);
+        let last = addr + size - 1;
+        let hit = self.watchpoints.iter().find(|wp| {
+            let kind_matches = match wp.kind {
+                WatchKind::Read => !write,
+                WatchKind::Write => write,
+                WatchKind::Access => true,
+            };
+            let start = wp.start & 0x07FF_FFFF;
+            let end = wp.end & 0x07FF_FFFF;
+            kind_matches && addr <= end && last >= start && wp.value.map_or(true, |expected| expected == value)
+        });
+
+        if let (Some(&watchpoint), None) = (hit, self.watch_hit.get()) {
+            self.watch_hit.set(Some(WatchHit { watchpoint, addr, size, value, write }));
+        }
+    }
+
+    // Reads made by instructions, checked against watchpoints
+    pub fn read8(&self, addr: u32) -> u8 {
+        let val = self.peek8(addr);
+        self.watch(addr, 1, val as u32, false);
+        val
+    }
+
+    pub fn read16(&self, addr: u32) -> u16 {
+        let val = self.peek16(addr);
+        self.watch(addr, 2, val as u32, false);
+        val
+    }
+
+    pub fn read32(&self, addr: u32) -> u32 {
+        let val = self.peek32(addr);
+        self.watch(addr, 4, val, false);
+        val
+    }
+
+    // Reads that don't trigger watchpoints, for instruction fetches and inspecting memory
+    pub fn peek8(&self, mut addr: u32) -> u8 {
         // Addresses are 27-bit on the VB, so we mask out the top 5 bits
         addr &= 0x07FF_FFFF;
 
@@ -39,11 +205,12 @@ impl Bus {
                 let rom_addr = addr as usize & self.memory.rom_mask;
                 self.memory.rom[rom_addr]
             }
-            _ => panic!("8-bit read from unimpl memory address {:08X}", addr),
+            1 => self.memory.vsu_memory_stub[addr as usize & 0x7FF],
+            _ => 0xFF, // Open bus
         }
     }
 
-    pub fn read16(&self, mut addr: u32) -> u16 {
+    pub fn peek16(&self, mut addr: u32) -> u16 {
         // Addresses are 27-bit on the VB, so we mask out the top 5 bits (as well as the lowest bit due to alignment).
         addr &= 0x07FF_FFFE;
 
@@ -62,11 +229,6 @@ impl Bus {
                 u16::from_le_bytes([self.memory.misc_hw_memory_stub[misc_hw_addr], self.memory.misc_hw_memory_stub[misc_hw_addr+1]])
             }
 
-            3 => {
-                0xFFFF
-                //println!("Unimplemented 16-bit read from unmapped memory???")
-            }
-
             5=> {
                 // Misc hw range
                 let ram_addr = addr as usize & 0xFFFF;
@@ -79,11 +241,15 @@ impl Bus {
                 let rom_addr = addr as usize & self.memory.rom_mask;
                 u16::from_le_bytes([self.memory.rom[rom_addr], self.memory.rom[rom_addr + 1]])
             }
-            _ => panic!("16-bit read from unimpl memory address {:08X}", addr),
+            1 => {
+                let vsu_addr = addr as usize & 0x7FF;
+                u16::from_le_bytes([self.memory.vsu_memory_stub[vsu_addr], self.memory.vsu_memory_stub[vsu_addr + 1]])
+            }
+            _ => 0xFFFF, // Open bus
         }
     }
 
-    pub fn read32(&self, mut addr: u32) -> u32 {
+    pub fn peek32(&self, mut addr: u32) -> u32 {
         // Addresses are 27-bit on the VB, so we mask out the top 5 bits (as well as the lowest 2 bits due to alignment).
         addr &= 0x07FF_FFFC;
 
@@ -133,11 +299,22 @@ impl Bus {
                     self.memory.rom[rom_addr + 3],
                 ])
             }
-            _ => panic!("32-bit read from unimpl memory address {:08X}", addr),
+            1 => {
+                let vsu_addr = addr as usize & 0x7FF;
+                u32::from_le_bytes([
+                    self.memory.vsu_memory_stub[vsu_addr],
+                    self.memory.vsu_memory_stub[vsu_addr + 1],
+                    self.memory.vsu_memory_stub[vsu_addr + 2],
+                    self.memory.vsu_memory_stub[vsu_addr + 3],
+                ])
+            }
+            _ => 0xFFFFFFFF, // Open bus
         }
     }
 
     pub fn write8(&mut self, mut addr: u32, val: u8) {
+        self.watch(addr, 1, val as u32, true);
+
         // Addresses are 27-bit on the VB, so we mask out the top 5 bits
         addr &= 0x07FF_FFFF;
 
@@ -150,12 +327,18 @@ impl Bus {
                 self.memory.misc_hw_memory_stub[addr as usize & 0x3F] = val;
                 //println!("Unimplemented 8-bit write to misc hw memory!")
             }
-            5 => self.memory.ram[addr as usize & 0xFFFF] = val, // Handle RAM mirroring
-            _ => panic!("8-bit write to unimpl memory address {:08X}", addr),
+            1 => self.memory.vsu_memory_stub[addr as usize & 0x7FF] = val,
+            5 => {
+                self.memory.ram[addr as usize & 0xFFFF] = val; // Handle RAM mirroring
+                self.wram_written(addr);
+            }
+            _ => {} // ROM and unmapped memory
         }
     }
 
     pub fn write16(&mut self, mut addr: u32, val: u16) {
+        self.watch(addr, 2, val as u32, true);
+
         // Addresses are 27-bit on the VB, so we mask out the top 5 bits
         addr &= 0x07FF_FFFF;
 
@@ -175,13 +358,22 @@ impl Bus {
             5 => {
                 self.memory.ram[addr as usize & 0xFFFF] = val as u8;
                 self.memory.ram[(addr as usize + 1) & 0xFFFF] = (val >> 8) as u8;
+                self.wram_written(addr);
+                self.wram_written(addr + 1);
+            }
+
+            1 => {
+                self.memory.vsu_memory_stub[addr as usize & 0x7FF] = val as u8;
+                self.memory.vsu_memory_stub[(addr as usize + 1) & 0x7FF] = (val >> 8) as u8;
             }
 
-            _ => panic!("16-bit write to unimpl memory address {:08X}", addr)
+            _ => {} // ROM and unmapped memory
         }
     }
 
     pub fn write32(&mut self, mut addr: u32, val: u32) {
+        self.watch(addr, 4, val, true);
+
         // Addresses are 27-bit on the VB, so we mask out the top 5 bits
         addr &= 0x07FF_FFFF;
 
@@ -216,9 +408,26 @@ impl Bus {
                 self.memory.ram[(addr as usize + 1) & 0xFFFF] = (val >> 8) as u8;
                 self.memory.ram[(addr as usize + 2) & 0xFFFF] = (val >> 16) as u8;
                 self.memory.ram[(addr as usize + 3) & 0xFFFF] = (val >> 24) as u8;
+                self.wram_written(addr);
+                self.wram_written(addr + 3);
             }
 
-            _ => panic!("32-bit write to unimpl memory address {:08X}", addr)
+            _ => {} // ROM and unmapped memory
         }
     }
 }
+
+// Index of the WRAM page an address falls in, taking mirroring into account
+pub fn wram_page(addr: u32) -> usize {
+    (addr as usize & 0xFFFF) >> WRAM_PAGE_SHIFT
+}
+
+// Whether reads from "addr" reach emulated memory rather than the open bus
+pub fn is_readable(addr: u32) -> bool {
+    matches!(addr >> 24 & 7, 0 | 1 | 2 | 5 | 7)
+}
+
+// Whether writes to "addr" get stored. Writes to ROM and to regions nothing is emulated for are dropped
+pub fn is_writable(addr: u32) -> bool {
+    matches!(addr >> 24 & 7, 0 | 1 | 2 | 5)
+}
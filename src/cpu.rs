@@ -1,9 +1,20 @@
-mod instrs;
+pub mod instrs;
+pub mod block_cache;
+pub mod cache;
+#[cfg(feature = "dynarec")]
+pub mod dynarec;
+pub mod exceptions;
+pub mod idle;
+pub mod trace;
 use crate::bus::Bus;
-use instrs::opcodes;
+use block_cache::BlockCache;
+use cache::InstrCache;
+use instrs::decoder::{self, Instr, Op};
 
 bitfield! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
     pub struct Psw(u32);
+    impl Debug;
     
     pub raw, set_raw: 31, 0;
     // CPU control flags
@@ -66,29 +77,85 @@ impl Psw {
             3 => self.zero() || self.carry(),                       // NH
             4 => self.sign(),                                       // N
             5 => true,                                              // Always (BR)
-            6 => self.overflow() || self.sign(),                    // LT
-            7 => ((self.overflow() ^ self.sign()) || self.zero()),  // LE
+            6 => self.overflow() ^ self.sign(),                     // LT
+            7 => (self.overflow() ^ self.sign()) || self.zero(),    // LE
             8 => !self.overflow(),                                  // MV
             9 => !self.carry(),                                     // NC
             10 => !self.zero(),                                     // NE
             11 => !(self.zero() || self.carry()),                   // H
             12 => !self.sign(),                                     // P
             13 => false,                                            // Never (NOP)
-            14 => !(self.overflow() || self.sign()),                // GT
-            _ => !((self.overflow() ^ self.sign()) || self.zero()), // GE
+            14 => !(self.overflow() ^ self.sign()),                 // GE
+            _ => !((self.overflow() ^ self.sign()) || self.zero()), // GT
         }
     }
 }
 
+pub const CHCW_ICE: u32 = 1 << 1; // Instruction cache enable bit in CHCW
+
+// Instructions taking more cycles than this count as "long" for the load pipelining rules
+const LONG_INSTRUCTION_CYCLES: u32 = 5;
+
+// Function that executes a decoded instruction, see Cpu::handler
+pub type Handler = fn(&mut Cpu, &mut Bus, Instr) -> u32;
+
+// The kind of the previously executed instruction, which affects the timing of loads and stores
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PrevInstr {
+    Load,
+    Store,
+    Long,
+    Other,
+}
+
+impl PrevInstr {
+    // Kind of an instruction that just executed and took "cycles" cycles
+    fn classify(op: Op, cycles: u32) -> PrevInstr {
+        match op {
+            Op::LdB | Op::LdH | Op::LdW | Op::InB | Op::InH | Op::InW => PrevInstr::Load,
+            Op::StB | Op::StH | Op::StW | Op::OutB | Op::OutH | Op::OutW => PrevInstr::Store,
+            _ if cycles > LONG_INSTRUCTION_CYCLES => PrevInstr::Long,
+            _ => PrevInstr::Other,
+        }
+    }
+}
+
+// The layout is fixed so that code generated by the dynarec can access the registers directly
+#[repr(C)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Regs {
     pub gprs: [u32; 32], // CPU general purpose registers (r0-r31)
     pub pc: u32,         // program counter
     pub psw: Psw,        // CPU flags
+
+    pub eipc: u32,  // PC saved when an exception or interrupt is taken
+    pub eipsw: u32, // PSW saved when an exception or interrupt is taken
+    pub fepc: u32,  // PC saved when a duplexed exception or NMI is taken
+    pub fepsw: u32, // PSW saved when a duplexed exception or NMI is taken
+    pub ecr: u32,   // Exception cause register. Lower 16 bits: exception code (EICC), upper 16 bits: duplexed exception code (FECC)
+    pub chcw: u32,  // Cache control word. Only the cache enable bit (ICE, bit 1) is stored
+    pub adtre: u32, // Address trap register. Address trap exceptions are raised when the PC matches this, if PSW.AE is set
+    pub sr29: u32,  // Undocumented system register 29. Stores all 32 bits
+    pub sr31: u32,  // Undocumented system register 31. Stores the absolute value of what's written to it
 }
 
+#[derive(Clone)]
 pub struct Cpu {
     pub regs: Regs,
-    // TODO: Add the different system registers, accessible via instructions LDSR and STSR
+    pub cache: InstrCache,
+    pub fetch_cycles: u32, // Wait states spent fetching the current instruction. Depends on cache hits and misses
+
+    pub halted: bool, // Set by HALT, cleared when an interrupt is accepted
+    pub strict_mode: bool, // If set, illegal opcodes stop the CPU instead of raising an exception. Meant for development
+    pub illegal_opcode: Option<u32>, // Address of the illegal opcode strict mode stopped at. The CPU doesn't run while it's set
+    pub cached_interpreter: bool, // If set, instructions are fetched from pre-decoded basic blocks. See block_cache.rs
+    pub blocks: BlockCache,
+    pub last_instr: Option<(u32, Instr)>, // Address and instruction run by the last step, None if it idled. Not updated by the dynarec
+    irq_lines: u8,    // Bit n is set if interrupt level n is being requested
+    nmi_line: bool,
+    pending_exception: Option<(u16, u32)>, // Exception code and return address of an exception raised by the current instruction
+    prev_instr: PrevInstr,
+    bit_string_active: bool, // Set while a bit string instruction is being resumed across steps
 }
 
 impl Cpu {
@@ -98,71 +165,205 @@ impl Cpu {
                 gprs: [0; 32],
                 pc: 0xFFFFFFF0,       // PC value on reset
                 psw: Psw(0x00008000), // PSW value on reset
+
+                eipc: 0,
+                eipsw: 0,
+                fepc: 0,
+                fepsw: 0,
+                ecr: exceptions::codes::RESET as u32, // ECR value on reset
+                chcw: 0,
+                adtre: 0,
+                sr29: 0,
+                sr31: 0,
             },
+
+            cache: InstrCache::new(),
+            fetch_cycles: 0,
+            halted: false,
+            strict_mode: false,
+            illegal_opcode: None,
+            cached_interpreter: false,
+            blocks: BlockCache::new(),
+            last_instr: None,
+            irq_lines: 0,
+            nmi_line: false,
+            pending_exception: None,
+            prev_instr: PrevInstr::Other,
+            bit_string_active: false,
         }
     }
 
     // Step the CPU by one instruction
-    pub fn step(&mut self, bus: &mut Bus) {
-        if self.regs.pc == 0x7001CC0 {panic!("breakpoint")}
-
-        let instr = bus.read16(self.regs.pc); // Fetch an opcode. Opcodes are fetched halfword-by-halfword and can be 16 or 32 bits
-        let opcode = instr >> 10; // Top 6 bits of each instruction determines its type.
-        self.regs.pc = self.regs.pc.wrapping_add(2); // Increment PC
-
-        //println!("{}", instrs::disassembler::disassemble(self, bus, instr, &mut self.regs.pc.clone()));
-
-        match opcode {
-            opcodes::BCOND_START..=opcodes::BCOND_END => self.bcond(bus, instr),
-            opcodes::JMP => self.jmp(bus, instr), // JMP reg
-            opcodes::JR  => self.jr(bus, instr), // JR $addr
-            opcodes::JAL => self.jal(bus, instr), // JAL $addr
-
-            opcodes::MOVEA => self.movea(bus, instr), // MOVEA
-            opcodes::MOVHI => self.movhi(bus, instr), // MOVHI
-            opcodes::MOV_IMM => self.mov_imm(bus, instr), // mov reg2, #imm
-            opcodes::MOV_REG => self.mov_reg(bus, instr), // mov reg2, reg1
-
-            opcodes::ADD_REG => self.add_reg(bus, instr), // ADD reg2, reg1
-            opcodes::ADDI_SHORT => self.addi_short(bus, instr), // ADD reg2, #imm. 16-bit version of ADDI.
-            opcodes::ADDI_LONG => self.addi_long(bus, instr), // ADDI reg2, reg1, #imm with a 32-bit imm.
-            opcodes::ANDI => self.andi(bus, instr), // andi r2, r1, (zero extend) #imm
-            opcodes::ORI => self.ori(bus, instr), // ori r2, r1, (zero extend) #imm
-            opcodes::CMP_IMM => self.cmp_imm(bus, instr), // cmp reg2, #imm
-            opcodes::CMP_REG => self.cmp_reg(bus, instr), // cmp reg2, reg1
-            opcodes::DIV => self.div(instr), // r30 = reg2 MOD reg1. reg2 = reg2 / reg1.
-            opcodes::MUL => self.mul(instr), // res = (signed) reg2 * (signed) reg1. r30 = (res >> 32). reg2 = (reg & 0xFFFFFFFF)
-
-            opcodes::LD_BYTE => self.ld_byte(bus, instr), // reg2 = (byte) [reg1 + disp]
-            opcodes::LD_HALFWORD => self.ld_halfword(bus, instr), // reg2 = (halfword) [reg1 + disp]
-            opcodes::LD_WORD => self.ld_word(bus, instr), // reg2 = (word) [reg1 + disp]
-            opcodes::ST_BYTE => self.st_byte(bus, instr), // [reg1 + disp] = reg2 & 0xFF
-            opcodes::ST_HALFWORD => self.st_halfword(bus, instr), // [reg1 + disp] = reg2 & 0xFFFF
-            opcodes::ST_WORD => self.st_word(bus, instr), // [reg1 + disp] = reg2
-
-            opcodes::LDSR => self.ldsr(bus, instr), // systemReg = reg2
-
-            opcodes::SEI => self.sei(), // interrupts disabled = true;
-
-            _ => panic!("Unimplemented opcode {:b} at address {:08X}", opcode, self.regs.pc.wrapping_sub(2)),
+    // Returns the number of cycles taken. While halted or stopped at an illegal opcode, the CPU idles for 1 cycle per step
+    pub fn step(&mut self, bus: &mut Bus) -> u32 {
+        if self.illegal_opcode.is_some() {
+            self.last_instr = None;
+            return 1;
         }
 
+        self.check_interrupts();
+        if self.halted {
+            self.prev_instr = PrevInstr::Other;
+            self.last_instr = None;
+            return 1;
+        }
+
+        self.check_address_trap(bus);
+
+        self.fetch_cycles = 0;
+        let (instr, handler) = if self.cached_interpreter {
+            self.fetch_cached(bus, self.regs.pc)
+        } else {
+            let instr = self.fetch_instr(bus, self.regs.pc);
+            (instr, Cpu::handler(instr.op))
+        };
+        self.last_instr = Some((self.regs.pc, instr)); // For tracing, see trace.rs
+        self.regs.pc = self.regs.pc.wrapping_add(instr.len); // Increment PC
+
+        let cycles = handler(self, bus, instr);
         self.regs.gprs[0] = 0;
+
+        self.prev_instr = PrevInstr::classify(instr.op, cycles);
+
+        if let Some((code, return_pc)) = self.pending_exception.take() {
+            self.process_exception(bus, code, return_pc);
+        }
+
+        cycles + self.fetch_cycles
     }
 
-    // Read 2 bytes from mem[pc] and increment PC
-    pub fn consume_halfword(&mut self, bus: &Bus) -> u16 {
-        let val = bus.read16(self.regs.pc);
-        self.regs.pc = self.regs.pc.wrapping_add(2);
+    // Loads take 5 cycles on their own, 4 right after another load, and 1 right after an instruction that takes many cycles,
+    // as the load can then be overlapped with the previous instruction
+    pub fn load_cycles(&self) -> u32 {
+        match self.prev_instr {
+            PrevInstr::Load => 4,
+            PrevInstr::Long => 1,
+            _ => 5,
+        }
+    }
 
-        val
+    // Stores are buffered and take 1 cycle, unless they immediately follow another store, which makes them wait for the previous one
+    pub fn store_cycles(&self) -> u32 {
+        match self.prev_instr {
+            PrevInstr::Store => 4,
+            _ => 1,
+        }
     }
 
-    // Read 4 bytes from mem[pc] and increment PC
-    pub fn consume_word(&mut self, bus: &Bus) -> u32 {
-        let val = bus.read32(self.regs.pc);
-        self.regs.pc = self.regs.pc.wrapping_add(4);
+    // Fetch an instruction halfword. Goes through the instruction cache if it's enabled (CHCW.ICE)
+    // Fetches that miss the cache (or all fetches, if it's disabled) pay the wait states of the memory they come from
+    pub fn fetch_halfword(&mut self, bus: &Bus, addr: u32) -> u16 {
+        if self.regs.chcw & CHCW_ICE != 0 {
+            let (word, hit) = self.cache.fetch(bus, addr);
+            if !hit {
+                self.fetch_cycles += cache::fetch_penalty(addr);
+            }
+
+            (word >> ((addr & 2) * 8)) as u16
+        } else {
+            self.fetch_cycles += cache::fetch_penalty(addr);
+            bus.peek16(addr)
+        }
+    }
+
+    // Fetch and decode the instruction at "addr". Instructions are fetched halfword-by-halfword and can be 16 or 32 bits
+    pub fn fetch_instr(&mut self, bus: &Bus, addr: u32) -> Instr {
+        let first = self.fetch_halfword(bus, addr);
+        let second = if decoder::instr_length(first) == 4 { self.fetch_halfword(bus, addr.wrapping_add(2)) } else { 0 };
+
+        decoder::decode(first, second)
+    }
+
+    // Execute a decoded instruction. PC must already point to the next instruction.
+    // Returns the cycles taken, not counting fetches
+    pub fn execute(&mut self, bus: &mut Bus, instr: Instr) -> u32 {
+        Cpu::handler(instr.op)(self, bus, instr)
+    }
 
-        val
+    // Get the function that executes a kind of instruction.
+    // The cached interpreter looks these up once per decoded instruction instead of on every execution
+    pub fn handler(op: Op) -> Handler {
+        match op {
+            Op::Bcond => |cpu, _, instr| cpu.bcond(instr),
+            Op::Jmp => |cpu, _, instr| cpu.jmp(instr), // JMP reg
+            Op::Jr => |cpu, _, instr| cpu.jr(instr), // JR $addr
+            Op::Jal => |cpu, _, instr| cpu.jal(instr), // JAL $addr
+
+            Op::Movea => |cpu, _, instr| cpu.movea(instr), // MOVEA
+            Op::Movhi => |cpu, _, instr| cpu.movhi(instr), // MOVHI
+            Op::MovImm => |cpu, _, instr| cpu.mov_imm(instr), // mov reg2, #imm
+            Op::MovReg => |cpu, _, instr| cpu.mov_reg(instr), // mov reg2, reg1
+
+            Op::AddReg => |cpu, _, instr| cpu.add_reg(instr), // ADD reg2, reg1
+            Op::AddImm => |cpu, _, instr| cpu.addi_short(instr), // ADD reg2, #imm. 16-bit version of ADDI.
+            Op::Addi => |cpu, _, instr| cpu.addi_long(instr), // ADDI reg2, reg1, #imm with a 32-bit imm.
+            Op::Sub => |cpu, _, instr| cpu.sub(instr), // SUB reg2, reg1
+            Op::Andi => |cpu, _, instr| cpu.andi(instr), // andi r2, r1, (zero extend) #imm
+            Op::Ori => |cpu, _, instr| cpu.ori(instr), // ori r2, r1, (zero extend) #imm
+            Op::Xori => |cpu, _, instr| cpu.xori(instr), // xori r2, r1, (zero extend) #imm
+            Op::And => |cpu, _, instr| cpu.and(instr), // AND reg2, reg1
+            Op::Or => |cpu, _, instr| cpu.or(instr), // OR reg2, reg1
+            Op::Xor => |cpu, _, instr| cpu.xor(instr), // XOR reg2, reg1
+            Op::Not => |cpu, _, instr| cpu.not(instr), // NOT reg2, reg1
+            Op::ShlReg => |cpu, _, instr| cpu.shl_reg(instr), // SHL reg2, reg1
+            Op::ShlImm => |cpu, _, instr| cpu.shl_imm(instr), // SHL reg2, #imm
+            Op::ShrReg => |cpu, _, instr| cpu.shr_reg(instr), // SHR reg2, reg1
+            Op::ShrImm => |cpu, _, instr| cpu.shr_imm(instr), // SHR reg2, #imm
+            Op::SarReg => |cpu, _, instr| cpu.sar_reg(instr), // SAR reg2, reg1
+            Op::SarImm => |cpu, _, instr| cpu.sar_imm(instr), // SAR reg2, #imm
+            Op::CmpImm => |cpu, _, instr| cpu.cmp_imm(instr), // cmp reg2, #imm
+            Op::CmpReg => |cpu, _, instr| cpu.cmp_reg(instr), // cmp reg2, reg1
+            Op::Setf => |cpu, _, instr| cpu.setf(instr), // reg2 = cond ? 1 : 0
+            Op::Div => |cpu, _, instr| cpu.div(instr), // r30 = reg2 MOD reg1. reg2 = reg2 / reg1.
+            Op::Divu => |cpu, _, instr| cpu.divu(instr), // Unsigned version of DIV
+            Op::Mul => |cpu, _, instr| cpu.mul(instr), // res = (signed) reg2 * (signed) reg1. r30 = (res >> 32). reg2 = (reg & 0xFFFFFFFF)
+            Op::Mulu => |cpu, _, instr| cpu.mulu(instr), // Unsigned version of MUL
+
+            Op::LdB => |cpu, bus, instr| cpu.ld_byte(bus, instr), // reg2 = (byte) [reg1 + disp]
+            Op::LdH => |cpu, bus, instr| cpu.ld_halfword(bus, instr), // reg2 = (halfword) [reg1 + disp]
+            Op::LdW => |cpu, bus, instr| cpu.ld_word(bus, instr), // reg2 = (word) [reg1 + disp]
+            Op::StB => |cpu, bus, instr| cpu.st_byte(bus, instr), // [reg1 + disp] = reg2 & 0xFF
+            Op::StH => |cpu, bus, instr| cpu.st_halfword(bus, instr), // [reg1 + disp] = reg2 & 0xFFFF
+            Op::StW => |cpu, bus, instr| cpu.st_word(bus, instr), // [reg1 + disp] = reg2
+            Op::InB => |cpu, bus, instr| cpu.in_byte(bus, instr), // reg2 = (zero extend) (byte) [reg1 + disp]
+            Op::InH => |cpu, bus, instr| cpu.in_halfword(bus, instr), // reg2 = (zero extend) (halfword) [reg1 + disp]
+            Op::InW => |cpu, bus, instr| cpu.in_word(bus, instr), // reg2 = (word) [reg1 + disp]
+            Op::Caxi => |cpu, bus, instr| cpu.caxi(bus, instr), // Compare [reg1 + disp] with reg2 and exchange with r30 if equal
+            Op::OutB => |cpu, bus, instr| cpu.st_byte(bus, instr), // OUT.B behaves exactly like ST.B
+            Op::OutH => |cpu, bus, instr| cpu.st_halfword(bus, instr), // OUT.H behaves exactly like ST.H
+            Op::OutW => |cpu, bus, instr| cpu.st_word(bus, instr), // OUT.W behaves exactly like ST.W
+
+            Op::Ldsr => |cpu, bus, instr| cpu.ldsr(bus, instr), // systemReg = reg2
+            Op::Stsr => |cpu, _, instr| cpu.stsr(instr), // reg2 = systemReg
+
+            Op::Sei => |cpu, _, _| cpu.sei(), // interrupts disabled = true;
+            Op::Cli => |cpu, _, _| cpu.cli(), // interrupts disabled = false;
+            Op::Trap => |cpu, _, instr| cpu.trap(instr), // Raise exception 0xFFA0 + vector
+            Op::Reti => |cpu, _, _| cpu.reti(), // Return from an exception or interrupt handler
+            Op::Halt => |cpu, _, _| cpu.halt(), // Sleep until an interrupt is accepted
+
+            // Bit string instructions, which operate on r26-r30
+            Op::Sch0bsu | Op::Sch0bsd | Op::Sch1bsu | Op::Sch1bsd |
+            Op::Orbsu | Op::Andbsu | Op::Xorbsu | Op::Movbsu |
+            Op::Ornbsu | Op::Andnbsu | Op::Xornbsu | Op::Notbsu => |cpu, bus, instr| cpu.bit_string(bus, instr),
+
+            // Floating point instructions
+            Op::CmpfS => |cpu, _, instr| cpu.cmpf_s(instr.reg2, instr.reg1),
+            Op::CvtWs => |cpu, _, instr| cpu.cvt_ws(instr.reg2, instr.reg1),
+            Op::CvtSw => |cpu, _, instr| cpu.cvt_sw(instr.reg2, instr.reg1),
+            Op::AddfS => |cpu, _, instr| cpu.addf_s(instr.reg2, instr.reg1),
+            Op::SubfS => |cpu, _, instr| cpu.subf_s(instr.reg2, instr.reg1),
+            Op::MulfS => |cpu, _, instr| cpu.mulf_s(instr.reg2, instr.reg1),
+            Op::DivfS => |cpu, _, instr| cpu.divf_s(instr.reg2, instr.reg1),
+            Op::TrncSw => |cpu, _, instr| cpu.trnc_sw(instr.reg2, instr.reg1),
+
+            // Nintendo-specific instructions
+            Op::Xb => |cpu, _, instr| cpu.xb(instr.reg2),
+            Op::Xh => |cpu, _, instr| cpu.xh(instr.reg2),
+            Op::Rev => |cpu, _, instr| cpu.rev(instr.reg2, instr.reg1),
+            Op::Mpyhw => |cpu, _, instr| cpu.mpyhw(instr.reg2, instr.reg1),
+
+            Op::Illegal => |cpu, _, instr| cpu.illegal_opcode(cpu.regs.pc.wrapping_sub(instr.len)), // Reserved opcodes
+        }
     }
 }
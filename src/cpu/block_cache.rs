@@ -0,0 +1,228 @@
+use super::instrs::decoder::{self, Instr, Op};
+use super::{cache, Cpu, Handler, CHCW_ICE};
+use crate::bus::{self, Bus};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/*
+    Cached interpreter. Instead of fetching and decoding every instruction on every step,
+    straight-line runs of code (basic blocks) are decoded once into arrays of instructions along with their handlers,
+    which later steps replay.
+
+    Blocks start at a branch target (or wherever execution happens to be) and end after an instruction that can change
+    the flow of execution, or when they get too long. Only ROM and WRAM code is cached.
+    ROM can't change, while WRAM pages holding cached code are tracked by the bus, and writes to them discard
+    every block decoded from them before the next instruction is fetched.
+
+    Everything besides fetching and decoding still happens per instruction like in the regular interpreter
+    (interrupts, address traps, timing), so both produce identical results.
+    When the instruction cache is enabled, fetches still go through it to keep its state and timing exact,
+    and the fetched halfwords are checked against the decoded ones, as the instruction cache doesn't see memory writes.
+*/
+
+const MAX_BLOCK_LENGTH: usize = 64; // In instructions
+const LOOKUP_TABLE_SIZE: usize = 4096; // Entries in the direct-mapped table of recently entered blocks
+
+#[derive(Clone, Copy)]
+struct CachedInstr {
+    pc: u32,
+    instr: Instr,
+    handler: Handler,
+    fetch_penalty: u32, // Wait states for fetching this instruction when the instruction cache is off
+}
+
+struct Block {
+    instrs: Vec<CachedInstr>,
+    wram_pages: Vec<usize>, // WRAM pages the block was decoded from. Empty for ROM blocks
+}
+
+#[derive(Clone)]
+pub struct BlockCache {
+    blocks: HashMap<u32, Rc<Block>>,     // Blocks indexed by their start address
+    lookup_table: Vec<Option<(u32, Rc<Block>)>>, // Recently entered blocks and their start address, to avoid hashing on every branch
+    current: Option<(Rc<Block>, usize)>, // Block being executed and the index of the instruction expected to run next
+    pub hits: u64,   // Instructions that were served from a block
+    pub misses: u64, // Blocks that had to be decoded
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache {
+            blocks: HashMap::new(),
+            lookup_table: vec![None; LOOKUP_TABLE_SIZE],
+            current: None,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Discard every cached block
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.lookup_table.iter_mut().for_each(|entry| *entry = None);
+        self.current = None;
+    }
+
+    // Discard the blocks decoded from any of the given WRAM pages
+    pub(crate) fn invalidate_wram_pages(&mut self, pages: &[usize]) {
+        let is_stale = |block: &Block| block.wram_pages.iter().any(|page| pages.contains(page));
+        self.blocks.retain(|_, block| !is_stale(block));
+        for entry in self.lookup_table.iter_mut() {
+            if matches!(entry, Some((_, block)) if is_stale(block)) {
+                *entry = None;
+            }
+        }
+        self.current = None;
+    }
+
+    // Get the next instruction of the block being executed, if it's the one at "pc"
+    #[inline]
+    fn next_in_current(&mut self, pc: u32) -> Option<CachedInstr> {
+        let (block, index) = self.current.as_mut()?;
+        let instr = *block.instrs.get(*index).filter(|instr| instr.pc == pc)?;
+
+        *index += 1;
+        self.hits += 1;
+        Some(instr)
+    }
+
+    // Get the next instruction of the block being executed if it starts at "pc", or the first one of the block starting at "pc"
+    fn next(&mut self, pc: u32) -> Option<CachedInstr> {
+        if let Some(instr) = self.next_in_current(pc) {
+            return Some(instr);
+        }
+
+        let block = self.lookup(pc)?;
+        let instr = block.instrs[0];
+        self.current = Some((block, 1));
+        self.hits += 1;
+
+        Some(instr)
+    }
+
+    fn lookup(&mut self, pc: u32) -> Option<Rc<Block>> {
+        let entry = &mut self.lookup_table[(pc as usize >> 1) & (LOOKUP_TABLE_SIZE - 1)];
+        match entry {
+            Some((start, block)) if *start == pc => Some(block.clone()),
+            _ => {
+                let block = self.blocks.get(&pc)?.clone();
+                *entry = Some((pc, block.clone()));
+                Some(block)
+            }
+        }
+    }
+
+    fn insert(&mut self, start: u32, block: Block) {
+        self.misses += 1;
+        self.current = Some((Rc::new(block), 0));
+        self.blocks.insert(start, self.current.as_ref().unwrap().0.clone());
+    }
+}
+
+// Whether code at this address can be cached: ROM and WRAM
+fn is_cacheable(addr: u32) -> bool {
+    matches!(addr >> 24 & 7, 5 | 7)
+}
+
+// Whether a block must end after this instruction
+fn ends_block(op: Op) -> bool {
+    use Op::*;
+
+    match op {
+        Bcond | Jmp | Jr | Jal | Trap | Reti | Halt | Illegal => true,
+        // Bit string instructions rewind the PC to run again until they're done
+        Sch0bsu | Sch0bsd | Sch1bsu | Sch1bsd |
+        Orbsu | Andbsu | Xorbsu | Movbsu | Ornbsu | Andnbsu | Xornbsu | Notbsu => true,
+        _ => false,
+    }
+}
+
+impl Cpu {
+    // Fetch and decode the instruction at "addr" through the block cache.
+    // Returns the instruction along with its handler
+    pub fn fetch_cached(&mut self, bus: &mut Bus, addr: u32) -> (Instr, Handler) {
+        // Fast path: the next instruction in the current block, with no code written and the instruction cache off
+        if !bus.code_written() && self.regs.chcw & CHCW_ICE == 0 {
+            if let Some(cached) = self.blocks.next_in_current(addr) {
+                self.fetch_cycles += cached.fetch_penalty;
+                return (cached.instr, cached.handler);
+            }
+        }
+
+        self.fetch_cached_slow(bus, addr)
+    }
+
+    #[inline(never)]
+    fn fetch_cached_slow(&mut self, bus: &mut Bus, addr: u32) -> (Instr, Handler) {
+        if bus.code_written() {
+            self.blocks.invalidate_wram_pages(&bus.take_written_code_pages());
+        }
+
+        if !is_cacheable(addr) {
+            let instr = self.fetch_instr(bus, addr);
+            return (instr, Cpu::handler(instr.op));
+        }
+
+        let cached = match self.blocks.next(addr) {
+            Some(cached) => cached,
+            None => {
+                self.decode_block(bus, addr);
+                self.blocks.next(addr).unwrap()
+            }
+        };
+
+        if self.regs.chcw & CHCW_ICE == 0 {
+            self.fetch_cycles += cached.fetch_penalty;
+            return (cached.instr, cached.handler);
+        }
+
+        // Go through the instruction cache, which may hold different code from what's in memory
+        let first = self.fetch_halfword(bus, addr);
+        let second = if decoder::instr_length(first) == 4 { self.fetch_halfword(bus, addr.wrapping_add(2)) } else { 0 };
+        let instr = decoder::decode(first, second);
+
+        if instr.raw == cached.instr.raw {
+            (cached.instr, cached.handler)
+        } else {
+            (instr, Cpu::handler(instr.op))
+        }
+    }
+
+    // Decode the basic block starting at "start" and make it the current block
+    fn decode_block(&mut self, bus: &mut Bus, start: u32) {
+        let mut instrs = Vec::new();
+        let mut pc = start;
+
+        loop {
+            let first = bus.peek16(pc);
+            let second = if decoder::instr_length(first) == 4 { bus.peek16(pc.wrapping_add(2)) } else { 0 };
+            let instr = decoder::decode(first, second);
+            let mut fetch_penalty = cache::fetch_penalty(pc);
+            if instr.len == 4 {
+                fetch_penalty += cache::fetch_penalty(pc.wrapping_add(2));
+            }
+
+            instrs.push(CachedInstr { pc, instr, handler: Cpu::handler(instr.op), fetch_penalty });
+            pc = pc.wrapping_add(instr.len);
+
+            if ends_block(instr.op) || instrs.len() == MAX_BLOCK_LENGTH || (pc ^ start) >> 24 != 0 {
+                break;
+            }
+        }
+
+        let mut wram_pages = vec![];
+        if start >> 24 & 7 == 5 {
+            let mut addr = start;
+            while addr != pc {
+                let page = bus::wram_page(addr);
+                if !wram_pages.contains(&page) {
+                    wram_pages.push(page);
+                    bus.mark_wram_code(addr);
+                }
+                addr = addr.wrapping_add(1);
+            }
+        }
+
+        self.blocks.insert(start, Block { instrs, wram_pages });
+    }
+}
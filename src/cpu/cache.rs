@@ -0,0 +1,131 @@
+use crate::bus::Bus;
+
+/*
+    The V810 has a 1KB direct-mapped instruction cache, made of 128 entries of 8 bytes each.
+    Every entry holds 2 words, each with its own valid bit, and a 22-bit tag shared by both words.
+
+    Fetch address breakdown:
+    Bits 31-10: Tag
+    Bits 9-3:   Entry index
+    Bit 2:      Word inside the entry
+    Bits 1-0:   Byte inside the word
+
+    The cache is controlled via the CHCW system register:
+    Bit 0 (ICC): Clear CEC entries, starting from entry CEN
+    Bit 1 (ICE): Cache enable
+    Bit 4 (ICD): Dump the cache to memory at address SA
+    Bit 5 (ICR): Restore the cache from memory at address SA
+    Bits 8-19 (CEC): Number of entries to clear. Bits 20-31 (CEN): First entry to clear
+    Bits 8-31 (SA): Dump/restore address, in units of 256 bytes
+
+    Dumps write the data of every entry (2 words each) to SA + 0x000 - SA + 0x3FF,
+    followed by a word per entry at SA + 0x400 - SA + 0x5FF holding the tag in bits 0-21 and the valid bits in bits 22 and 23.
+*/
+
+const ENTRY_COUNT: usize = 128;
+const TAGS_OFFSET: u32 = 0x400;
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+struct CacheEntry {
+    tag: u32,
+    data: [u32; 2],
+    valid: [bool; 2],
+}
+
+#[derive(Clone)]
+pub struct InstrCache {
+    entries: [CacheEntry; ENTRY_COUNT],
+    pub hits: u64,   // Fetches served from the cache
+    pub misses: u64, // Fetches that had to go to the bus
+}
+
+impl InstrCache {
+    pub fn new() -> InstrCache {
+        InstrCache {
+            entries: [CacheEntry::default(); ENTRY_COUNT],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    // Fetch the word containing "addr" through the cache, filling it in on a miss.
+    // Returns the word and whether the fetch was a hit.
+    pub fn fetch(&mut self, bus: &Bus, addr: u32) -> (u32, bool) {
+        let tag = addr >> 10;
+        let entry = &mut self.entries[(addr as usize >> 3) & (ENTRY_COUNT - 1)];
+        let word_index = (addr as usize >> 2) & 1;
+
+        if entry.tag == tag && entry.valid[word_index] {
+            self.hits += 1;
+            return (entry.data[word_index], true);
+        }
+
+        if entry.tag != tag { // Replacing the entry invalidates the other word as well
+            entry.tag = tag;
+            entry.valid = [false; 2];
+        }
+
+        let word = bus.peek32(addr & !3);
+        entry.data[word_index] = word;
+        entry.valid[word_index] = true;
+        self.misses += 1;
+
+        (word, false)
+    }
+
+    // Get the word containing "addr" if it's in the cache, without affecting the cache state
+    pub fn peek(&self, addr: u32) -> Option<u32> {
+        let entry = &self.entries[(addr as usize >> 3) & (ENTRY_COUNT - 1)];
+        let word_index = (addr as usize >> 2) & 1;
+
+        if entry.tag == addr >> 10 && entry.valid[word_index] {
+            Some(entry.data[word_index])
+        } else {
+            None
+        }
+    }
+
+    // Whether both caches hold the same entries, ignoring their statistics
+    pub fn same_contents(&self, other: &InstrCache) -> bool {
+        self.entries == other.entries
+    }
+
+    // Invalidate "count" entries, starting from entry "start"
+    pub fn clear(&mut self, start: usize, count: usize) {
+        for entry in self.entries.iter_mut().skip(start).take(count) {
+            entry.valid = [false; 2];
+        }
+    }
+
+    pub fn dump(&self, bus: &mut Bus, addr: u32) {
+        for (i, entry) in self.entries.iter().enumerate() {
+            let i = i as u32;
+            bus.write32(addr.wrapping_add(i * 8), entry.data[0]);
+            bus.write32(addr.wrapping_add(i * 8 + 4), entry.data[1]);
+
+            let tag_info = entry.tag | (entry.valid[0] as u32) << 22 | (entry.valid[1] as u32) << 23;
+            bus.write32(addr.wrapping_add(TAGS_OFFSET + i * 4), tag_info);
+        }
+    }
+
+    pub fn restore(&mut self, bus: &Bus, addr: u32) {
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            let i = i as u32;
+            entry.data[0] = bus.read32(addr.wrapping_add(i * 8));
+            entry.data[1] = bus.read32(addr.wrapping_add(i * 8 + 4));
+
+            let tag_info = bus.read32(addr.wrapping_add(TAGS_OFFSET + i * 4));
+            entry.tag = tag_info & 0x3FFFFF;
+            entry.valid = [tag_info & (1 << 22) != 0, tag_info & (1 << 23) != 0];
+        }
+    }
+}
+
+// Wait states for fetching a word from the bus, which are paid on a cache miss or when the cache is disabled.
+// Game Pak ROM and RAM are slow, WRAM and the rest of the system are fast.
+pub fn fetch_penalty(addr: u32) -> u32 {
+    match addr >> 24 & 7 {
+        4 | 6 | 7 => 2, // Game Pak expansion, RAM and ROM
+        _ => 0,
+    }
+}
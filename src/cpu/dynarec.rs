@@ -0,0 +1,761 @@
+mod emitter;
+
+use super::instrs::decoder::{self, Instr, Op};
+use super::{cache, Cpu, PrevInstr, Regs, CHCW_ICE};
+use crate::bus::{self, Bus};
+use emitter::{AluOp, Cond, Emitter, Reg, RAX, RBX, RCX, RDI, RSI, R12};
+use std::any::Any;
+use std::collections::HashMap;
+use std::mem::offset_of;
+use std::panic::{self, AssertUnwindSafe};
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the dynarec feature is only supported on x86-64 Linux");
+
+/*
+    x86-64 dynamic recompiler, an alternative to the interpreter for running ROM and WRAM code.
+
+    Basic blocks are translated to native code the first time they run. Simple integer instructions
+    (moves, additions, subtractions, comparisons, logic ops and branches) are translated directly, and operate on
+    the Regs struct in place: RBX points to it, and R12 to the Context of the block being run.
+    Everything else (loads and stores, shifts, multiplications and divisions, bit strings, floating point, system instructions)
+    is run by calling back into the interpreter's handler for the instruction.
+
+    Flags are computed lazily: native instructions only record the kind of operation that last set the flags and its operands,
+    and the PSW flags are only worked out from them when something needs them (a conditional branch, an interpreter fallback,
+    or the end of the block). Logic operations leave the carry alone, so one following an addition or subtraction whose flags
+    are still pending works out that carry first.
+
+    Blocks stop as soon as anything happens that the interpreter would react to between instructions:
+    an exception, an interrupt becoming acceptable, the CPU halting, a jump, or a write to WRAM holding compiled code.
+    Blocks are only entered when no interrupt is pending, the address trap is disabled, and the instruction cache (if enabled)
+    holds the same code as memory. Otherwise, the dynarec falls back to stepping the interpreter.
+    Instruction timings, including fetch wait states and instruction cache state, are accounted for as in the interpreter,
+    so both produce the same results instruction-for-instruction.
+
+    Verification mode checks this by stepping an interpreter in lockstep on a copy of the system, and comparing the registers,
+    cycles, CPU state, instruction cache and memory after every instruction. Blocks are then compiled with a call after each
+    native instruction that brings the PC and fetches up to date and checks them, and the interpreter fallbacks
+    check themselves. The lazy flags are worked out on the side for the check, so that it doesn't change when they get computed.
+    The last instruction of a block is checked once the block is done.
+*/
+
+const CODE_BUFFER_SIZE: usize = 16 << 20; // When full, everything is flushed and compiled again
+const MAX_BLOCK_LENGTH: usize = 64; // In instructions
+
+// Kinds of operations the lazy flags can come from
+const FLAGS_MATERIALIZED: u32 = 0; // The PSW flags are up to date
+const FLAGS_ADD: u32 = 1; // a + b
+const FLAGS_SUB: u32 = 2; // a - b
+const FLAGS_LOGIC: u32 = 3; // a is the result of a logic operation. Carry is left untouched
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct LazyFlags {
+    kind: u32,
+    a: u32,
+    b: u32,
+}
+
+// State shared between the generated code and the helpers it calls. A pointer to it is kept in R12
+#[repr(C)]
+struct Context {
+    regs: *mut Regs,
+    cpu: *mut Cpu,
+    bus: *mut Bus,
+    block: *const Block,
+    cycles: u64,  // Cycles taken by the instructions run so far, not counting fetches
+    fetched: u32, // Number of instructions of the block whose fetches have been accounted for
+    flags: LazyFlags,
+    panic: Option<Box<dyn Any + Send>>, // Panic raised by the interpreter in a helper, resumed once back from the generated code
+
+    // Verification mode
+    shadow: *mut (Cpu, Bus), // Null if verification is off
+    verified: u32,           // Number of instructions of the block checked so far
+    shadow_cycles: u64,      // Cycles the shadow interpreter took for them
+    mismatch: Option<String>,
+}
+
+// Signature of a compiled block. Returns the number of instructions run
+type BlockFn = unsafe extern "sysv64" fn(*mut Context) -> u32;
+
+struct BlockInstr {
+    pc: u32,
+    instr: Instr,
+    native: bool,
+    fetch_penalty: u32, // Wait states for fetching this instruction when the instruction cache is off
+}
+
+struct Block {
+    code: BlockFn,
+    instrs: Vec<BlockInstr>,
+    wram_pages: Vec<usize>, // WRAM pages the block was compiled from. Empty for ROM blocks
+}
+
+// Executable memory that compiled blocks are written to
+struct CodeBuffer {
+    ptr: *mut u8,
+    used: usize,
+}
+
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+const PROT_READ_WRITE_EXEC: i32 = 0x7;
+const MAP_PRIVATE_ANONYMOUS: i32 = 0x22;
+
+impl CodeBuffer {
+    fn new() -> CodeBuffer {
+        let ptr = unsafe { mmap(std::ptr::null_mut(), CODE_BUFFER_SIZE, PROT_READ_WRITE_EXEC, MAP_PRIVATE_ANONYMOUS, -1, 0) };
+        assert!(ptr as isize != -1, "couldn't allocate executable memory for the dynarec");
+
+        CodeBuffer { ptr, used: 0 }
+    }
+
+    // Copy code into the buffer. Returns None if it's full
+    fn push(&mut self, code: &[u8]) -> Option<BlockFn> {
+        if self.used + code.len() > CODE_BUFFER_SIZE {
+            return None;
+        }
+
+        unsafe {
+            let dst = self.ptr.add(self.used);
+            std::ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len());
+            self.used += (code.len() + 15) & !15;
+            Some(std::mem::transmute::<*mut u8, BlockFn>(dst))
+        }
+    }
+
+    fn clear(&mut self) {
+        self.used = 0;
+    }
+}
+
+impl Drop for CodeBuffer {
+    fn drop(&mut self) {
+        unsafe { munmap(self.ptr, CODE_BUFFER_SIZE); }
+    }
+}
+
+pub struct Dynarec {
+    code: CodeBuffer,
+    blocks: HashMap<u32, Box<Block>>, // Boxed so that the generated code can keep a pointer to the block it's running
+    shadow: Option<Box<(Cpu, Bus)>>,  // Interpreter run in lockstep in verification mode
+    pub blocks_compiled: u64,
+    pub native_steps: u64,      // Steps run as compiled blocks
+    pub interpreted_steps: u64, // Steps that fell back to the interpreter
+}
+
+impl Dynarec {
+    pub fn new() -> Dynarec {
+        Dynarec {
+            code: CodeBuffer::new(),
+            blocks: HashMap::new(),
+            shadow: None,
+            blocks_compiled: 0,
+            native_steps: 0,
+            interpreted_steps: 0,
+        }
+    }
+
+    // Discard every compiled block
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.code.clear();
+    }
+
+    // In verification mode, an interpreter runs on a copy of the current state of the system alongside the dynarec,
+    // and the dynarec panics as soon as their state differs after an instruction. Blocks get compiled again, with the checks
+    pub fn set_verification(&mut self, cpu: &Cpu, bus: &Bus, enabled: bool) {
+        self.shadow = if enabled { Some(Box::new((cpu.clone(), bus.clone()))) } else { None };
+        self.clear();
+    }
+
+    // Run a compiled block, or a single instruction in the interpreter if that's not possible.
+    // Returns the number of cycles taken
+    pub fn step(&mut self, cpu: &mut Cpu, bus: &mut Bus) -> u32 {
+        self.step_with(cpu, bus, true)
+    }
+
+    // Run a single instruction in the interpreter, keeping compiled blocks and verification in sync. Used while tracing
+    pub fn step_interpreter(&mut self, cpu: &mut Cpu, bus: &mut Bus) -> u32 {
+        self.step_with(cpu, bus, false)
+    }
+
+    fn step_with(&mut self, cpu: &mut Cpu, bus: &mut Bus, native: bool) -> u32 {
+        if bus.code_written() {
+            let pages = bus.take_written_code_pages();
+            self.blocks.retain(|_, block| !block.wram_pages.iter().any(|page| pages.contains(page)));
+            cpu.blocks.invalidate_wram_pages(&pages);
+        }
+
+        if let Some(cycles) = if native { self.run_block(cpu, bus) } else { None } {
+            self.native_steps += 1;
+            return cycles;
+        }
+
+        self.interpreted_steps += 1;
+        let cycles = cpu.step(bus);
+
+        if let Some(shadow) = &mut self.shadow {
+            let shadow_cycles = shadow.0.step(&mut shadow.1);
+            if let Some(mismatch) = compare(cpu, bus, cycles as u64, shadow, shadow_cycles as u64) {
+                panic!("{}", mismatch);
+            }
+        }
+
+        cycles
+    }
+
+    // Returns the cycles taken, or None if the block can't be run natively
+    fn run_block(&mut self, cpu: &mut Cpu, bus: &mut Bus) -> Option<u32> {
+        let pc = cpu.regs.pc;
+        let stopped = cpu.halted || cpu.illegal_opcode.is_some();
+        if stopped || cpu.regs.psw.addr_trap_enabled() || cpu.interrupt_pending() || !matches!(pc >> 24 & 7, 5 | 7) {
+            return None;
+        }
+
+        if !self.blocks.contains_key(&pc) {
+            self.compile(bus, pc);
+        }
+
+        let block = &self.blocks[&pc];
+        if cpu.regs.chcw & CHCW_ICE != 0 && !cache_matches_memory(cpu, bus, block) {
+            return None;
+        }
+
+        cpu.fetch_cycles = 0;
+        let cpu_ptr = cpu as *mut Cpu;
+        let mut ctx = Context {
+            regs: unsafe { std::ptr::addr_of_mut!((*cpu_ptr).regs) }, // Derived from the CPU pointer, which the helpers use too
+            cpu: cpu_ptr,
+            bus: bus as *mut Bus,
+            block: &**block,
+            cycles: 0,
+            fetched: 0,
+            flags: LazyFlags { kind: FLAGS_MATERIALIZED, a: 0, b: 0 },
+            panic: None,
+            shadow: self.shadow.as_deref_mut().map_or(std::ptr::null_mut(), |shadow| shadow as *mut (Cpu, Bus)),
+            verified: 0,
+            shadow_cycles: 0,
+            mismatch: None,
+        };
+
+        let count = unsafe { (block.code)(&mut ctx) };
+        if let Some(payload) = ctx.panic.take() {
+            panic::resume_unwind(payload);
+        }
+
+        // Finish what the instructions run natively at the end of the block left pending
+        account_fetches(cpu, bus, block, ctx.fetched as usize, count as usize);
+        materialize_flags(cpu, &mut ctx.flags);
+        if block.instrs[count as usize - 1].native {
+            cpu.prev_instr = PrevInstr::Other;
+        }
+
+        if let Some((code, return_pc)) = cpu.pending_exception.take() {
+            cpu.process_exception(bus, code, return_pc);
+        }
+
+        let cycles = ctx.cycles as u32 + cpu.fetch_cycles;
+        if let Some(shadow) = &mut self.shadow {
+            let mut shadow_cycles = ctx.shadow_cycles;
+            for _ in ctx.verified..count {
+                shadow_cycles += shadow.0.step(&mut shadow.1) as u64;
+            }
+            if let Some(mismatch) = ctx.mismatch.or_else(|| compare(cpu, bus, cycles as u64, shadow, shadow_cycles)) {
+                panic!("{}", mismatch);
+            }
+        }
+
+        Some(cycles)
+    }
+
+    fn compile(&mut self, bus: &mut Bus, start: u32) {
+        let mut instrs = Vec::new();
+        let mut pc = start;
+
+        loop {
+            let first = bus.peek16(pc);
+            let second = if decoder::instr_length(first) == 4 { bus.peek16(pc.wrapping_add(2)) } else { 0 };
+            let instr = decoder::decode(first, second);
+            let mut fetch_penalty = cache::fetch_penalty(pc);
+            if instr.len == 4 {
+                fetch_penalty += cache::fetch_penalty(pc.wrapping_add(2));
+            }
+
+            instrs.push(BlockInstr { pc, instr, native: is_native(instr.op), fetch_penalty });
+            pc = pc.wrapping_add(instr.len);
+
+            if ends_block(instr.op) || instrs.len() == MAX_BLOCK_LENGTH || (pc ^ start) >> 24 != 0 {
+                break;
+            }
+        }
+
+        let mut wram_pages = vec![];
+        if start >> 24 & 7 == 5 {
+            let mut addr = start;
+            while addr != pc {
+                let page = bus::wram_page(addr);
+                if !wram_pages.contains(&page) {
+                    wram_pages.push(page);
+                    bus.mark_wram_code(addr);
+                }
+                addr = addr.wrapping_add(1);
+            }
+        }
+
+        let code = generate(&instrs, self.shadow.is_some());
+        let code = match self.code.push(&code) {
+            Some(code) => code,
+            None => {
+                self.clear();
+                self.code.push(&code).unwrap()
+            }
+        };
+
+        self.blocks_compiled += 1;
+        self.blocks.insert(start, Box::new(Block { code, instrs, wram_pages }));
+    }
+}
+
+// Instructions that get translated to native code. The rest call the interpreter
+fn is_native(op: Op) -> bool {
+    use Op::*;
+
+    matches!(op,
+        MovReg | MovImm | Movea | Movhi |
+        AddReg | AddImm | Addi | Sub | CmpReg | CmpImm |
+        And | Or | Xor | Not | Andi | Ori | Xori |
+        Bcond | Jmp | Jr | Jal
+    )
+}
+
+// Whether a block must end after this instruction, because it can jump or change the state of the CPU
+fn ends_block(op: Op) -> bool {
+    use Op::*;
+
+    matches!(op,
+        Bcond | Jmp | Jr | Jal | Trap | Reti | Halt | Ldsr | Illegal |
+        Sch0bsu | Sch0bsd | Sch1bsu | Sch1bsd |
+        Orbsu | Andbsu | Xorbsu | Movbsu | Ornbsu | Andnbsu | Xornbsu | Notbsu
+    )
+}
+
+// Whether the instruction cache holds the same code as memory for every instruction of the block.
+// Stale code in the cache is left to the interpreter
+fn cache_matches_memory(cpu: &Cpu, bus: &Bus, block: &Block) -> bool {
+    block.instrs.iter().all(|block_instr| {
+        let start = block_instr.pc & !3;
+        let end = block_instr.pc.wrapping_add(block_instr.instr.len - 1) & !3;
+
+        [start, end].iter().all(|&addr| cpu.cache.peek(addr).map_or(true, |word| word == bus.peek32(addr)))
+    })
+}
+
+// Describes how the state of the system differs between the dynarec and the shadow interpreter, if it does.
+// "cycles" are counted from the start of the block, like "shadow_cycles"
+fn compare(cpu: &Cpu, bus: &Bus, cycles: u64, shadow: &(Cpu, Bus), shadow_cycles: u64) -> Option<String> {
+    let (shadow_cpu, shadow_bus) = shadow;
+    let checks = [
+        ("registers", cpu.regs == shadow_cpu.regs),
+        ("cycles", cycles == shadow_cycles),
+        ("halted", cpu.halted == shadow_cpu.halted && cpu.illegal_opcode == shadow_cpu.illegal_opcode),
+        ("previous instruction kind", cpu.prev_instr == shadow_cpu.prev_instr),
+        ("bit string state", cpu.bit_string_active == shadow_cpu.bit_string_active),
+        ("instruction cache", cpu.cache.same_contents(&shadow_cpu.cache)),
+        ("memory", bus.same_memory(shadow_bus)),
+    ];
+
+    let differences: Vec<&str> = checks.iter().filter(|(_, same)| !same).map(|(name, _)| *name).collect();
+    if differences.is_empty() {
+        return None;
+    }
+
+    let pc = shadow_cpu.last_instr.map_or(shadow_cpu.regs.pc, |(pc, _)| pc);
+    Some(format!(
+        "Dynarec mismatch in {} after the instruction at {:08X}\nDynarec ({} cycles): {:X?}\nInterpreter ({} cycles): {:X?}",
+        differences.join(", "), pc, cycles, cpu.regs, shadow_cycles, shadow_cpu.regs
+    ))
+}
+
+// Account for the fetches of instructions [from, to) of a block, like the interpreter would have done
+fn account_fetches(cpu: &mut Cpu, bus: &Bus, block: &Block, from: usize, to: usize) {
+    for block_instr in &block.instrs[from..to] {
+        if cpu.regs.chcw & CHCW_ICE != 0 {
+            cpu.fetch_halfword(bus, block_instr.pc);
+            if block_instr.instr.len == 4 {
+                cpu.fetch_halfword(bus, block_instr.pc.wrapping_add(2));
+            }
+        } else {
+            cpu.fetch_cycles += block_instr.fetch_penalty;
+        }
+    }
+}
+
+// Update the PSW flags from the lazy flags
+fn materialize_flags(cpu: &mut Cpu, flags: &mut LazyFlags) {
+    let psw = &mut cpu.regs.psw;
+    let (a, b) = (flags.a, flags.b);
+
+    match flags.kind {
+        FLAGS_ADD => {
+            let (res, carry) = a.overflowing_add(b);
+            psw.set_sign_and_zero(res);
+            psw.set_carry(carry);
+            psw.set_overflow((a as i32).overflowing_add(b as i32).1);
+        }
+
+        FLAGS_SUB => {
+            let (res, borrow) = a.overflowing_sub(b);
+            psw.set_sign_and_zero(res);
+            psw.set_carry(borrow);
+            psw.set_overflow((a as i32).overflowing_sub(b as i32).1);
+        }
+
+        FLAGS_LOGIC => {
+            psw.set_sign_and_zero(a);
+            psw.set_overflow(false);
+        }
+
+        _ => {}
+    }
+
+    flags.kind = FLAGS_MATERIALIZED;
+}
+
+// Called by the generated code to check the condition of a conditional branch
+unsafe extern "sysv64" fn helper_condition(ctx: *mut Context, cond: u32) -> u32 {
+    let ctx = &mut *ctx;
+    let cpu = &mut *ctx.cpu;
+
+    materialize_flags(cpu, &mut ctx.flags);
+    cpu.regs.psw.satisfies_cond(cond as u16) as u32
+}
+
+// Called by the generated code to run instruction "index" of the block in the interpreter.
+// PC already points to the next instruction. Returns non-zero if the block must stop after this instruction.
+// Panics can't unwind through the generated code, so they're caught here and handed back to run_block
+unsafe extern "sysv64" fn helper_interpret(ctx: *mut Context, index: u32) -> u32 {
+    let ctx = &mut *ctx;
+    match panic::catch_unwind(AssertUnwindSafe(|| interpret(ctx, index as usize))) {
+        Ok(must_stop) => {
+            if !must_stop && !ctx.shadow.is_null() {
+                verify(ctx); // Instructions that stop the block get checked once it's done
+            }
+            must_stop as u32
+        }
+        Err(payload) => {
+            ctx.panic = Some(payload);
+            1
+        }
+    }
+}
+
+unsafe fn interpret(ctx: &mut Context, index: usize) -> bool {
+    let cpu = &mut *ctx.cpu;
+    let bus = &mut *ctx.bus;
+    let block = &*ctx.block;
+    let block_instr = &block.instrs[index];
+
+    // The interpreter handler might depend on state the native instructions before it haven't updated yet
+    account_fetches(cpu, bus, block, ctx.fetched as usize, index + 1);
+    ctx.fetched = index as u32 + 1;
+    materialize_flags(cpu, &mut ctx.flags);
+    if index > 0 && block.instrs[index - 1].native {
+        cpu.prev_instr = PrevInstr::Other;
+    }
+
+    let cycles = Cpu::handler(block_instr.instr.op)(cpu, bus, block_instr.instr);
+    cpu.regs.gprs[0] = 0;
+    cpu.prev_instr = PrevInstr::classify(block_instr.instr.op, cycles);
+    ctx.cycles += cycles as u64;
+
+    let next_pc = block_instr.pc.wrapping_add(block_instr.instr.len);
+    let stopped = cpu.halted || cpu.illegal_opcode.is_some();
+    cpu.pending_exception.is_some() || stopped || cpu.regs.pc != next_pc || bus.code_written() || cpu.interrupt_pending()
+}
+
+// Called by the generated code in verification mode after instruction "index" of the block ran natively, unless it's the last one.
+// Brings the CPU up to date like the end of the block would, and checks it against the interpreter
+unsafe extern "sysv64" fn helper_verify(ctx: *mut Context, index: u32) {
+    let ctx = &mut *ctx;
+    let cpu = &mut *ctx.cpu;
+    let block = &*ctx.block;
+    let block_instr = &block.instrs[index as usize];
+
+    account_fetches(cpu, &*ctx.bus, block, ctx.fetched as usize, index as usize + 1);
+    ctx.fetched = index + 1;
+    cpu.prev_instr = PrevInstr::Other;
+    cpu.regs.pc = block_instr.pc.wrapping_add(block_instr.instr.len);
+
+    // Compare with the flags the lazy ones stand for, and put the PSW back so that they stay pending
+    let psw = cpu.regs.psw;
+    materialize_flags(cpu, &mut ctx.flags.clone());
+    verify(ctx);
+    cpu.regs.psw = psw;
+}
+
+// Called by the generated code before a logic operation records its flags, when an addition or subtraction's are still pending
+unsafe extern "sysv64" fn helper_materialize_flags(ctx: *mut Context) {
+    let ctx = &mut *ctx;
+    materialize_flags(&mut *ctx.cpu, &mut ctx.flags);
+}
+
+// Step the shadow interpreter over the instruction the block just ran, and compare them. Mismatches are reported after the block
+unsafe fn verify(ctx: &mut Context) {
+    if ctx.mismatch.is_some() {
+        return;
+    }
+
+    let shadow = &mut *ctx.shadow;
+    ctx.verified += 1;
+    ctx.shadow_cycles += shadow.0.step(&mut shadow.1) as u64;
+
+    let cycles = ctx.cycles + (*ctx.cpu).fetch_cycles as u64;
+    ctx.mismatch = compare(&*ctx.cpu, &*ctx.bus, cycles, shadow, ctx.shadow_cycles);
+}
+
+// Offsets for the generated code
+fn gpr(index: usize) -> i32 {
+    (offset_of!(Regs, gprs) + index * 4) as i32
+}
+
+const PC: i32 = offset_of!(Regs, pc) as i32;
+const CYCLES: i32 = offset_of!(Context, cycles) as i32;
+const FLAGS_KIND: i32 = (offset_of!(Context, flags) + offset_of!(LazyFlags, kind)) as i32;
+const FLAGS_A: i32 = (offset_of!(Context, flags) + offset_of!(LazyFlags, a)) as i32;
+const FLAGS_B: i32 = (offset_of!(Context, flags) + offset_of!(LazyFlags, b)) as i32;
+
+// Generates the code for a block
+struct Generator {
+    e: Emitter,
+    pending_cycles: u32, // Cycles of the native instructions that haven't been added to Context::cycles yet
+    flags_kind: u32,     // Kind of the lazy flags at this point of the block, which is always known while generating it
+}
+
+fn generate(instrs: &[BlockInstr], verify: bool) -> Vec<u8> {
+    let mut gen = Generator { e: Emitter::new(), pending_cycles: 0, flags_kind: FLAGS_MATERIALIZED };
+
+    gen.e.prologue();
+    gen.e.mov64(R12, RDI);
+    gen.e.load64(RBX, R12, offset_of!(Context, regs) as i32);
+
+    for (index, block_instr) in instrs.iter().enumerate() {
+        if block_instr.native {
+            gen.native(index, block_instr);
+            if verify && index + 1 < instrs.len() {
+                gen.verify(index);
+            }
+        } else {
+            gen.fallback(index, block_instr);
+        }
+    }
+
+    // Ran off the end of the block without jumping
+    let last = instrs.last().unwrap();
+    if last.native && !ends_block(last.instr.op) {
+        gen.exit(instrs.len(), Some(last.pc.wrapping_add(last.instr.len)), 0);
+    } else if !last.native {
+        gen.exit(instrs.len(), None, 0);
+    }
+
+    gen.e.code
+}
+
+impl Generator {
+    fn load_gpr(&mut self, dst: Reg, index: usize) {
+        if index == 0 {
+            self.e.mov_imm32(dst, 0);
+        } else {
+            self.e.load32(dst, RBX, gpr(index));
+        }
+    }
+
+    fn store_gpr(&mut self, index: usize, src: Reg) {
+        if index != 0 { // r0 is hardwired to 0
+            self.e.store32(RBX, gpr(index), src);
+        }
+    }
+
+    fn flush_cycles(&mut self, extra: u32) {
+        let cycles = self.pending_cycles + extra;
+        if cycles != 0 {
+            self.e.add_mem64_imm(R12, CYCLES, cycles);
+        }
+        self.pending_cycles = 0;
+    }
+
+    // Return from the block after "count" instructions, optionally setting the PC
+    fn exit(&mut self, count: usize, pc: Option<u32>, extra_cycles: u32) {
+        let pending = self.pending_cycles;
+        self.flush_cycles(extra_cycles);
+        self.pending_cycles = pending; // Other paths out of the block still need to add these
+
+        if let Some(pc) = pc {
+            self.e.store_imm32(RBX, PC, pc);
+        }
+        self.e.mov_imm32(RAX, count as u32);
+        self.e.epilogue();
+    }
+
+    // Record the operands of an operation that sets the flags: a in EAX, b in ECX
+    fn set_flags(&mut self, kind: u32) {
+        self.e.store_imm32(R12, FLAGS_KIND, kind);
+        self.e.store32(R12, FLAGS_A, RAX);
+        if kind != FLAGS_LOGIC {
+            self.e.store32(R12, FLAGS_B, RCX);
+        }
+        self.flags_kind = kind;
+    }
+
+    // Logic operations only record their result, so a carry still pending from an addition or subtraction has to be
+    // worked out before they overwrite the lazy flags. Called before anything is loaded, as the helper clobbers EAX and ECX
+    fn keep_carry(&mut self) {
+        if self.flags_kind == FLAGS_ADD || self.flags_kind == FLAGS_SUB {
+            self.e.mov64(RDI, R12);
+            self.e.call(helper_materialize_flags as *const () as usize);
+            self.flags_kind = FLAGS_MATERIALIZED;
+        }
+    }
+
+    fn native(&mut self, index: usize, block_instr: &BlockInstr) {
+        let instr = block_instr.instr;
+        let next_pc = block_instr.pc.wrapping_add(instr.len);
+        let target = instr.branch_target(block_instr.pc);
+
+        if matches!(instr.op, Op::And | Op::Or | Op::Xor | Op::Andi | Op::Ori | Op::Xori | Op::Not) {
+            self.keep_carry();
+        }
+
+        match instr.op {
+            Op::MovReg => {
+                self.load_gpr(RAX, instr.reg1);
+                self.store_gpr(instr.reg2, RAX);
+            }
+
+            Op::MovImm => {
+                self.e.mov_imm32(RAX, instr.imm);
+                self.store_gpr(instr.reg2, RAX);
+            }
+
+            Op::Movea | Op::Movhi => {
+                self.load_gpr(RAX, instr.reg1);
+                self.e.alu32_imm(AluOp::Add, RAX, instr.imm);
+                self.store_gpr(instr.reg2, RAX);
+            }
+
+            Op::AddReg | Op::AddImm | Op::Addi | Op::Sub | Op::CmpReg | Op::CmpImm => {
+                // a op b
+                let a = if instr.op == Op::Addi { instr.reg1 } else { instr.reg2 };
+                self.load_gpr(RAX, a);
+                match instr.op {
+                    Op::AddReg | Op::Sub | Op::CmpReg => self.load_gpr(RCX, instr.reg1),
+                    _ => self.e.mov_imm32(RCX, instr.imm),
+                }
+
+                let is_add = matches!(instr.op, Op::AddReg | Op::AddImm | Op::Addi);
+                self.set_flags(if is_add { FLAGS_ADD } else { FLAGS_SUB });
+
+                if instr.op != Op::CmpReg && instr.op != Op::CmpImm {
+                    self.e.alu32(if is_add { AluOp::Add } else { AluOp::Sub }, RAX, RCX);
+                    self.store_gpr(instr.reg2, RAX);
+                }
+            }
+
+            Op::And | Op::Or | Op::Xor => {
+                let op = match instr.op {
+                    Op::And => AluOp::And,
+                    Op::Or => AluOp::Or,
+                    _ => AluOp::Xor,
+                };
+
+                self.load_gpr(RAX, instr.reg2);
+                self.load_gpr(RCX, instr.reg1);
+                self.e.alu32(op, RAX, RCX);
+                self.set_flags(FLAGS_LOGIC);
+                self.store_gpr(instr.reg2, RAX);
+            }
+
+            Op::Andi | Op::Ori | Op::Xori => {
+                let op = match instr.op {
+                    Op::Andi => AluOp::And,
+                    Op::Ori => AluOp::Or,
+                    _ => AluOp::Xor,
+                };
+
+                self.load_gpr(RAX, instr.reg1);
+                self.e.alu32_imm(op, RAX, instr.imm);
+                self.set_flags(FLAGS_LOGIC);
+                self.store_gpr(instr.reg2, RAX);
+            }
+
+            Op::Not => {
+                self.load_gpr(RAX, instr.reg1);
+                self.e.not32(RAX);
+                self.set_flags(FLAGS_LOGIC);
+                self.store_gpr(instr.reg2, RAX);
+            }
+
+            Op::Bcond => match instr.cond {
+                5 => self.exit(index + 1, Some(target), 3), // BR
+                13 => self.exit(index + 1, Some(next_pc), 1), // NOP
+                cond => {
+                    self.e.mov64(RDI, R12);
+                    self.e.mov_imm32(RSI, cond as u32);
+                    self.e.call(helper_condition as *const () as usize);
+                    self.e.test32(RAX);
+                    let not_taken = self.e.jump_if(Cond::Zero);
+                    self.exit(index + 1, Some(target), 3);
+                    self.e.bind(not_taken);
+                    self.exit(index + 1, Some(next_pc), 1);
+                }
+            },
+
+            Op::Jmp => {
+                self.load_gpr(RAX, instr.reg1);
+                self.e.alu32_imm(AluOp::And, RAX, !1);
+                self.e.store32(RBX, PC, RAX);
+                self.exit(index + 1, None, 3);
+            }
+
+            Op::Jr => self.exit(index + 1, Some(target), 3),
+
+            Op::Jal => {
+                self.e.store_imm32(RBX, gpr(31), next_pc);
+                self.exit(index + 1, Some(target), 3);
+            }
+
+            _ => unreachable!("{:?} can't be compiled natively", instr.op),
+        }
+
+        if !ends_block(instr.op) {
+            self.pending_cycles += 1; // All the remaining native instructions take 1 cycle
+        }
+    }
+
+    // Check the state after a native instruction, in verification mode
+    fn verify(&mut self, index: usize) {
+        self.flush_cycles(0);
+        self.e.mov64(RDI, R12);
+        self.e.mov_imm32(RSI, index as u32);
+        self.e.call(helper_verify as *const () as usize);
+    }
+
+    fn fallback(&mut self, index: usize, block_instr: &BlockInstr) {
+        self.flush_cycles(0);
+        self.e.store_imm32(RBX, PC, block_instr.pc.wrapping_add(block_instr.instr.len));
+
+        self.e.mov64(RDI, R12);
+        self.e.mov_imm32(RSI, index as u32);
+        self.e.call(helper_interpret as *const () as usize);
+        self.e.test32(RAX);
+        self.flags_kind = FLAGS_MATERIALIZED; // The helper works the flags out before running the instruction
+
+        let keep_going = self.e.jump_if(Cond::Zero);
+        self.exit(index + 1, None, 0);
+        self.e.bind(keep_going);
+    }
+}
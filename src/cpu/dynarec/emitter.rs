@@ -0,0 +1,190 @@
+// Minimal x86-64 assembler, covering the instructions the dynarec generates.
+// 32-bit operations only take registers, 32-bit immediates and [base + disp32] memory operands
+
+pub type Reg = u8;
+
+pub const RAX: Reg = 0;
+pub const RCX: Reg = 1;
+pub const RBX: Reg = 3;
+pub const RSI: Reg = 6;
+pub const RDI: Reg = 7;
+pub const R12: Reg = 12;
+
+// Arithmetic/logic operations, as the "reg" field used by opcode 0x81 (op r/m32, imm32).
+// The register-register forms use opcode (ext << 3) | 1
+#[derive(Clone, Copy)]
+pub enum AluOp {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+}
+
+// Condition codes for Jcc
+#[derive(Clone, Copy)]
+pub enum Cond {
+    Zero = 0x4,
+}
+
+// Position of a rel32 field to be patched once its target is known
+pub struct Label(usize);
+
+pub struct Emitter {
+    pub code: Vec<u8>,
+}
+
+impl Emitter {
+    pub fn new() -> Emitter {
+        Emitter { code: Vec::with_capacity(1024) }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit32(&mut self, val: u32) {
+        self.emit(&val.to_le_bytes());
+    }
+
+    // REX prefix, omitted when it would be empty
+    fn rex(&mut self, w: bool, reg: Reg, base: Reg) {
+        let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | (base >> 3);
+        if rex != 0x40 {
+            self.emit(&[rex]);
+        }
+    }
+
+    // ModRM (+ SIB) + disp32 for a [base + disp] operand
+    fn mem_operand(&mut self, reg: Reg, base: Reg, disp: i32) {
+        self.emit(&[0x80 | (reg & 7) << 3 | (base & 7)]);
+        if base & 7 == 4 { // RSP and R12 need a SIB byte
+            self.emit(&[0x24]);
+        }
+        self.emit32(disp as u32);
+    }
+
+    fn reg_operand(&mut self, reg: Reg, rm: Reg) {
+        self.emit(&[0xC0 | (reg & 7) << 3 | (rm & 7)]);
+    }
+
+    // mov dst, dword [base + disp]
+    pub fn load32(&mut self, dst: Reg, base: Reg, disp: i32) {
+        self.rex(false, dst, base);
+        self.emit(&[0x8B]);
+        self.mem_operand(dst, base, disp);
+    }
+
+    // mov dst, qword [base + disp]
+    pub fn load64(&mut self, dst: Reg, base: Reg, disp: i32) {
+        self.rex(true, dst, base);
+        self.emit(&[0x8B]);
+        self.mem_operand(dst, base, disp);
+    }
+
+    // mov dword [base + disp], src
+    pub fn store32(&mut self, base: Reg, disp: i32, src: Reg) {
+        self.rex(false, src, base);
+        self.emit(&[0x89]);
+        self.mem_operand(src, base, disp);
+    }
+
+    // mov dword [base + disp], imm
+    pub fn store_imm32(&mut self, base: Reg, disp: i32, imm: u32) {
+        self.rex(false, 0, base);
+        self.emit(&[0xC7]);
+        self.mem_operand(0, base, disp);
+        self.emit32(imm);
+    }
+
+    // add qword [base + disp], imm
+    pub fn add_mem64_imm(&mut self, base: Reg, disp: i32, imm: u32) {
+        self.rex(true, 0, base);
+        self.emit(&[0x81]);
+        self.mem_operand(AluOp::Add as u8, base, disp);
+        self.emit32(imm);
+    }
+
+    // mov dst, imm
+    pub fn mov_imm32(&mut self, dst: Reg, imm: u32) {
+        self.rex(false, 0, dst);
+        self.emit(&[0xB8 + (dst & 7)]);
+        self.emit32(imm);
+    }
+
+    // mov dst, imm (64-bit)
+    pub fn mov_imm64(&mut self, dst: Reg, imm: u64) {
+        self.rex(true, 0, dst);
+        self.emit(&[0xB8 + (dst & 7)]);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    // mov dst, src (64-bit)
+    pub fn mov64(&mut self, dst: Reg, src: Reg) {
+        self.rex(true, src, dst);
+        self.emit(&[0x89]);
+        self.reg_operand(src, dst);
+    }
+
+    // op dst, src
+    pub fn alu32(&mut self, op: AluOp, dst: Reg, src: Reg) {
+        self.rex(false, src, dst);
+        self.emit(&[(op as u8) << 3 | 1]);
+        self.reg_operand(src, dst);
+    }
+
+    // op dst, imm
+    pub fn alu32_imm(&mut self, op: AluOp, dst: Reg, imm: u32) {
+        self.rex(false, 0, dst);
+        self.emit(&[0x81]);
+        self.reg_operand(op as u8, dst);
+        self.emit32(imm);
+    }
+
+    // not dst
+    pub fn not32(&mut self, dst: Reg) {
+        self.rex(false, 0, dst);
+        self.emit(&[0xF7]);
+        self.reg_operand(2, dst);
+    }
+
+    // test reg, reg
+    pub fn test32(&mut self, reg: Reg) {
+        self.rex(false, reg, reg);
+        self.emit(&[0x85]);
+        self.reg_operand(reg, reg);
+    }
+
+    // Call an absolute address. Clobbers RAX and every other caller-saved register
+    pub fn call(&mut self, addr: usize) {
+        self.mov_imm64(RAX, addr as u64);
+        self.emit(&[0xFF, 0xD0]); // call rax
+    }
+
+    // Jcc to a label placed later with bind
+    pub fn jump_if(&mut self, cond: Cond) -> Label {
+        self.emit(&[0x0F, 0x80 | cond as u8]);
+        self.emit32(0);
+        Label(self.code.len() - 4)
+    }
+
+    // Point a forward jump to the current position
+    pub fn bind(&mut self, label: Label) {
+        let rel = (self.code.len() - (label.0 + 4)) as u32;
+        self.code[label.0..label.0 + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+
+    // Saves the callee-saved registers the generated code uses and keeps the stack 16-byte aligned for calls
+    pub fn prologue(&mut self) {
+        self.emit(&[0x53]); // push rbx
+        self.emit(&[0x41, 0x54]); // push r12
+        self.emit(&[0x48, 0x83, 0xEC, 0x08]); // sub rsp, 8
+    }
+
+    pub fn epilogue(&mut self) {
+        self.emit(&[0x48, 0x83, 0xC4, 0x08]); // add rsp, 8
+        self.emit(&[0x41, 0x5C]); // pop r12
+        self.emit(&[0x5B]); // pop rbx
+        self.emit(&[0xC3]); // ret
+    }
+}
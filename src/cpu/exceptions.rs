@@ -0,0 +1,171 @@
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+
+// Exception codes, as stored in ECR. The handler address for each one is 0xFFFF0000 | (code & 0xFFF0),
+// except for floating point exceptions, which all share the same handler.
+pub mod codes {
+    pub const FP_RESERVED_OPERAND: u16 = 0xFF60;
+    pub const FP_OVERFLOW: u16 = 0xFF64;
+    pub const FP_ZERO_DIVISION: u16 = 0xFF68;
+    pub const FP_INVALID_OPERATION: u16 = 0xFF70;
+    pub const ZERO_DIVISION: u16 = 0xFF80;
+    pub const ILLEGAL_OPCODE: u16 = 0xFF90;
+    pub const TRAP_BASE: u16 = 0xFFA0; // TRAP n raises 0xFFA0 + n
+    pub const ADDRESS_TRAP: u16 = 0xFFC0;
+    pub const DUPLEXED: u16 = 0xFFD0; // Also used for NMIs
+    pub const RESET: u16 = 0xFFF0;
+    pub const INTERRUPT_BASE: u16 = 0xFE00; // Interrupt level n raises 0xFE00 + (n << 4)
+}
+
+// Interrupt levels used by the VB hardware. Higher levels have higher priority.
+pub mod irq_levels {
+    pub const GAME_PAD: u8 = 0;
+    pub const TIMER: u8 = 1;
+    pub const GAME_PAK: u8 = 2;
+    pub const COMMUNICATION: u8 = 3;
+    pub const VIP: u8 = 4;
+}
+
+const FP_HANDLER: u32 = 0xFFFFFF60;
+const DUPLEXED_HANDLER: u32 = 0xFFFFFFD0;
+
+impl Cpu {
+    // Assert the interrupt line of a certain level (0-4). The line stays asserted until it's cleared.
+    pub fn request_irq(&mut self, level: u8) {
+        debug_assert!(level < 5);
+        self.irq_lines |= 1 << level;
+    }
+
+    // Acknowledge an interrupt request
+    pub fn clear_irq(&mut self, level: u8) {
+        debug_assert!(level < 5);
+        self.irq_lines &= !(1 << level);
+    }
+
+    // Signal a non-maskable interrupt. The VB has nothing connected to the NMI pin, but the V810 supports it
+    pub fn request_nmi(&mut self) {
+        self.nmi_line = true;
+    }
+
+    // Schedule an exception to be taken once the current instruction is done.
+    // "return_pc" is the address stored in EIPC/FEPC: the faulting instruction for most exceptions, the next one for TRAP.
+    pub fn raise_exception(&mut self, code: u16, return_pc: u32) {
+        self.pending_exception = Some((code, return_pc));
+    }
+
+    // Checks for pending NMIs and interrupts, before executing an instruction.
+    // Interrupts are masked while an exception, NMI or duplexed exception is being handled, while PSW.ID is set,
+    // and when their level is below PSW.I. An accepted interrupt wakes the CPU from HALT.
+    pub fn check_interrupts(&mut self) {
+        if self.nmi_line && !self.regs.psw.nmi_pending() {
+            self.nmi_line = false;
+            self.halted = false;
+            self.enter_duplexed_exception(codes::DUPLEXED, self.regs.pc);
+            return;
+        }
+
+        let level = match self.acceptable_irq() {
+            Some(level) => level,
+            None => return,
+        };
+
+        let code = codes::INTERRUPT_BASE | (level << 4) as u16;
+        self.halted = false;
+        self.enter_exception(code, self.regs.pc);
+        self.regs.psw.set_i((level + 1).min(15)); // Mask interrupts of the same or lower level while this one is serviced
+        self.regs.pc = 0xFFFF0000 | code as u32;
+    }
+
+    // Whether check_interrupts would take an NMI or an interrupt right now
+    pub fn interrupt_pending(&self) -> bool {
+        (self.nmi_line && !self.regs.psw.nmi_pending()) || self.acceptable_irq().is_some()
+    }
+
+    // Returns the level of the highest priority interrupt being requested, unless it's masked
+    fn acceptable_irq(&self) -> Option<u32> {
+        if self.irq_lines == 0 {
+            return None;
+        }
+
+        let psw = &self.regs.psw;
+        if psw.nmi_pending() || psw.exception_pending() || psw.irqs_disabled() {
+            return None;
+        }
+
+        let level = 7 - self.irq_lines.leading_zeros(); // Highest requested level has priority
+        if level < psw.i() {
+            return None;
+        }
+
+        Some(level)
+    }
+
+    // Called when decoding a reserved instruction encoding at "instr_pc".
+    // Raises an illegal opcode exception. In strict mode, the CPU stops at the offending PC instead, and reports it in illegal_opcode.
+    // Returns the cycles taken
+    pub fn illegal_opcode(&mut self, instr_pc: u32) -> u32 {
+        if self.strict_mode {
+            self.illegal_opcode = Some(instr_pc);
+            self.regs.pc = instr_pc;
+            return 1;
+        }
+
+        self.raise_exception(codes::ILLEGAL_OPCODE, instr_pc);
+        1
+    }
+
+    // Hardware breakpoint: raises an address trap exception when the instruction at ADTRE is about to run, if PSW.AE is set.
+    // The return address is the trapped instruction itself. Taking the exception clears PSW.AE,
+    // so handlers need to clear AE in EIPSW (or move ADTRE) before returning to avoid trapping again.
+    pub fn check_address_trap(&mut self, bus: &mut Bus) {
+        if self.regs.psw.addr_trap_enabled() && self.regs.pc == self.regs.adtre {
+            self.process_exception(bus, codes::ADDRESS_TRAP, self.regs.pc);
+        }
+    }
+
+    // Takes an exception raised during the last instruction.
+    // Exceptions during normal operation go to the handler for their code via EIPC/EIPSW.
+    // Exceptions while PSW.EP is set are "duplexed" and go to the duplexed exception handler via FEPC/FEPSW.
+    // Exceptions while PSW.NP is set are fatal: the CPU dumps some state to address 0 and stops.
+    pub fn process_exception(&mut self, bus: &mut Bus, code: u16, return_pc: u32) {
+        if self.regs.psw.nmi_pending() {
+            bus.write32(0x00000000, 0xFFFF0000 | code as u32);
+            bus.write32(0x00000004, self.regs.psw.raw());
+            bus.write32(0x00000008, return_pc);
+            self.halted = true; // Nothing can wake the CPU up now that NP is set, short of a reset
+        } else if self.regs.psw.exception_pending() {
+            self.enter_duplexed_exception(code, return_pc);
+        } else {
+            self.enter_exception(code, return_pc);
+            self.regs.pc = match code {
+                codes::FP_RESERVED_OPERAND..=codes::FP_INVALID_OPERATION => FP_HANDLER,
+                _ => 0xFFFF0000 | (code & 0xFFF0) as u32,
+            };
+        }
+    }
+
+    // Save state to EIPC/EIPSW and set the exception code in the lower half of ECR. The caller sets the new PC
+    fn enter_exception(&mut self, code: u16, return_pc: u32) {
+        self.bit_string_active = false; // An interrupted bit string instruction pays its setup cycles again when resumed
+        self.regs.eipc = return_pc;
+        self.regs.eipsw = self.regs.psw.raw();
+        self.regs.ecr = (self.regs.ecr & 0xFFFF0000) | code as u32;
+
+        self.regs.psw.set_exception_pending(true);
+        self.regs.psw.set_irqs_disabled(true);
+        self.regs.psw.set_addr_trap_enabled(false);
+    }
+
+    // Save state to FEPC/FEPSW, set the exception code in the upper half of ECR and jump to the duplexed exception handler
+    fn enter_duplexed_exception(&mut self, code: u16, return_pc: u32) {
+        self.bit_string_active = false;
+        self.regs.fepc = return_pc;
+        self.regs.fepsw = self.regs.psw.raw();
+        self.regs.ecr = (self.regs.ecr & 0xFFFF) | (code as u32) << 16;
+
+        self.regs.psw.set_nmi_pending(true);
+        self.regs.psw.set_irqs_disabled(true);
+        self.regs.psw.set_addr_trap_enabled(false);
+        self.regs.pc = DUPLEXED_HANDLER;
+    }
+}
@@ -0,0 +1,192 @@
+use super::instrs::decoder::{self, Op};
+use super::{Cpu, InstrCache, PrevInstr, Regs, CHCW_ICE};
+use crate::bus::Bus;
+
+/*
+    Idle loop and HALT fast-forwarding.
+
+    Games spend a lot of time waiting on the hardware, either halted until an interrupt comes in,
+    or spinning in a short loop that polls an I/O register. Neither changes anything until the next hardware event,
+    so instead of stepping through them, time can be skipped ahead to that event.
+
+    While the CPU is halted and no interrupt can be accepted, every step only idles for 1 cycle, so the whole wait is skipped.
+
+    Idle loops are short loops closed by a backward branch, made only of loads, comparisons, register-only ALU ops and branches,
+    so that running them can't write memory. Every time execution jumps back to the start of such a loop, the CPU state
+    (registers, instruction cache contents and pipelining state) is compared with the one from the previous time around.
+    If nothing changed and execution never left the loop in between, every further iteration will do exactly the same thing
+    in the same number of cycles, until something outside the CPU changes what the loop polls or raises an interrupt.
+    Whole iterations are then skipped, stopping short of the next event so that the rest gets stepped through as usual.
+
+    The results are identical to stepping through everything, down to the hit/miss statistics of the instruction cache
+    and of the cached interpreter's block cache, which are advanced by what the skipped iterations would have added to them.
+*/
+
+const MAX_LOOP_LENGTH: u32 = 32; // In bytes, from the start of the loop to its closing branch
+const MAX_ATTEMPTS: u32 = 16; // Iterations that can go by without the state repeating before a loop is given up on
+
+// The state an idle loop iteration must leave unchanged
+struct Snapshot {
+    regs: Regs,
+    cache: InstrCache,
+    prev_instr: PrevInstr,
+    block_hits: u64,
+    block_misses: u64, // An iteration that had to decode blocks doesn't count as a repeat
+}
+
+impl Snapshot {
+    fn take(cpu: &Cpu) -> Snapshot {
+        Snapshot {
+            regs: cpu.regs.clone(),
+            cache: cpu.cache.clone(), // Along with the statistics at that point, to tell what an iteration adds to them
+            prev_instr: cpu.prev_instr,
+            block_hits: cpu.blocks.hits,
+            block_misses: cpu.blocks.misses,
+        }
+    }
+
+    fn matches(&self, cpu: &Cpu) -> bool {
+        self.regs == cpu.regs && self.prev_instr == cpu.prev_instr && self.cache.same_contents(&cpu.cache) &&
+            self.block_misses == cpu.blocks.misses
+    }
+}
+
+// A loop that's being watched to see if it's idling
+struct Candidate {
+    start: u32,
+    end: u32, // Address of the closing branch
+    snapshot: Snapshot,
+    cycles: u64, // Cycle count when the snapshot was taken
+    attempts: u32,
+}
+
+pub struct IdleSkipper {
+    candidate: Option<Candidate>,
+    pub skipped_cycles: u64, // Total cycles fast-forwarded over
+}
+
+impl IdleSkipper {
+    pub fn new() -> IdleSkipper {
+        IdleSkipper {
+            candidate: None,
+            skipped_cycles: 0,
+        }
+    }
+
+    // Forget about the loop being watched, after steps ran without the skipper being called
+    pub fn reset(&mut self) {
+        self.candidate = None;
+    }
+
+    // Called after every step with the PC from before the step, the number of cycles run so far,
+    // and the cycle count at which the next hardware event happens.
+    // Returns the new cycle count, after skipping over whatever idle time can be skipped
+    pub fn fast_forward(&mut self, cpu: &mut Cpu, bus: &Bus, prev_pc: u32, cycles: u64, target: u64) -> u64 {
+        if cpu.halted && !cpu.interrupt_pending() && cpu.prev_instr == PrevInstr::Other {
+            // Until the next event, every step would idle for 1 cycle without changing anything
+            let skipped = target.saturating_sub(cycles);
+            self.skipped_cycles += skipped;
+            self.candidate = None;
+            return cycles + skipped;
+        }
+
+        let pc = cpu.regs.pc;
+        if matches!(&self.candidate, Some(candidate) if pc < candidate.start || pc > candidate.end) {
+            self.candidate = None; // Left the loop
+        }
+
+        // Only look further when execution just jumped back, or with the dynarec, when a block looped back to its start
+        if pc > prev_pc || prev_pc - pc > MAX_LOOP_LENGTH {
+            return cycles;
+        }
+
+        match &mut self.candidate {
+            Some(candidate) if candidate.start == pc => {
+                if candidate.snapshot.matches(cpu) {
+                    // Skip as many iterations as possible while staying before the next event
+                    let iteration = cycles - candidate.cycles;
+                    let iterations = target.saturating_sub(cycles + 1) / iteration;
+                    let skipped = iterations * iteration;
+                    self.skipped_cycles += skipped;
+                    candidate.cycles = cycles + skipped;
+
+                    // Every iteration fetches the same way, so it hits and misses the cache as many times as the last one
+                    let cache = &mut cpu.cache;
+                    cache.hits += iterations * (cache.hits - candidate.snapshot.cache.hits);
+                    cache.misses += iterations * (cache.misses - candidate.snapshot.cache.misses);
+                    candidate.snapshot.cache.hits = cache.hits;
+                    candidate.snapshot.cache.misses = cache.misses;
+
+                    // Same for the block cache, which only had hits during the iteration
+                    let blocks = &mut cpu.blocks;
+                    blocks.hits += iterations * (blocks.hits - candidate.snapshot.block_hits);
+                    candidate.snapshot.block_hits = blocks.hits;
+                    return cycles + skipped;
+                }
+
+                if candidate.attempts < MAX_ATTEMPTS {
+                    candidate.attempts += 1;
+                    candidate.snapshot = Snapshot::take(cpu);
+                    candidate.cycles = cycles;
+                }
+            }
+
+            _ => {
+                self.candidate = idle_loop_end(cpu, bus, pc).map(|end| Candidate {
+                    start: pc,
+                    end,
+                    snapshot: Snapshot::take(cpu),
+                    cycles,
+                    attempts: 0,
+                });
+            }
+        }
+
+        cycles
+    }
+}
+
+// If the code at "start" could be an idle loop, returns the address of the branch closing it
+fn idle_loop_end(cpu: &Cpu, bus: &Bus, start: u32) -> Option<u32> {
+    if !matches!(start >> 24 & 7, 5 | 7) { // Only ROM and WRAM code
+        return None;
+    }
+
+    let mut pc = start;
+    while pc.wrapping_sub(start) <= MAX_LOOP_LENGTH {
+        // With the instruction cache on, the CPU might be running different code from what's in memory
+        if cpu.regs.chcw & CHCW_ICE != 0 && !cached_code_matches(cpu, bus, pc) {
+            return None;
+        }
+
+        let first = bus.peek16(pc);
+        let second = if decoder::instr_length(first) == 4 { bus.peek16(pc.wrapping_add(2)) } else { 0 };
+        let instr = decoder::decode(first, second);
+
+        match instr.op {
+            Op::Bcond | Op::Jr if instr.cond != 13 && instr.branch_target(pc) == start => return Some(pc),
+            op if !is_idle_safe(op) => return None,
+            _ => pc = pc.wrapping_add(instr.len),
+        }
+    }
+
+    None
+}
+
+// Whether the instruction at "addr" is the same in the instruction cache (if it's there at all) and in memory
+fn cached_code_matches(cpu: &Cpu, bus: &Bus, addr: u32) -> bool {
+    [addr, addr.wrapping_add(2)].iter().all(|&addr| cpu.cache.peek(addr).map_or(true, |word| word == bus.peek32(addr)))
+}
+
+// Instructions that can be part of an idle loop: nothing that writes memory, raises exceptions or touches system state
+fn is_idle_safe(op: Op) -> bool {
+    use Op::*;
+
+    matches!(op,
+        Bcond | MovReg | MovImm | Movea | Movhi |
+        AddReg | AddImm | Addi | Sub | CmpReg | CmpImm |
+        And | Andi | Or | Ori | Xor | Xori | Not |
+        ShlReg | ShlImm | ShrReg | ShrImm | SarReg | SarImm | Setf |
+        LdB | LdH | LdW | InB | InH | InW
+    )
+}
@@ -1,12 +1,16 @@
-#[allow(unused)]
+pub mod assembler;
 pub mod disassembler;
 
 pub mod alu;
+pub mod bit_string;
+pub mod decoder;
 pub mod branches;
+pub mod floating_point;
 pub mod reg_transfer;
 pub mod loads_stores;
 pub mod misc_instrs;
 
+#[allow(unused)]
 pub mod opcodes {
     pub const MOV_IMM: u16 = 0b010000;
     pub const MOV_REG: u16 = 0b000000;
@@ -25,6 +29,18 @@ pub mod opcodes {
     pub const MUL: u16 = 0b001000;
     pub const MULU: u16 = 0b001010;
     pub const SUB: u16 = 0b000010;
+    pub const AND: u16 = 0b001101;
+    pub const OR: u16 = 0b001100;
+    pub const XOR: u16 = 0b001110;
+    pub const XORI: u16 = 0b101110;
+    pub const NOT: u16 = 0b001111;
+    pub const SHL_REG: u16 = 0b000100;
+    pub const SHL_IMM: u16 = 0b010100;
+    pub const SHR_REG: u16 = 0b000101;
+    pub const SHR_IMM: u16 = 0b010101;
+    pub const SAR_REG: u16 = 0b000111;
+    pub const SAR_IMM: u16 = 0b010111;
+    pub const SETF: u16 = 0b010010;
 
     pub const BCOND_START: u16 = 0b100000;
     pub const BCOND_END: u16 = 0b100111;
@@ -36,6 +52,10 @@ pub mod opcodes {
     pub const IN_BYTE: u16 = 0b111000;
     pub const IN_HALFWORD: u16 = 0b111001;
     pub const IN_WORD: u16 = 0b111011;
+    pub const CAXI: u16 = 0b111010;
+    pub const OUT_BYTE: u16 = 0b111100;
+    pub const OUT_HALFWORD: u16 = 0b111101;
+    pub const OUT_WORD: u16 = 0b111111;
 
     pub const LD_BYTE: u16 = 0b110000;
     pub const ST_BYTE: u16 = 0b110100; 
@@ -45,6 +65,84 @@ pub mod opcodes {
     pub const ST_WORD: u16 = 0b110111;
 
     pub const LDSR: u16 = 0b011100;
+    pub const STSR: u16 = 0b011101;
 
     pub const SEI: u16 = 0b011110;
+    pub const CLI: u16 = 0b010110;
+    pub const TRAP: u16 = 0b011000;
+    pub const RETI: u16 = 0b011001;
+    pub const HALT: u16 = 0b011010;
+
+    pub const BIT_STRING: u16 = 0b011111; // Bit string instructions. The sub-opcodes are in the "bit_string" module
+
+    // Sub-opcodes for opcode 0b011111, stored in the reg1 field
+    pub mod bit_string {
+        pub const SCH0BSU: u16 = 0b00000;
+        pub const SCH0BSD: u16 = 0b00001;
+        pub const SCH1BSU: u16 = 0b00010;
+        pub const SCH1BSD: u16 = 0b00011;
+
+        pub const ORBSU: u16 = 0b01000;
+        pub const ANDBSU: u16 = 0b01001;
+        pub const XORBSU: u16 = 0b01010;
+        pub const MOVBSU: u16 = 0b01011;
+        pub const ORNBSU: u16 = 0b01100;
+        pub const ANDNBSU: u16 = 0b01101;
+        pub const XORNBSU: u16 = 0b01110;
+        pub const NOTBSU: u16 = 0b01111;
+    }
+
+    pub const FORMAT_VII: u16 = 0b111110; // Floating point and Nintendo-specific instructions. The sub-opcodes are in the "extended" module
+
+    // Sub-opcodes for opcode 0b111110, stored in the top 6 bits of the second instruction halfword
+    pub mod extended {
+        pub const CMPF_S: u16 = 0b000000;
+        pub const CVT_WS: u16 = 0b000010;
+        pub const CVT_SW: u16 = 0b000011;
+        pub const ADDF_S: u16 = 0b000100;
+        pub const SUBF_S: u16 = 0b000101;
+        pub const MULF_S: u16 = 0b000110;
+        pub const DIVF_S: u16 = 0b000111;
+        pub const TRNC_SW: u16 = 0b001011;
+
+        // Nintendo-specific
+        pub const XB: u16 = 0b001000;
+        pub const XH: u16 = 0b001001;
+        pub const REV: u16 = 0b001010;
+        pub const MPYHW: u16 = 0b001100;
+    }
+}
+
+// IDs of the system registers accessible via LDSR and STSR. IDs not listed here are reserved.
+pub mod system_regs {
+    pub const EIPC: u16 = 0;
+    pub const EIPSW: u16 = 1;
+    pub const FEPC: u16 = 2;
+    pub const FEPSW: u16 = 3;
+    pub const ECR: u16 = 4;
+    pub const PSW: u16 = 5;
+    pub const PIR: u16 = 6;
+    pub const TKCW: u16 = 7;
+    pub const CHCW: u16 = 24;
+    pub const ADTRE: u16 = 25;
+    pub const SR29: u16 = 29;
+    pub const SR30: u16 = 30;
+    pub const SR31: u16 = 31;
+
+    // Name of a system register, as used by assembly code. Reserved IDs have no name
+    pub fn name(id: u16) -> Option<&'static str> {
+        match id {
+            EIPC => Some("eipc"),
+            EIPSW => Some("eipsw"),
+            FEPC => Some("fepc"),
+            FEPSW => Some("fepsw"),
+            ECR => Some("ecr"),
+            PSW => Some("psw"),
+            PIR => Some("pir"),
+            TKCW => Some("tkcw"),
+            CHCW => Some("chcw"),
+            ADTRE => Some("adtre"),
+            _ => None,
+        }
+    }
 }
@@ -1,4 +1,5 @@
-use crate::bus::Bus;
+use super::decoder::Instr;
+use crate::cpu::exceptions::codes;
 use crate::cpu::Cpu;
 
 impl Cpu {
@@ -6,10 +7,10 @@ impl Cpu {
     // Cycles: 1
     // Flags affected: Zero, Sign, Carry, Overflow
     // Opcode: 0b010001
-    pub fn addi_short (&mut self, bus: &mut Bus, instr: u16) {
-        let reg2_index = (instr >> 5 & 0x1F) as usize;
+    pub fn addi_short (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
         let reg2 = self.regs.gprs[reg2_index];
-        let imm = ((instr as i32) << 27 >> 27) as u32; // sign extend immediate
+        let imm = instr.imm;
 
         let (res, overflow) = (reg2 as i32).overflowing_add(imm as i32);
         let res = res as u32;
@@ -18,14 +19,16 @@ impl Cpu {
         self.regs.psw.set_overflow(overflow);
 
         self.regs.gprs[reg2_index] = res;
+
+        1
     }
 
-    pub fn addi_long (&mut self, bus: &mut Bus, instr: u16) {
-        let reg2_index = (instr >> 5 & 0x1F) as usize;
-        let reg1_index = (instr & 0x1F) as usize;
+    pub fn addi_long (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let reg1_index = instr.reg1;
 
         let reg1 = self.regs.gprs[reg1_index];
-        let imm = self.consume_halfword(bus) as i16 as u32;
+        let imm = instr.imm;
 
         let (res, overflow) = (reg1 as i32).overflowing_add(imm as i32);
         let res = res as u32;
@@ -34,11 +37,13 @@ impl Cpu {
         self.regs.psw.set_overflow(overflow);
 
         self.regs.gprs[reg2_index] = res;
+
+        1
     }
 
-    pub fn add_reg (&mut self, bus: &mut Bus, instr: u16) {
-        let reg2_index = (instr >> 5 & 0x1F) as usize;
-        let reg1_index = (instr & 0x1F) as usize;
+    pub fn add_reg (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let reg1_index = instr.reg1;
 
         let reg1 = self.regs.gprs[reg1_index];
         let reg2 = self.regs.gprs[reg2_index];
@@ -50,88 +55,373 @@ impl Cpu {
         self.regs.psw.set_overflow(overflow);
 
         self.regs.gprs[reg2_index] = res;
+
+        1
+    }
+
+    // reg2 = reg2 - reg1
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Carry, Overflow
+    // Opcode: 0b000010
+    pub fn sub (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let reg1_index = instr.reg1;
+
+        let reg1 = self.regs.gprs[reg1_index];
+        let reg2 = self.regs.gprs[reg2_index];
+
+        let (res, overflow) = (reg2 as i32).overflowing_sub(reg1 as i32);
+        let res = res as u32;
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.psw.set_carry(reg1 > reg2); // Set carry if the subtraction borrowed.
+        self.regs.psw.set_overflow(overflow);
+
+        self.regs.gprs[reg2_index] = res;
+
+        1
     }
 
     // NOTE: ANDI DOESN'T SIGN EXTEND
-    pub fn andi (&mut self, bus: &mut Bus, instr: u16) {
-        let reg1_index = instr as usize & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
-        let imm = self.consume_halfword(bus);
+    pub fn andi (&mut self, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
+        let imm = instr.imm;
         let reg1 = self.regs.gprs[reg1_index];
-        let res = reg1 & imm as u32; 
+        let res = reg1 & imm; 
 
         self.regs.psw.set_sign(false);
         self.regs.psw.set_overflow(false);
         self.regs.psw.set_zero(res == 0);
         self.regs.gprs[reg2_index] = res;
+
+        1
+    }
+
+    // NOTE: ORI DOESN'T SIGN EXTEND
+    pub fn ori (&mut self, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
+        let imm = instr.imm;
+        let reg1 = self.regs.gprs[reg1_index];
+        let res = reg1 | imm; 
+
+        self.regs.psw.set_overflow(false);
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.gprs[reg2_index] = res;
+
+        1
     }
 
-     // NOTE: ORI DOESN'T SIGN EXTEND
-     pub fn ori (&mut self, bus: &mut Bus, instr: u16) {
-        let reg1_index = instr as usize & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
-        let imm = self.consume_halfword(bus);
+    // reg2 = reg1 ^ (zero extend) imm
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Overflow
+    // Opcode: 0b101110
+    pub fn xori (&mut self, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
+        let imm = instr.imm;
         let reg1 = self.regs.gprs[reg1_index];
-        let res = reg1 | imm as u32; 
+        let res = reg1 ^ imm;
+
+        self.regs.psw.set_overflow(false);
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.gprs[reg2_index] = res;
+
+        1
+    }
+
+    // reg2 = reg2 & reg1
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Overflow
+    // Opcode: 0b001101
+    pub fn and (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let res = self.regs.gprs[reg2_index] & self.regs.gprs[instr.reg1];
 
         self.regs.psw.set_overflow(false);
         self.regs.psw.set_sign_and_zero(res);
         self.regs.gprs[reg2_index] = res;
+
+        1
+    }
+
+    // reg2 = reg2 | reg1
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Overflow
+    // Opcode: 0b001100
+    pub fn or (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let res = self.regs.gprs[reg2_index] | self.regs.gprs[instr.reg1];
+
+        self.regs.psw.set_overflow(false);
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.gprs[reg2_index] = res;
+
+        1
+    }
+
+    // reg2 = reg2 ^ reg1
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Overflow
+    // Opcode: 0b001110
+    pub fn xor (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let res = self.regs.gprs[reg2_index] ^ self.regs.gprs[instr.reg1];
+
+        self.regs.psw.set_overflow(false);
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.gprs[reg2_index] = res;
+
+        1
+    }
+
+    // reg2 = !reg1
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Overflow
+    // Opcode: 0b001111
+    pub fn not (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let res = !self.regs.gprs[instr.reg1];
+
+        self.regs.psw.set_overflow(false);
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.gprs[reg2_index] = res;
+
+        1
+    }
+
+    // reg2 = reg2 << (reg1 & 0x1F)
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Carry, Overflow
+    // Opcode: 0b000100
+    pub fn shl_reg (&mut self, instr: Instr) -> u32 {
+        let amount = self.regs.gprs[instr.reg1] & 0x1F;
+        self.shl(instr.reg2, amount);
+
+        1
+    }
+
+    // reg2 = reg2 << (zero extend) imm
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Carry, Overflow
+    // Opcode: 0b010100
+    pub fn shl_imm (&mut self, instr: Instr) -> u32 {
+        self.shl(instr.reg2, instr.imm);
+
+        1
+    }
+
+    // reg2 = reg2 >> (reg1 & 0x1F), shifting in zeroes
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Carry, Overflow
+    // Opcode: 0b000101
+    pub fn shr_reg (&mut self, instr: Instr) -> u32 {
+        let amount = self.regs.gprs[instr.reg1] & 0x1F;
+        self.shr(instr.reg2, amount);
+
+        1
+    }
+
+    // reg2 = reg2 >> (zero extend) imm, shifting in zeroes
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Carry, Overflow
+    // Opcode: 0b010101
+    pub fn shr_imm (&mut self, instr: Instr) -> u32 {
+        self.shr(instr.reg2, instr.imm);
+
+        1
+    }
+
+    // reg2 = reg2 >> (reg1 & 0x1F), shifting in copies of the sign bit
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Carry, Overflow
+    // Opcode: 0b000111
+    pub fn sar_reg (&mut self, instr: Instr) -> u32 {
+        let amount = self.regs.gprs[instr.reg1] & 0x1F;
+        self.sar(instr.reg2, amount);
+
+        1
+    }
+
+    // reg2 = reg2 >> (zero extend) imm, shifting in copies of the sign bit
+    // Cycles: 1
+    // Flags affected: Zero, Sign, Carry, Overflow
+    // Opcode: 0b010111
+    pub fn sar_imm (&mut self, instr: Instr) -> u32 {
+        self.sar(instr.reg2, instr.imm);
+
+        1
+    }
+
+    // Shared by both forms of SHL. Carry is the last bit shifted out, or 0 if the shift amount is 0
+    fn shl (&mut self, reg2_index: usize, amount: u32) {
+        let reg2 = self.regs.gprs[reg2_index];
+        let res = reg2 << amount;
+
+        self.regs.psw.set_carry(amount != 0 && (reg2 >> (32 - amount)) & 1 != 0);
+        self.regs.psw.set_overflow(false);
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.gprs[reg2_index] = res;
+    }
+
+    // Shared by both forms of SHR. Carry is the last bit shifted out, or 0 if the shift amount is 0
+    fn shr (&mut self, reg2_index: usize, amount: u32) {
+        let reg2 = self.regs.gprs[reg2_index];
+        let res = reg2 >> amount;
+
+        self.regs.psw.set_carry(amount != 0 && (reg2 >> (amount - 1)) & 1 != 0);
+        self.regs.psw.set_overflow(false);
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.gprs[reg2_index] = res;
+    }
+
+    // Shared by both forms of SAR. Carry is the last bit shifted out, or 0 if the shift amount is 0
+    fn sar (&mut self, reg2_index: usize, amount: u32) {
+        let reg2 = self.regs.gprs[reg2_index];
+        let res = ((reg2 as i32) >> amount) as u32;
+
+        self.regs.psw.set_carry(amount != 0 && (reg2 >> (amount - 1)) & 1 != 0);
+        self.regs.psw.set_overflow(false);
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.gprs[reg2_index] = res;
+    }
+
+    // reg2 = 1 if the condition in the low 4 bits of imm is satisfied, 0 otherwise
+    // Cycles: 1
+    // Flags affected: none
+    // Opcode: 0b010010
+    pub fn setf (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let cond = instr.cond;
+
+        self.regs.gprs[reg2_index] = self.regs.psw.satisfies_cond(cond) as u32;
+
+        1
     }
 
     // (discard) reg2 - (sign extend) imm
     // Cycles: 1
     // Flags affected: Zero, Sign, Carry, Overflow
     // Opcode: 0b010011
-    pub fn cmp_imm (&mut self, bus: &mut Bus, instr: u16) {
-        let reg2_index = (instr >> 5 & 0x1F) as usize;
+    pub fn cmp_imm (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
         let reg2 = self.regs.gprs[reg2_index];
-        let imm = ((instr as i32) << 27 >> 27) as u32; // sign extend immediate
+        let imm = instr.imm;
 
         let (res, overflow) = (reg2 as i32).overflowing_sub(imm as i32);
         let res = res as u32;
         self.regs.psw.set_sign_and_zero(res);
         self.regs.psw.set_carry(imm > reg2); // Set carry if the result wrapped around.
         self.regs.psw.set_overflow(overflow);
+
+        1
     }
 
     // (discard) reg2 - reg1
     // Cycles: 1
     // Flags affected: Zero, Sign, Carry, Overflow
     // Opcode: 0b000011
-    pub fn cmp_reg (&mut self, bus: &mut Bus, instr: u16) {
-        let reg2_index = (instr >> 5 & 0x1F) as usize;
+    pub fn cmp_reg (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
         let reg2 = self.regs.gprs[reg2_index];
-        let reg1 = self.regs.gprs[instr as usize & 0x1F];
+        let reg1 = self.regs.gprs[instr.reg1];
 
         let (res, overflow) = (reg2 as i32).overflowing_sub(reg1 as i32);
         let res = res as u32;
         self.regs.psw.set_sign_and_zero(res);
         self.regs.psw.set_carry(reg1 > reg2); // Set carry if the result wrapped around.
         self.regs.psw.set_overflow(overflow);
+
+        1
     }
 
-    pub fn div (&mut self, instr: u16) {
-        let reg2_index = (instr as usize >> 5) & 0x1F;
-        let reg1 = self.regs.gprs[instr as usize & 0x1F];
+    // r30 = (signed) reg2 MOD reg1. reg2 = (signed) reg2 / reg1
+    // The remainder has the sign of the dividend. 0x80000000 / -1 overflows, giving a quotient of 0x80000000 and a remainder of 0
+    // A divisor of 0 raises a zero division exception and leaves the registers and flags untouched
+    // Cycles: 38
+    // Flags affected: Zero, Sign, Overflow
+    // Opcode: 0b001001
+    pub fn div (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let reg1 = self.regs.gprs[instr.reg1];
         let reg2 = self.regs.gprs[reg2_index];
 
+        if reg1 == 0 {
+            self.raise_exception(codes::ZERO_DIVISION, self.regs.pc.wrapping_sub(instr.len));
+            return 38;
+        }
+
         let (res, overflow) = (reg2 as i32).overflowing_div(reg1 as i32);
-        self.regs.gprs[30] = reg2 % reg1; // Reg2 MOD reg1 is stored in r30 during a DIV instruction
+        let (rem, _) = (reg2 as i32).overflowing_rem(reg1 as i32);
+        self.regs.gprs[30] = rem as u32; // Reg2 MOD reg1 is stored in r30 during a DIV instruction
         self.regs.gprs[reg2_index] = res as u32;
 
         self.regs.psw.set_sign_and_zero(res as u32);
         self.regs.psw.set_overflow(overflow);
+
+        38
     }
 
-    pub fn mul (&mut self, instr: u16) {
-        let reg2_index = (instr as usize >> 5) & 0x1F;
-        let reg1 = self.regs.gprs[instr as usize & 0x1F];
+    // res = (signed) reg2 * (signed) reg1. r30 = (res >> 32). reg2 = (res & 0xFFFFFFFF)
+    // Cycles: 13
+    // Flags affected: Zero, Sign (both from the lower 32 bits), Overflow
+    // Opcode: 0b001000
+    pub fn mul (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let reg1 = self.regs.gprs[instr.reg1];
         let reg2 = self.regs.gprs[reg2_index];
 
-        
-        let (res, overflow) = (reg2 as i32 as i64).overflowing_mul(reg1 as i32 as i64);
+        let res = reg2 as i32 as i64 * reg1 as i32 as i64;
         self.regs.gprs[30] = (res >> 32) as u32; // MUL is a 64-bit signed multiplication. Upper 32 bits are stored in r30
         self.regs.gprs[reg2_index] = res as u32; // Lower 32 bits are stored in reg2
+
+        self.regs.psw.set_sign_and_zero(res as u32);
+        self.regs.psw.set_overflow(res != res as i32 as i64); // Set if the result doesn't fit in a signed 32-bit integer
+
+        13
+    }
+
+    // res = (unsigned) reg2 * (unsigned) reg1. r30 = (res >> 32). reg2 = (res & 0xFFFFFFFF)
+    // Cycles: 13
+    // Flags affected: Zero, Sign, Overflow
+    // Opcode: 0b001010
+    pub fn mulu (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let reg1 = self.regs.gprs[instr.reg1];
+        let reg2 = self.regs.gprs[reg2_index];
+
+        let res = reg2 as u64 * reg1 as u64;
+        self.regs.gprs[30] = (res >> 32) as u32; // Upper 32 bits are stored in r30
+        self.regs.gprs[reg2_index] = res as u32; // Lower 32 bits are stored in reg2
+
+        self.regs.psw.set_sign_and_zero(res as u32);
+        self.regs.psw.set_overflow(res > u32::MAX as u64); // Set if the result doesn't fit in 32 bits
+
+        13
+    }
+
+    // r30 = (unsigned) reg2 MOD reg1. reg2 = (unsigned) reg2 / reg1
+    // A divisor of 0 raises a zero division exception and leaves the registers and flags untouched
+    // Cycles: 36
+    // Flags affected: Zero, Sign, Overflow
+    // Opcode: 0b001011
+    pub fn divu (&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let reg1 = self.regs.gprs[instr.reg1];
+        let reg2 = self.regs.gprs[reg2_index];
+
+        if reg1 == 0 {
+            self.raise_exception(codes::ZERO_DIVISION, self.regs.pc.wrapping_sub(instr.len));
+            return 36;
+        }
+
+        let res = reg2 / reg1;
+        self.regs.gprs[30] = reg2 % reg1; // Remainder is stored in r30
+        self.regs.gprs[reg2_index] = res;
+
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.psw.set_overflow(false); // Unsigned division can't overflow
+
+        36
     }
 }
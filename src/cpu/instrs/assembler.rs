@@ -0,0 +1,766 @@
+use super::decoder::{Format, Op, BCOND_MNEMONICS};
+use super::opcodes::{self, bit_string, extended};
+use super::system_regs;
+use crate::mem::MIN_ROM_SIZE;
+use std::collections::HashMap;
+use std::fmt;
+
+/*
+    V810 assembler, meant for writing test programs.
+
+    The syntax is the NEC one the disassembler outputs: one instruction or directive per line, "; comments",
+    and "label:" definitions, which can share a line with an instruction. Mnemonics and register names are case insensitive.
+    Registers are r0-r31, with sp (r3), gp (r4), tp (r5) and lp (r31) as aliases.
+    System registers go by name (psw, chcw...) or number (sr29, or just 29).
+
+    Numbers are decimal, hex (0x) or binary (0b). Wherever a number is expected, an expression made of numbers, labels,
+    + and - can be used, along with hi(x) and lo(x): the upper and lower halves of x for loading it with MOVHI and MOVEA
+    (hi is adjusted for MOVEA sign extending the lower half). Branches take the absolute address of their target.
+
+    Pseudo-instructions:
+    mov imm32, reg: load any 32-bit value. Assembles to a plain MOV when the value is a number that fits in 5 bits,
+                    to MOVEA if it fits in 16 bits, and to MOVHI + MOVEA otherwise, or whenever it uses labels
+
+    Directives:
+    .org address:       continue assembling at this address
+    .db/.dh/.dw values: emit bytes, halfwords or words
+
+    Code goes in ROM, either from 0x07000000 onwards or counting down from the end of the address space,
+    like the vectors at 0xFFFFFE00-0xFFFFFFFF, without wrapping around past the end.
+    to_rom lays both out in a ROM image padded to a power of two.
+*/
+
+const ROM_START: u32 = 0x07000000;
+const ROM_END: u64 = 0x08000000;
+const MIRROR_START: u32 = 0xFF000000; // Addresses from here to the end of the address space count down from the end of ROM
+const ADDRESS_SPACE_END: u64 = 1 << 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub line: Option<usize>, // 1-based line the error is on, if it's about a specific line
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+// A run of assembled bytes starting at "addr"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Assembly {
+    pub sections: Vec<Section>,
+    pub labels: HashMap<String, u32>,
+}
+
+impl Assembly {
+    // Lay the sections out in a ROM image, padded with 0xFF to a power of two, and to at least MIN_ROM_SIZE.
+    // Sections at 0x07000000 onwards go at the start of the image, and sections near the end of the address space at its end
+    pub fn to_rom(&self) -> Result<Vec<u8>, Error> {
+        let mut low_end = 0; // End of the sections at the start of ROM
+        let mut high_size = 0; // Space taken by the sections at the end of ROM
+
+        for section in &self.sections {
+            let end = section_end(section);
+            if section.addr >= ROM_START && end <= ROM_END {
+                low_end = low_end.max(end - ROM_START as u64);
+            } else if section.addr >= MIRROR_START && end <= ADDRESS_SPACE_END {
+                high_size = high_size.max(ADDRESS_SPACE_END - section.addr as u64);
+            } else if end > ADDRESS_SPACE_END {
+                let message = format!("code at {:08X} goes past the end of the address space", section.addr);
+                return Err(Error { line: None, message });
+            } else {
+                return Err(Error { line: None, message: format!("code at {:08X} is outside ROM", section.addr) });
+            }
+        }
+
+        let size = ((low_end + high_size).next_power_of_two() as usize).max(MIN_ROM_SIZE);
+        let mut rom = vec![0xFF; size];
+        let mut written = vec![false; size];
+
+        for section in &self.sections {
+            let start = if section.addr >= MIRROR_START {
+                size - (ADDRESS_SPACE_END - section.addr as u64) as usize
+            } else {
+                (section.addr - ROM_START) as usize
+            };
+
+            for (i, &byte) in section.data.iter().enumerate() {
+                if written[start + i] {
+                    let message = format!("code at {:08X} overlaps with other code in the ROM", section.addr as usize + i);
+                    return Err(Error { line: None, message });
+                }
+
+                rom[start + i] = byte;
+                written[start + i] = true;
+            }
+        }
+
+        Ok(rom)
+    }
+}
+
+// Address right after a section, which is 1 << 32 for sections at the very end of the address space
+fn section_end(section: &Section) -> u64 {
+    section.addr as u64 + section.data.len() as u64
+}
+
+// Every instruction with a mnemonic of its own. Bcond goes by the condition mnemonics instead
+const OPS: &[Op] = {
+    use Op::*;
+
+    &[
+        MovReg, AddReg, Sub, CmpReg, ShlReg, ShrReg, Jmp, SarReg, Mul, Div, Mulu, Divu, Or, And, Xor, Not,
+        MovImm, AddImm, Setf, CmpImm, ShlImm, ShrImm, Cli, SarImm, Trap, Reti, Halt, Ldsr, Stsr, Sei,
+        Sch0bsu, Sch0bsd, Sch1bsu, Sch1bsd, Orbsu, Andbsu, Xorbsu, Movbsu, Ornbsu, Andnbsu, Xornbsu, Notbsu,
+        Jr, Jal, Movea, Addi, Ori, Andi, Xori, Movhi,
+        LdB, LdH, LdW, StB, StH, StW, InB, InH, InW, OutB, OutH, OutW, Caxi,
+        CmpfS, CvtWs, CvtSw, AddfS, SubfS, MulfS, DivfS, TrncSw, Xb, Xh, Rev, Mpyhw,
+    ]
+};
+
+// Alternative names for some branch conditions
+const BCOND_ALIASES: &[(&str, u16)] = &[("bl", 1), ("bz", 2), ("bnl", 9), ("bnz", 10)];
+
+// Where the opcode of an instruction goes
+enum Encoding {
+    Opcode(u16),    // Top 6 bits of the first halfword
+    BitString(u16), // Sub-opcode of a bit string instruction
+    Extended(u16),  // Sub-opcode of a format VII instruction
+}
+
+fn encoding(op: Op) -> Encoding {
+    use Encoding::*;
+
+    match op {
+        Op::MovReg => Opcode(opcodes::MOV_REG),
+        Op::AddReg => Opcode(opcodes::ADD_REG),
+        Op::Sub => Opcode(opcodes::SUB),
+        Op::CmpReg => Opcode(opcodes::CMP_REG),
+        Op::ShlReg => Opcode(opcodes::SHL_REG),
+        Op::ShrReg => Opcode(opcodes::SHR_REG),
+        Op::Jmp => Opcode(opcodes::JMP),
+        Op::SarReg => Opcode(opcodes::SAR_REG),
+        Op::Mul => Opcode(opcodes::MUL),
+        Op::Div => Opcode(opcodes::DIV),
+        Op::Mulu => Opcode(opcodes::MULU),
+        Op::Divu => Opcode(opcodes::DIVU),
+        Op::Or => Opcode(opcodes::OR),
+        Op::And => Opcode(opcodes::AND),
+        Op::Xor => Opcode(opcodes::XOR),
+        Op::Not => Opcode(opcodes::NOT),
+
+        Op::MovImm => Opcode(opcodes::MOV_IMM),
+        Op::AddImm => Opcode(opcodes::ADDI_SHORT),
+        Op::Setf => Opcode(opcodes::SETF),
+        Op::CmpImm => Opcode(opcodes::CMP_IMM),
+        Op::ShlImm => Opcode(opcodes::SHL_IMM),
+        Op::ShrImm => Opcode(opcodes::SHR_IMM),
+        Op::Cli => Opcode(opcodes::CLI),
+        Op::SarImm => Opcode(opcodes::SAR_IMM),
+        Op::Trap => Opcode(opcodes::TRAP),
+        Op::Reti => Opcode(opcodes::RETI),
+        Op::Halt => Opcode(opcodes::HALT),
+        Op::Ldsr => Opcode(opcodes::LDSR),
+        Op::Stsr => Opcode(opcodes::STSR),
+        Op::Sei => Opcode(opcodes::SEI),
+
+        Op::Sch0bsu => BitString(bit_string::SCH0BSU),
+        Op::Sch0bsd => BitString(bit_string::SCH0BSD),
+        Op::Sch1bsu => BitString(bit_string::SCH1BSU),
+        Op::Sch1bsd => BitString(bit_string::SCH1BSD),
+        Op::Orbsu => BitString(bit_string::ORBSU),
+        Op::Andbsu => BitString(bit_string::ANDBSU),
+        Op::Xorbsu => BitString(bit_string::XORBSU),
+        Op::Movbsu => BitString(bit_string::MOVBSU),
+        Op::Ornbsu => BitString(bit_string::ORNBSU),
+        Op::Andnbsu => BitString(bit_string::ANDNBSU),
+        Op::Xornbsu => BitString(bit_string::XORNBSU),
+        Op::Notbsu => BitString(bit_string::NOTBSU),
+
+        Op::Bcond | Op::Illegal => Opcode(opcodes::BCOND_START), // The condition is added separately
+        Op::Jr => Opcode(opcodes::JR),
+        Op::Jal => Opcode(opcodes::JAL),
+
+        Op::Movea => Opcode(opcodes::MOVEA),
+        Op::Addi => Opcode(opcodes::ADDI_LONG),
+        Op::Ori => Opcode(opcodes::ORI),
+        Op::Andi => Opcode(opcodes::ANDI),
+        Op::Xori => Opcode(opcodes::XORI),
+        Op::Movhi => Opcode(opcodes::MOVHI),
+
+        Op::LdB => Opcode(opcodes::LD_BYTE),
+        Op::LdH => Opcode(opcodes::LD_HALFWORD),
+        Op::LdW => Opcode(opcodes::LD_WORD),
+        Op::StB => Opcode(opcodes::ST_BYTE),
+        Op::StH => Opcode(opcodes::ST_HALFWORD),
+        Op::StW => Opcode(opcodes::ST_WORD),
+        Op::InB => Opcode(opcodes::IN_BYTE),
+        Op::InH => Opcode(opcodes::IN_HALFWORD),
+        Op::InW => Opcode(opcodes::IN_WORD),
+        Op::OutB => Opcode(opcodes::OUT_BYTE),
+        Op::OutH => Opcode(opcodes::OUT_HALFWORD),
+        Op::OutW => Opcode(opcodes::OUT_WORD),
+        Op::Caxi => Opcode(opcodes::CAXI),
+
+        Op::CmpfS => Extended(extended::CMPF_S),
+        Op::CvtWs => Extended(extended::CVT_WS),
+        Op::CvtSw => Extended(extended::CVT_SW),
+        Op::AddfS => Extended(extended::ADDF_S),
+        Op::SubfS => Extended(extended::SUBF_S),
+        Op::MulfS => Extended(extended::MULF_S),
+        Op::DivfS => Extended(extended::DIVF_S),
+        Op::TrncSw => Extended(extended::TRNC_SW),
+        Op::Xb => Extended(extended::XB),
+        Op::Xh => Extended(extended::XH),
+        Op::Rev => Extended(extended::REV),
+        Op::Mpyhw => Extended(extended::MPYHW),
+    }
+}
+
+// A line after splitting off its label and comment
+struct Statement<'a> {
+    line: usize,
+    addr: u32,
+    mnemonic: String, // Lowercase
+    operands: Vec<&'a str>,
+}
+
+// Assemble a whole program
+pub fn assemble(source: &str) -> Result<Assembly, Error> {
+    let mut assembly = Assembly::default();
+    let mut statements = vec![];
+    let mut addr = ROM_START as u64; // Can end up at 1 << 32, right after code at the very end of the address space
+
+    // First pass: find where everything goes and define labels
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |message: String| Error { line: Some(line), message };
+
+        let mut text = text.split(';').next().unwrap().trim();
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_identifier(label) {
+                return Err(error(format!("invalid label name \"{}\"", label)));
+            }
+            if assembly.labels.insert(label.to_string(), addr as u32).is_some() {
+                return Err(error(format!("label \"{}\" is defined more than once", label)));
+            }
+            text = rest.trim();
+        }
+
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = match text.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (mnemonic.to_lowercase(), split_operands(operands)),
+            None => (text.to_lowercase(), vec![]),
+        };
+
+        if mnemonic == ".org" {
+            if operands.len() != 1 {
+                return Err(error(".org expects an address".to_string()));
+            }
+            addr = eval(operands[0], &assembly.labels).and_then(to_u32).map_err(error)? as u64;
+            continue;
+        }
+
+        let statement = Statement { line, addr: addr as u32, mnemonic, operands };
+        let end = addr + size(&statement).map_err(error)? as u64;
+        if addr >= ADDRESS_SPACE_END || end > ADDRESS_SPACE_END {
+            return Err(error("code goes past the end of the address space".to_string()));
+        }
+        addr = end;
+        statements.push(statement);
+    }
+
+    // Second pass: encode everything, now that every label is known
+    for statement in statements {
+        let bytes = encode_statement(&statement, &assembly.labels)
+            .map_err(|message| Error { line: Some(statement.line), message })?;
+
+        match assembly.sections.last_mut() {
+            Some(section) if section_end(section) == statement.addr as u64 => section.data.extend(bytes),
+            _ => assembly.sections.push(Section { addr: statement.addr, data: bytes }),
+        }
+    }
+
+    Ok(assembly)
+}
+
+// Size in bytes of what a statement assembles to
+fn size(statement: &Statement) -> Result<u32, String> {
+    let count = statement.operands.len() as u32;
+
+    match statement.mnemonic.as_str() {
+        ".db" => Ok(count),
+        ".dh" => Ok(count * 2),
+        ".dw" => Ok(count * 4),
+        "mov" if is_mov_pseudo(statement) => Ok(mov_pseudo_size(statement.operands[0])),
+        mnemonic => Ok(match find_op(mnemonic, &statement.operands)?.0.format() {
+            Format::I | Format::II | Format::III => 2,
+            _ => 4,
+        }),
+    }
+}
+
+fn encode_statement(statement: &Statement, labels: &HashMap<String, u32>) -> Result<Vec<u8>, String> {
+    let eval = |text: &str| eval(text, labels);
+    let operands = &statement.operands;
+
+    let (size, values) = match statement.mnemonic.as_str() {
+        ".db" => (1, operands.iter().map(|text| eval(text).and_then(|val| check_range(val, -0x80, 0xFF))).collect::<Result<Vec<_>, _>>()?),
+        ".dh" => (2, operands.iter().map(|text| eval(text).and_then(|val| check_range(val, -0x8000, 0xFFFF))).collect::<Result<Vec<_>, _>>()?),
+        ".dw" => (4, operands.iter().map(|text| eval(text).and_then(to_u32).map(|val| val as i64)).collect::<Result<Vec<_>, _>>()?),
+
+        _ => {
+            if statement.addr & 1 != 0 {
+                return Err("instructions must be at even addresses".to_string());
+            }
+
+            let halfwords = if statement.mnemonic == "mov" && is_mov_pseudo(statement) {
+                encode_mov_pseudo(operands, labels)?
+            } else {
+                let (op, cond) = find_op(&statement.mnemonic, operands)?;
+                encode(op, cond, operands, statement.addr, labels)?
+            };
+            (2, halfwords.into_iter().map(|halfword| halfword as i64).collect())
+        }
+    };
+
+    Ok(values.into_iter().flat_map(|val| (val as u32).to_le_bytes()[..size].to_vec()).collect())
+}
+
+// Find the instruction a mnemonic refers to, along with its condition for Bcond.
+// Some mnemonics have a register and an immediate form, told apart by their first operand
+fn find_op(mnemonic: &str, operands: &[&str]) -> Result<(Op, u16), String> {
+    if let Some(cond) = BCOND_MNEMONICS.iter().position(|&name| name == mnemonic) {
+        return Ok((Op::Bcond, cond as u16));
+    }
+    if let Some(&(_, cond)) = BCOND_ALIASES.iter().find(|&&(name, _)| name == mnemonic) {
+        return Ok((Op::Bcond, cond));
+    }
+
+    let first_is_reg = operands.first().is_some_and(|operand| parse_reg(operand).is_some());
+    let mut candidates = OPS.iter().filter(|op| op.mnemonic() == mnemonic);
+
+    match (candidates.next(), candidates.next()) {
+        (Some(&op), None) => Ok((op, 0)),
+        (Some(&reg_form), Some(&imm_form)) => Ok((if first_is_reg { reg_form } else { imm_form }, 0)),
+        _ => Err(format!("unknown instruction \"{}\"", mnemonic)),
+    }
+}
+
+fn encode(op: Op, cond: u16, operands: &[&str], pc: u32, labels: &HashMap<String, u32>) -> Result<Vec<u16>, String> {
+    let expected = match op {
+        Op::Cli | Op::Sei | Op::Reti | Op::Halt => 0,
+        Op::Sch0bsu | Op::Sch0bsd | Op::Sch1bsu | Op::Sch1bsd |
+        Op::Orbsu | Op::Andbsu | Op::Xorbsu | Op::Movbsu | Op::Ornbsu | Op::Andnbsu | Op::Xornbsu | Op::Notbsu => 0,
+        Op::Bcond if cond == 0xD => 0, // NOP
+        Op::Jmp | Op::Trap | Op::Bcond | Op::Jr | Op::Jal | Op::Xb | Op::Xh => 1,
+        _ if op.format() == Format::V => 3,
+        _ => 2,
+    };
+    if operands.len() != expected {
+        return Err(format!("{} expects {} operand(s), got {}", op.mnemonic(), expected, operands.len()));
+    }
+
+    let eval = |text: &str| eval(text, labels);
+    let reg = |text: &str| parse_reg(text).ok_or_else(|| format!("expected a register, got \"{}\"", text));
+    let displacement = |text: &str, bits: u32| {
+        let target = eval(text).and_then(to_u32)?;
+        let disp = target.wrapping_sub(pc) as i32 as i64;
+        if disp & 1 != 0 {
+            return Err(format!("branch target {:08X} is at an odd address", target));
+        }
+        check_range(disp, -(1 << (bits - 1)), (1 << (bits - 1)) - 2)
+            .map_err(|_| format!("branch target {:08X} is out of range", target))
+    };
+
+    let (opcode, sub_opcode) = match encoding(op) {
+        Encoding::Opcode(opcode) => (opcode, 0),
+        Encoding::BitString(sub_opcode) => (opcodes::BIT_STRING, sub_opcode),
+        Encoding::Extended(sub_opcode) => (opcodes::FORMAT_VII, sub_opcode),
+    };
+    let first = |reg2: usize, reg1: u16| opcode << 10 | (reg2 as u16) << 5 | reg1;
+
+    let halfwords = match op.format() {
+        Format::I => match op {
+            Op::Jmp => {
+                let target = operands[0].trim();
+                let inner = target.strip_prefix('[').and_then(|text| text.strip_suffix(']'))
+                    .ok_or_else(|| format!("expected [reg], got \"{}\"", target))?;
+                vec![first(0, reg(inner)? as u16)]
+            }
+            _ => vec![first(reg(operands[1])?, reg(operands[0])? as u16)],
+        },
+
+        Format::II => match op {
+            _ if expected == 0 => vec![first(0, sub_opcode)],
+            Op::Trap => vec![first(0, check_range(eval(operands[0])?, 0, 31)? as u16)],
+            Op::Setf => vec![first(reg(operands[1])?, check_range(eval(operands[0])?, 0, 15)? as u16)],
+            Op::Ldsr => vec![first(reg(operands[0])?, parse_system_reg(operands[1], labels)?)],
+            Op::Stsr => vec![first(reg(operands[1])?, parse_system_reg(operands[0], labels)?)],
+            Op::MovImm | Op::AddImm | Op::CmpImm => vec![first(reg(operands[1])?, (check_range(eval(operands[0])?, -16, 15)? & 0x1F) as u16)],
+            _ => vec![first(reg(operands[1])?, check_range(eval(operands[0])?, 0, 31)? as u16)], // Shifts
+        },
+
+        Format::III => {
+            let disp = if cond == 0xD { 0 } else { displacement(operands[0], 9)? };
+            vec![opcodes::BCOND_START << 10 | cond << 9 | (disp & 0x1FF) as u16]
+        }
+
+        Format::IV => {
+            let disp = displacement(operands[0], 26)? as u32;
+            vec![opcode << 10 | (disp >> 16 & 0x3FF) as u16, disp as u16]
+        }
+
+        Format::V => {
+            let imm = check_range(eval(operands[0])?, -0x8000, 0xFFFF)?;
+            vec![first(reg(operands[2])?, reg(operands[1])? as u16), imm as u16]
+        }
+
+        Format::VI => {
+            let (memory, reg2) = match op {
+                Op::StB | Op::StH | Op::StW | Op::OutB | Op::OutH | Op::OutW => (operands[1], operands[0]),
+                _ => (operands[0], operands[1]),
+            };
+            let (disp, reg1) = parse_memory(memory, labels)?;
+            vec![first(reg(reg2)?, reg1 as u16), disp as u16]
+        }
+
+        Format::VII => {
+            let (reg1, reg2) = match op {
+                Op::Xb | Op::Xh => (0, reg(operands[0])?),
+                _ => (reg(operands[0])?, reg(operands[1])?),
+            };
+            vec![first(reg2, reg1 as u16), sub_opcode << 10]
+        }
+    };
+
+    Ok(halfwords)
+}
+
+// MOV with an immediate that might not fit in 5 bits
+fn is_mov_pseudo(statement: &Statement) -> bool {
+    statement.operands.len() == 2 && parse_reg(statement.operands[0]).is_none()
+}
+
+// The size is decided in the first pass, so values that depend on labels always get the longest form
+fn mov_pseudo_size(value: &str) -> u32 {
+    match eval(value, &HashMap::new()) {
+        Ok(-16..=15) => 2,
+        Ok(-0x8000..=0x7FFF) => 4,
+        _ => 8,
+    }
+}
+
+fn encode_mov_pseudo(operands: &[&str], labels: &HashMap<String, u32>) -> Result<Vec<u16>, String> {
+    let value = eval(operands[0], labels).and_then(to_u32)?;
+    let reg = parse_reg(operands[1]).ok_or_else(|| format!("expected a register, got \"{}\"", operands[1]))? as u16;
+
+    let halfwords = match mov_pseudo_size(operands[0]) {
+        2 => vec![opcodes::MOV_IMM << 10 | reg << 5 | (value & 0x1F) as u16],
+        4 => vec![opcodes::MOVEA << 10 | reg << 5, value as u16],
+        _ => vec![
+            opcodes::MOVHI << 10 | reg << 5, hi(value),
+            opcodes::MOVEA << 10 | reg << 5 | reg, value as u16,
+        ],
+    };
+
+    Ok(halfwords)
+}
+
+// Upper half of a value to be loaded with MOVHI followed by MOVEA, which sign extends the lower half
+fn hi(value: u32) -> u16 {
+    (value.wrapping_add(0x8000) >> 16) as u16
+}
+
+// Split operands on commas, except for those inside parentheses
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut operands = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    operands.push(text[start..].trim());
+    operands
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn parse_reg(text: &str) -> Option<usize> {
+    let text = text.trim().to_lowercase();
+
+    match text.as_str() {
+        "sp" => Some(3),
+        "gp" => Some(4),
+        "tp" => Some(5),
+        "lp" => Some(31),
+        _ => text.strip_prefix('r')?.parse().ok().filter(|&reg| reg < 32),
+    }
+}
+
+fn parse_system_reg(text: &str, labels: &HashMap<String, u32>) -> Result<u16, String> {
+    let name = text.trim().to_lowercase();
+    if let Some(id) = (0..32).find(|&id| system_regs::name(id) == Some(name.as_str())) {
+        return Ok(id);
+    }
+
+    let number = name.strip_prefix("sr").unwrap_or(&name);
+    Ok(check_range(eval(number, labels)?, 0, 31)? as u16)
+}
+
+// Parses disp[reg], where the displacement is optional
+fn parse_memory(text: &str, labels: &HashMap<String, u32>) -> Result<(i64, usize), String> {
+    let text = text.trim();
+    let (disp, reg) = text.strip_suffix(']').and_then(|text| text.rsplit_once('['))
+        .ok_or_else(|| format!("expected disp[reg], got \"{}\"", text))?;
+
+    let disp = if disp.trim().is_empty() { 0 } else { check_range(eval(disp, labels)?, -0x8000, 0x7FFF)? };
+    let reg = parse_reg(reg).ok_or_else(|| format!("expected a register, got \"{}\"", reg))?;
+
+    Ok((disp, reg))
+}
+
+fn check_range(val: i64, min: i64, max: i64) -> Result<i64, String> {
+    if (min..=max).contains(&val) {
+        Ok(val)
+    } else {
+        Err(format!("{} doesn't fit in {}..{}", val, min, max))
+    }
+}
+
+fn to_u32(val: i64) -> Result<u32, String> {
+    check_range(val, i32::MIN as i64, u32::MAX as i64).map(|val| val as u32)
+}
+
+// Evaluate an expression: numbers and labels, added and subtracted, along with hi() and lo()
+fn eval(text: &str, labels: &HashMap<String, u32>) -> Result<i64, String> {
+    let mut parser = ExprParser { text: text.trim(), pos: 0, labels };
+    let val = parser.expr()?;
+
+    parser.skip_spaces();
+    if parser.pos != parser.text.len() {
+        return Err(format!("invalid expression \"{}\"", text.trim()));
+    }
+
+    Ok(val)
+}
+
+struct ExprParser<'a> {
+    text: &'a str,
+    pos: usize,
+    labels: &'a HashMap<String, u32>,
+}
+
+impl ExprParser<'_> {
+    fn skip_spaces(&mut self) {
+        while let Some(c) = self.text[self.pos..].chars().next().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        if self.text[self.pos..].starts_with(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expr(&mut self) -> Result<i64, String> {
+        let mut val = self.term()?;
+
+        loop {
+            if self.eat('+') {
+                val = val.wrapping_add(self.term()?);
+            } else if self.eat('-') {
+                val = val.wrapping_sub(self.term()?);
+            } else {
+                return Ok(val);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<i64, String> {
+        if self.eat('-') {
+            return self.term()?.checked_neg().ok_or_else(|| format!("value out of range in \"{}\"", self.text));
+        }
+        if self.eat('(') {
+            let val = self.expr()?;
+            return if self.eat(')') { Ok(val) } else { Err("missing \")\"".to_string()) };
+        }
+
+        self.skip_spaces();
+        let rest = &self.text[self.pos..];
+        let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.')).unwrap_or(rest.len());
+        let token = &rest[..len];
+        self.pos += len;
+
+        if token.is_empty() {
+            return Err(format!("invalid expression \"{}\"", self.text));
+        }
+
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            let lower = token.to_lowercase();
+            let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+                i64::from_str_radix(hex, 16)
+            } else if let Some(binary) = lower.strip_prefix("0b") {
+                i64::from_str_radix(binary, 2)
+            } else {
+                lower.parse()
+            };
+            return parsed.map_err(|_| format!("invalid number \"{}\"", token));
+        }
+
+        match token.to_lowercase().as_str() {
+            "hi" | "lo" if self.eat('(') => {
+                let val = to_u32(self.expr()?)?;
+                if !self.eat(')') {
+                    return Err("missing \")\"".to_string());
+                }
+                Ok(if token.eq_ignore_ascii_case("hi") { hi(val) as i64 } else { (val & 0xFFFF) as i64 })
+            }
+            _ => self.labels.get(token).map(|&addr| addr as i64).ok_or_else(|| format!("unknown label \"{}\"", token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instrs::decoder;
+    use crate::bus::Bus;
+    use crate::cpu::instrs::disassembler::disassemble;
+    use crate::mem::Memory;
+
+    const PC: u32 = 0x07000100;
+
+    // Assembles a single line at PC
+    fn assemble_line(line: &str) -> Result<Vec<u8>, Error> {
+        let assembly = assemble(&format!(".org {:#X}\n{}", PC, line))?;
+        Ok(assembly.sections.into_iter().flat_map(|section| section.data).collect())
+    }
+
+    fn error(source: &str) -> String {
+        assemble(source).and_then(|assembly| assembly.to_rom()).unwrap_err().to_string()
+    }
+
+    // Every encoding is disassembled, assembled back and decoded again, which has to give the same instruction.
+    // Fields the instruction ignores can come back different, so the disassemblies get compared rather than the encodings
+    #[test]
+    fn round_trip() {
+        let mut seen = vec![];
+
+        for first in 0..=0xFFFF {
+            let seconds: Vec<u16> = match first >> 10 {
+                opcodes::FORMAT_VII => (0..64).map(|sub_opcode| sub_opcode << 10 | 0x155).collect(), // Every sub-opcode
+                _ if decoder::instr_length(first) == 4 => vec![0x0000, 0x8001, 0x7FFE, 0xFFFF],
+                _ => vec![0],
+            };
+
+            for second in seconds {
+                let instr = decoder::decode(first, second);
+                if instr.op == Op::Illegal {
+                    continue;
+                }
+
+                let text = disassemble(&instr, PC).to_string();
+                let bytes = assemble_line(&text).unwrap_or_else(|error| panic!("{:04X} {:04X} \"{}\": {}", first, second, text, error));
+                assert_eq!(bytes.len() as u32, instr.len, "\"{}\"", text);
+
+                let halfword = |index: usize| u16::from_le_bytes([bytes[index], bytes[index + 1]]);
+                let again = decoder::decode(halfword(0), if instr.len == 4 { halfword(2) } else { 0 });
+                assert_eq!(again.op, instr.op, "\"{}\"", text);
+                assert_eq!(disassemble(&again, PC).to_string(), text);
+
+                if !seen.contains(&instr.op) {
+                    seen.push(instr.op);
+                }
+            }
+        }
+
+        for op in OPS.iter().chain(&[Op::Bcond]) {
+            assert!(seen.contains(op), "{:?} never came up", op);
+        }
+    }
+
+    #[test]
+    fn mov_pseudo() {
+        assert_eq!(assemble_line("mov -16, r1").unwrap(), [0x30, 0x40]);
+        assert_eq!(assemble_line("mov 0x7FFF, r1").unwrap(), [0x20, 0xA0, 0xFF, 0x7F]);
+        // MOVHI 0x1235, r0, r1 then MOVEA 0x8000, r1, r1, as MOVEA sign extends
+        assert_eq!(assemble_line("mov 0x12348000, r1").unwrap(), [0x20, 0xBC, 0x35, 0x12, 0x21, 0xA0, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("mov -(0x7FFFFFFFFFFFFFFF + 1), r1"), "line 1: value out of range in \"-(0x7FFFFFFFFFFFFFFF + 1)\"");
+        assert_eq!(error(".org 0xFFFFFFF0\n.dw 1, 2, 3, 4, 5"), "line 2: code goes past the end of the address space");
+        assert_eq!(error(".org 0xFFFFFFF0\n.dw 1, 2, 3, 4\nhalt"), "line 3: code goes past the end of the address space");
+        assert_eq!(error(".org 0x06000000\nhalt"), "code at 06000000 is outside ROM");
+        assert_eq!(error("halt\n.org 0x07000000\nnop"), "code at 07000000 overlaps with other code in the ROM");
+        assert_eq!(error("jr 0x07000001"), "line 1: branch target 07000001 is at an odd address");
+        assert_eq!(error("label: halt\nlabel: halt"), "line 2: label \"label\" is defined more than once");
+
+        // Whitespace outside of ASCII is skipped whole, in expressions that are fine and ones that aren't
+        assert_eq!(assemble_line(".dw 1 +\u{a0}2").unwrap(), [3, 0, 0, 0]);
+        assert_eq!(error(".dw 1 +\u{a0})"), "line 1: invalid expression \"1 +\u{a0})\"");
+
+        // Sections built by hand go through the same checks
+        let assembly = Assembly { sections: vec![Section { addr: 0xFFFFFFFE, data: vec![0; 4] }], labels: HashMap::new() };
+        assert_eq!(assembly.to_rom().unwrap_err().to_string(), "code at FFFFFFFE goes past the end of the address space");
+    }
+
+    #[test]
+    fn rom_layout() {
+        let rom = assemble("halt\n.org 0xFFFFFFF0\n.dh 1, 2").and_then(|assembly| assembly.to_rom()).unwrap();
+        assert_eq!(rom.len(), 32);
+        assert_eq!(rom[..2], [0x00, 0x68]);
+        assert_eq!(rom[2..16], [0xFF; 14]);
+        assert_eq!(rom[16..20], [1, 0, 2, 0]);
+    }
+
+    // The smallest program still makes a ROM every read can be served from
+    #[test]
+    fn tiny_rom() {
+        let rom = assemble("halt").and_then(|assembly| assembly.to_rom()).unwrap();
+        assert_eq!(rom, [0x00, 0x68, 0xFF, 0xFF]);
+
+        let bus = Bus::from_memory(Memory::from_rom(rom).unwrap());
+        assert_eq!(decoder::decode(bus.peek16(0xFFFFFFF0), 0).op, Op::Halt); // Through the mirrors
+        assert_eq!(bus.peek32(0x07000000), 0xFFFF6800);
+        assert_eq!(bus.read32(0x0700FFFC), 0xFFFF6800);
+
+        assert!(Memory::from_rom(vec![0; 2]).is_err());
+    }
+}
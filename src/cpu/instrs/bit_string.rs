@@ -0,0 +1,322 @@
+use super::decoder::{Instr, Op};
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+
+// Bit string instructions operate on the following registers:
+//  r30: Source word address (the low 2 bits are ignored)
+//  r29: Destination word address for transfers, number of bits skipped for searches
+//  r28: Length of the bit string, in bits
+//  r27: Source bit offset (0-31)
+//  r26: Destination bit offset (0-31)
+// These instructions can take thousands of cycles, so they're executed at most one word at a time.
+// If there's work left, the PC is rewound so the instruction runs again on the next step, picking up from the registers.
+// This is what lets interrupts be serviced in the middle of a bit string instruction, like on hardware.
+// The first step of an instruction pays for its setup, which is skipped when resuming.
+const SRC_ADDR: usize = 30;
+const DST_ADDR: usize = 29;
+const SKIPPED: usize = 29;
+const LENGTH: usize = 28;
+const SRC_OFFSET: usize = 27;
+const DST_OFFSET: usize = 26;
+
+const BIT_STRING_SETUP_CYCLES: u32 = 13;
+
+impl Cpu {
+    // Handles the bit string instructions (opcode 0b011111). The decoder has already looked up the sub-opcode in the reg1 field
+    pub fn bit_string(&mut self, bus: &mut Bus, instr: Instr) -> u32 {
+        let resuming = self.bit_string_active;
+
+        let (done, cycles) = match instr.op {
+            Op::Sch0bsu => self.bit_search(bus, false, true),
+            Op::Sch0bsd => self.bit_search(bus, false, false),
+            Op::Sch1bsu => self.bit_search(bus, true, true),
+            Op::Sch1bsd => self.bit_search(bus, true, false),
+
+            Op::Orbsu => self.bit_transfer(bus, |dst, src| dst | src),
+            Op::Andbsu => self.bit_transfer(bus, |dst, src| dst & src),
+            Op::Xorbsu => self.bit_transfer(bus, |dst, src| dst ^ src),
+            Op::Movbsu => self.bit_transfer(bus, |_, src| src),
+            Op::Ornbsu => self.bit_transfer(bus, |dst, src| dst | !src),
+            Op::Andnbsu => self.bit_transfer(bus, |dst, src| dst & !src),
+            Op::Xornbsu => self.bit_transfer(bus, |dst, src| dst ^ !src),
+            Op::Notbsu => self.bit_transfer(bus, |_, src| !src),
+
+            _ => (true, self.illegal_opcode(self.regs.pc.wrapping_sub(2))),
+        };
+
+        self.bit_string_active = !done;
+        if !done {
+            self.regs.pc = self.regs.pc.wrapping_sub(2); // Re-execute this instruction on the next step
+        }
+
+        if resuming { cycles } else { cycles + BIT_STRING_SETUP_CYCLES }
+    }
+
+    // SCH0BSU, SCH0BSD, SCH1BSU, SCH1BSD
+    // Searches the bit string starting at [r30], bit r27 for a bit equal to "target", going up or down.
+    // Every bit examined (including the one found) decrements r28 and increments r29.
+    // On a match, r30/r27 point to the bit after the matching one.
+    // Cycles: 13 (setup) + 1 per bit examined
+    // Flags affected: Zero (cleared if found, set if not found)
+    // Returns whether the instruction finished and the cycles taken by this step
+    fn bit_search(&mut self, bus: &Bus, target: bool, upwards: bool) -> (bool, u32) {
+        let mut addr = self.regs.gprs[SRC_ADDR] & !3;
+        let mut offset = self.regs.gprs[SRC_OFFSET] & 0x1F;
+        let mut length = self.regs.gprs[LENGTH];
+        let mut skipped = self.regs.gprs[SKIPPED];
+        let word = if length != 0 { bus.read32(addr) } else { 0 };
+        let mut found = false;
+        let mut cycles = 0;
+
+        while length != 0 && !found {
+            cycles += 1;
+            found = ((word >> offset) & 1 != 0) == target;
+            length -= 1;
+            skipped = skipped.wrapping_add(1);
+
+            // Move on to the next bit, crossing over to the next word at the edge of this one
+            let crossed_word = if upwards {
+                offset = (offset + 1) & 0x1F;
+                offset == 0
+            } else {
+                offset = offset.wrapping_sub(1) & 0x1F;
+                offset == 31
+            };
+
+            if crossed_word {
+                addr = if upwards { addr.wrapping_add(4) } else { addr.wrapping_sub(4) };
+                break; // Resume from the next word on the next step
+            }
+        }
+
+        self.regs.gprs[SRC_ADDR] = addr;
+        self.regs.gprs[SRC_OFFSET] = offset;
+        self.regs.gprs[LENGTH] = length;
+        self.regs.gprs[SKIPPED] = skipped;
+
+        let done = found || length == 0;
+        if done {
+            self.regs.psw.set_zero(!found);
+        }
+
+        (done, cycles)
+    }
+
+    // ORBSU, ANDBSU, XORBSU, MOVBSU, ORNBSU, ANDNBSU, XORNBSU, NOTBSU
+    // [r29] bit r26 = op([r29] bit r26, [r30] bit r27) for r28 bits, upwards.
+    // Each step fills the rest of the current destination word.
+    // Cycles: 13 (setup) + 25 for the first word, 6 to 15 per additional word (6 if the source and destination are aligned)
+    // Flags affected: none
+    // Returns whether the instruction finished and the cycles taken by this step
+    fn bit_transfer<F>(&mut self, bus: &mut Bus, op: F) -> (bool, u32)
+    where F: Fn(u32, u32) -> u32 {
+        let mut src_addr = self.regs.gprs[SRC_ADDR] & !3;
+        let mut dst_addr = self.regs.gprs[DST_ADDR] & !3;
+        let mut src_offset = self.regs.gprs[SRC_OFFSET] & 0x1F;
+        let mut dst_offset = self.regs.gprs[DST_OFFSET] & 0x1F;
+        let mut length = self.regs.gprs[LENGTH];
+        let mut cycles = 0;
+
+        if length != 0 {
+            cycles = if self.bit_string_active { 6 } else { 25 };
+            if src_offset != dst_offset {
+                cycles += 9; // Unaligned transfers need to shift the source bits into place
+            }
+
+            let count = length.min(32 - dst_offset); // Number of bits that go into the current destination word
+
+            // Fetch the source bits, which might span 2 words
+            let mut src = bus.read32(src_addr) as u64;
+            if src_offset + count > 32 {
+                src |= (bus.read32(src_addr.wrapping_add(4)) as u64) << 32;
+            }
+            let src = (src >> src_offset) as u32;
+
+            let mask = if count == 32 { u32::MAX } else { ((1 << count) - 1) << dst_offset };
+            let dst = bus.read32(dst_addr);
+            let res = op(dst, src << dst_offset);
+            bus.write32(dst_addr, (dst & !mask) | (res & mask));
+
+            length -= count;
+            src_offset += count;
+            if src_offset >= 32 {
+                src_offset -= 32;
+                src_addr = src_addr.wrapping_add(4);
+            }
+
+            dst_offset += count;
+            if dst_offset == 32 {
+                dst_offset = 0;
+                dst_addr = dst_addr.wrapping_add(4);
+            }
+        }
+
+        self.regs.gprs[SRC_ADDR] = src_addr;
+        self.regs.gprs[DST_ADDR] = dst_addr;
+        self.regs.gprs[SRC_OFFSET] = src_offset;
+        self.regs.gprs[DST_OFFSET] = dst_offset;
+        self.regs.gprs[LENGTH] = length;
+
+        (length == 0, cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::exceptions;
+    use crate::cpu::instrs::opcodes;
+    use std::convert::TryInto;
+
+    const CODE: u32 = 0x0500F000; // Where the instruction is run from
+    const SRC: u32 = 0x05000000;
+    const DST: u32 = 0x05000100;
+
+    // A CPU about to run the bit string instruction "subop" from WRAM, with r26-r30 = "regs" (in register order)
+    fn setup(subop: u16, regs: [u32; 5]) -> (Cpu, Bus) {
+        let mut bus = Bus::new("ROMs/ScreenDemo1.vb");
+        bus.write16(CODE, opcodes::BIT_STRING << 10 | subop);
+
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = CODE;
+        cpu.regs.gprs[26..=30].copy_from_slice(&regs);
+        (cpu, bus)
+    }
+
+    // Steps until the instruction is done. Returns the number of steps it took
+    fn run(cpu: &mut Cpu, bus: &mut Bus) -> u32 {
+        let mut steps = 0;
+        while cpu.regs.pc == CODE {
+            cpu.step(bus);
+            steps += 1;
+            assert!(steps < 100, "the instruction didn't finish");
+        }
+        assert_eq!(cpu.regs.pc, CODE + 2);
+        steps
+    }
+
+    fn write_words(bus: &mut Bus, addr: u32, words: &[u32]) {
+        for (i, &word) in words.iter().enumerate() {
+            bus.write32(addr + i as u32 * 4, word);
+        }
+    }
+
+    fn read_words(bus: &Bus, addr: u32, count: usize) -> Vec<u32> {
+        (0..count as u32).map(|i| bus.read32(addr + i * 4)).collect()
+    }
+
+    // r26-r30
+    fn bit_string_regs(cpu: &Cpu) -> [u32; 5] {
+        cpu.regs.gprs[26..=30].try_into().unwrap()
+    }
+
+    #[test]
+    fn searches() {
+        use opcodes::bit_string::*;
+
+        // Instruction, source words, r26-r30 before, r26-r30 after, Z after, steps taken
+        let cases = [
+            // The 0 in bit 3 of the second word is 32 bits up from bit 4 of the first one. r27 ends up past it
+            ("sch0bsu", SCH0BSU, [0xFFFFFFFF, 0xFFFFFFF7], [0, 4, 64, 0, SRC], [0, 4, 32, 32, SRC + 4], false, 2),
+            // Bits 2-0 of the second word, then 31-16 of the first one. r29 keeps counting from its initial value
+            ("sch1bsd", SCH1BSD, [0x00010000, 0x00000000], [0, 2, 64, 100, SRC + 4], [0, 15, 45, 119, SRC], false, 2),
+            // Not found: r28 runs out after bits 0-31 and 0-7
+            ("sch1bsu", SCH1BSU, [0x00000000, 0xFFFFFF00], [0, 0, 40, 0, SRC], [0, 8, 0, 40, SRC + 4], true, 2),
+            ("sch0bsd", SCH0BSD, [0xFFFFFFFF, 0xFFFFFFFF], [0, 5, 10, 0, SRC + 4], [0, 27, 0, 10, SRC], true, 2),
+            // The low 2 bits of r30 are ignored
+            ("sch1bsu", SCH1BSU, [0x00000100, 0x00000000], [0, 0, 32, 0, SRC + 3], [0, 9, 23, 9, SRC], false, 1),
+        ];
+
+        for &(name, subop, words, before, after, zero, steps) in &cases {
+            let (mut cpu, mut bus) = setup(subop, before);
+            cpu.regs.psw.set_zero(!zero); // The opposite of what the search should leave
+            write_words(&mut bus, SRC, &words);
+
+            assert_eq!(run(&mut cpu, &mut bus), steps, "{} {:X?}", name, before);
+            assert_eq!(bit_string_regs(&cpu), after, "{} {:X?}", name, before);
+            assert_eq!(cpu.regs.psw.zero(), zero, "{} {:X?}", name, before);
+        }
+    }
+
+    #[test]
+    fn misaligned_logic_ops() {
+        use opcodes::bit_string::*;
+
+        let src = [0x12345678, 0x9ABCDEF0];
+        let dst = [0x0F0F0F0F, 0xCAFEBABE, 0x13579BDF, 0x2468ACE0];
+        type Reference = fn(bool, bool) -> bool; // (dst, src) -> result, for a single bit
+        let ops: [(&str, u16, Reference); 4] = [
+            ("orbsu", ORBSU, |dst, src| dst | src),
+            ("andbsu", ANDBSU, |dst, src| dst & src),
+            ("xorbsu", XORBSU, |dst, src| dst ^ src),
+            ("notbsu", NOTBSU, |_, src| !src),
+        ];
+
+        for &(name, subop, f) in &ops {
+            // 50 bits from bit 5 of the source to bit 19 of the destination, spanning 3 destination words
+            let (mut cpu, mut bus) = setup(subop, [19, 5, 50, DST, SRC]);
+            write_words(&mut bus, SRC, &src);
+            write_words(&mut bus, DST, &dst);
+            assert_eq!(run(&mut cpu, &mut bus), 3, "{}", name); // One step per destination word
+
+            // Bit by bit reference
+            let mut expected = dst;
+            for i in 0..50 {
+                let (src_bit, dst_bit) = (5 + i, 19 + i);
+                let s = src[src_bit / 32] >> (src_bit % 32) & 1 != 0;
+                let d = expected[dst_bit / 32] >> (dst_bit % 32) & 1 != 0;
+                let mask = 1 << (dst_bit % 32);
+                expected[dst_bit / 32] = if f(d, s) { expected[dst_bit / 32] | mask } else { expected[dst_bit / 32] & !mask };
+            }
+
+            assert_eq!(read_words(&bus, DST, 4), expected, "{}", name);
+            assert_eq!(read_words(&bus, SRC, 2), src, "{}", name);
+            assert_eq!(bit_string_regs(&cpu), [5, 23, 0, DST + 8, SRC + 4], "{}", name);
+        }
+    }
+
+    #[test]
+    fn transfers_resume_from_the_registers() {
+        let src: Vec<u32> = (0..8).map(|i| 0x01010101 * (i + 1)).collect();
+        let (mut cpu, mut bus) = setup(opcodes::bit_string::MOVBSU, [0, 0, 256, DST, SRC]);
+        write_words(&mut bus, SRC, &src);
+
+        // One word per step, with the PC rewound to run the instruction again
+        for word in 1..8 {
+            cpu.step(&mut bus);
+            assert_eq!(cpu.regs.pc, CODE);
+            assert_eq!(bit_string_regs(&cpu), [0, 0, 256 - word * 32, DST + word * 4, SRC + word * 4]);
+        }
+
+        cpu.step(&mut bus);
+        assert_eq!(cpu.regs.pc, CODE + 2);
+        assert_eq!(read_words(&bus, DST, 8), src);
+        assert_eq!(bit_string_regs(&cpu), [0, 0, 0, DST + 32, SRC + 32]);
+    }
+
+    #[test]
+    fn interrupted_transfer_resumes() {
+        let src: Vec<u32> = (0..8).map(|i| 0x01010101 * (i + 1)).collect();
+        let (mut cpu, mut bus) = setup(opcodes::bit_string::MOVBSU, [0, 0, 256, DST, SRC]);
+        cpu.regs.psw.set_raw(0); // Enable interrupts
+        write_words(&mut bus, SRC, &src);
+
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert_eq!(bit_string_regs(&cpu), [0, 0, 192, DST + 8, SRC + 8]);
+
+        // The interrupt is taken between words, returning to the instruction
+        cpu.request_irq(exceptions::irq_levels::TIMER);
+        cpu.check_interrupts();
+        cpu.clear_irq(exceptions::irq_levels::TIMER);
+        assert_eq!(cpu.regs.pc, 0xFFFFFE10);
+        assert_eq!(cpu.regs.eipc, CODE);
+
+        // After RETI, the transfer picks up from the registers
+        cpu.reti();
+        assert_eq!(cpu.regs.pc, CODE);
+        assert_eq!(run(&mut cpu, &mut bus), 6);
+        assert_eq!(read_words(&bus, DST, 8), src);
+        assert_eq!(bit_string_regs(&cpu), [0, 0, 0, DST + 32, SRC + 32]);
+    }
+}
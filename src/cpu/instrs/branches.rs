@@ -1,4 +1,4 @@
-use crate::bus::Bus;
+use super::decoder::Instr;
 use crate::cpu::Cpu;
 
 impl Cpu {
@@ -6,14 +6,12 @@ impl Cpu {
     // Cycles: 1 if branch not taken, 3 if taken
     // Flags affected: none
     // Opcode: 0b100xxx
-    pub fn bcond(&mut self, bus: &mut Bus, instr: u16) {
-        let cond = (instr >> 9) & 0xF;
-
-        if self.regs.psw.satisfies_cond(cond) {
-            let offset = ((instr & 0x1FF) as i32) << 23 >> 23;  // Sign extend offset
-            let offset = offset as u32;
-
-            self.regs.pc = self.regs.pc.wrapping_sub(2).wrapping_add(offset);  // Calculate the new PC. Branch is relevant to the FIRST INSTRUCTION byte, hence the -2
+    pub fn bcond(&mut self, instr: Instr) -> u32 {
+        if self.regs.psw.satisfies_cond(instr.cond) {
+            self.regs.pc = instr.branch_target(self.regs.pc.wrapping_sub(instr.len)); // Branch is relative to the FIRST INSTRUCTION byte
+            3
+        } else {
+            1
         }
     }
 
@@ -21,32 +19,31 @@ impl Cpu {
     // Cycles: 3
     // Flags affected: none
     // Opcode: 0b000110
-    pub fn jmp(&mut self, bus: &mut Bus, instr: u16) {
-        let reg1_index = (instr & 0x1F) as usize;
+    pub fn jmp(&mut self, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
         self.regs.pc = self.regs.gprs[reg1_index] & !1;
+
+        3
     }
 
-    
     // pc += (sign extend) offset
     // Cycles: 3
     // Flags affected: none
     // Opcode: 0b101010
-    pub fn jr(&mut self, bus: &mut Bus, instr: u16) {
-        let mut offset = (instr as u32 & 0x3FF) << 16;
-        offset |= self.consume_halfword(bus) as u32;
-        offset = ((offset as i32) << 6 >> 6) as u32; // Sign extend offset
+    pub fn jr(&mut self, instr: Instr) -> u32 {
+        self.regs.pc = instr.branch_target(self.regs.pc.wrapping_sub(instr.len)); // Branch is relative to the FIRST INSTRUCTION byte
 
-        let addr = self.regs.pc.wrapping_sub(4).wrapping_add(offset) & !1; // Calculate the new PC. Branch is relevant to the FIRST INSTRUCTION byte, hence the -4
-        self.regs.pc = addr;
+        3
     }
 
-    pub fn jal(&mut self, bus: &mut Bus, instr: u16) {
-        let mut offset = (instr as u32 & 0x3FF) << 16;
-        offset |= self.consume_halfword(bus) as u32;
-    
-        offset = ((offset as i32) << 6 >> 6) as u32; // Sign extend offset
-        self.regs.gprs[31] = self.regs.pc; // Store return address to PC
-        let addr = self.regs.pc.wrapping_sub(4).wrapping_add(offset) & !1; // Calculate the new PC. Branch is relevant to the FIRST INSTRUCTION byte, hence the -4
-        self.regs.pc = addr;
+    // r31 = pc of the next instruction, pc += (sign extend) offset
+    // Cycles: 3
+    // Flags affected: none
+    // Opcode: 0b101011
+    pub fn jal(&mut self, instr: Instr) -> u32 {
+        self.regs.gprs[31] = self.regs.pc; // Store return address to r31
+        self.regs.pc = instr.branch_target(self.regs.pc.wrapping_sub(instr.len));
+
+        3
     }
 }
@@ -0,0 +1,362 @@
+use super::opcodes;
+use super::opcodes::{bit_string, extended};
+
+/*
+    Every V810 instruction is described by one of 7 formats:
+
+    Format I   (16 bits): opcode(6) reg2(5) reg1(5)
+    Format II  (16 bits): opcode(6) reg2(5) imm5(5). Bit string instructions keep their sub-opcode in imm5
+    Format III (16 bits): opcode(3) cond(4) disp9(9)
+    Format IV  (32 bits): opcode(6) disp26(26)
+    Format V   (32 bits): opcode(6) reg2(5) reg1(5) | imm16(16)
+    Format VI  (32 bits): opcode(6) reg2(5) reg1(5) | disp16(16)
+    Format VII (32 bits): opcode(6) reg2(5) reg1(5) | sub-opcode(6) unused(10)
+
+    The first halfword is always the one at the lower address. Opcodes 0b101000 and up are 32 bits long.
+    Decoding goes through 3 tables built from the opcodes module: one for the top 6 bits of the first halfword,
+    one for bit string sub-opcodes and one for the sub-opcodes of opcode 0b111110.
+    Adding an instruction means adding it to Op and to one of these tables.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    I,
+    II,
+    III,
+    IV,
+    V,
+    VI,
+    VII,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    // Format I
+    MovReg, AddReg, Sub, CmpReg, ShlReg, ShrReg, Jmp, SarReg,
+    Mul, Div, Mulu, Divu, Or, And, Xor, Not,
+
+    // Format II
+    MovImm, AddImm, Setf, CmpImm, ShlImm, ShrImm, Cli, SarImm,
+    Trap, Reti, Halt, Ldsr, Stsr, Sei,
+
+    // Format II, bit string instructions
+    Sch0bsu, Sch0bsd, Sch1bsu, Sch1bsd,
+    Orbsu, Andbsu, Xorbsu, Movbsu, Ornbsu, Andnbsu, Xornbsu, Notbsu,
+
+    // Format III
+    Bcond,
+
+    // Format IV
+    Jr, Jal,
+
+    // Format V
+    Movea, Addi, Ori, Andi, Xori, Movhi,
+
+    // Format VI
+    LdB, LdH, LdW, StB, StH, StW,
+    InB, InH, InW, OutB, OutH, OutW, Caxi,
+
+    // Format VII
+    CmpfS, CvtWs, CvtSw, AddfS, SubfS, MulfS, DivfS, TrncSw,
+    Xb, Xh, Rev, Mpyhw,
+
+    // Reserved encodings
+    Illegal,
+}
+
+impl Op {
+    pub fn format(self) -> Format {
+        use Op::*;
+
+        match self {
+            MovReg | AddReg | Sub | CmpReg | ShlReg | ShrReg | Jmp | SarReg |
+            Mul | Div | Mulu | Divu | Or | And | Xor | Not | Illegal => Format::I,
+
+            MovImm | AddImm | Setf | CmpImm | ShlImm | ShrImm | Cli | SarImm |
+            Trap | Reti | Halt | Ldsr | Stsr | Sei |
+            Sch0bsu | Sch0bsd | Sch1bsu | Sch1bsd |
+            Orbsu | Andbsu | Xorbsu | Movbsu | Ornbsu | Andnbsu | Xornbsu | Notbsu => Format::II,
+
+            Bcond => Format::III,
+            Jr | Jal => Format::IV,
+            Movea | Addi | Ori | Andi | Xori | Movhi => Format::V,
+
+            LdB | LdH | LdW | StB | StH | StW |
+            InB | InH | InW | OutB | OutH | OutW | Caxi => Format::VI,
+
+            CmpfS | CvtWs | CvtSw | AddfS | SubfS | MulfS | DivfS | TrncSw |
+            Xb | Xh | Rev | Mpyhw => Format::VII,
+        }
+    }
+
+    // Official NEC mnemonic. Bcond instructions are named after their condition, see BCOND_MNEMONICS
+    pub fn mnemonic(self) -> &'static str {
+        use Op::*;
+
+        match self {
+            MovReg | MovImm => "mov",
+            AddReg | AddImm => "add",
+            Sub => "sub",
+            CmpReg | CmpImm => "cmp",
+            ShlReg | ShlImm => "shl",
+            ShrReg | ShrImm => "shr",
+            SarReg | SarImm => "sar",
+            Jmp => "jmp",
+            Mul => "mul",
+            Div => "div",
+            Mulu => "mulu",
+            Divu => "divu",
+            Or => "or",
+            And => "and",
+            Xor => "xor",
+            Not => "not",
+            Setf => "setf",
+            Cli => "cli",
+            Trap => "trap",
+            Reti => "reti",
+            Halt => "halt",
+            Ldsr => "ldsr",
+            Stsr => "stsr",
+            Sei => "sei",
+            Sch0bsu => "sch0bsu",
+            Sch0bsd => "sch0bsd",
+            Sch1bsu => "sch1bsu",
+            Sch1bsd => "sch1bsd",
+            Orbsu => "orbsu",
+            Andbsu => "andbsu",
+            Xorbsu => "xorbsu",
+            Movbsu => "movbsu",
+            Ornbsu => "ornbsu",
+            Andnbsu => "andnbsu",
+            Xornbsu => "xornbsu",
+            Notbsu => "notbsu",
+            Bcond => "bcond",
+            Jr => "jr",
+            Jal => "jal",
+            Movea => "movea",
+            Addi => "addi",
+            Ori => "ori",
+            Andi => "andi",
+            Xori => "xori",
+            Movhi => "movhi",
+            LdB => "ld.b",
+            LdH => "ld.h",
+            LdW => "ld.w",
+            StB => "st.b",
+            StH => "st.h",
+            StW => "st.w",
+            InB => "in.b",
+            InH => "in.h",
+            InW => "in.w",
+            OutB => "out.b",
+            OutH => "out.h",
+            OutW => "out.w",
+            Caxi => "caxi",
+            CmpfS => "cmpf.s",
+            CvtWs => "cvt.ws",
+            CvtSw => "cvt.sw",
+            AddfS => "addf.s",
+            SubfS => "subf.s",
+            MulfS => "mulf.s",
+            DivfS => "divf.s",
+            TrncSw => "trnc.sw",
+            Xb => "xb",
+            Xh => "xh",
+            Rev => "rev",
+            Mpyhw => "mpyhw",
+            Illegal => "illegal",
+        }
+    }
+}
+
+pub const BCOND_MNEMONICS: &[&str] = &[
+    "bv", "bc", "be", "bnh", "bn", "br", "blt", "ble", "bnv", "bnc", "bne", "bh", "bp", "nop",
+    "bge", "bgt",
+];
+
+const OPCODE_TABLE: [Op; 64] = {
+    let mut table = [Op::Illegal; 64];
+
+    table[opcodes::MOV_REG as usize] = Op::MovReg;
+    table[opcodes::ADD_REG as usize] = Op::AddReg;
+    table[opcodes::SUB as usize] = Op::Sub;
+    table[opcodes::CMP_REG as usize] = Op::CmpReg;
+    table[opcodes::SHL_REG as usize] = Op::ShlReg;
+    table[opcodes::SHR_REG as usize] = Op::ShrReg;
+    table[opcodes::JMP as usize] = Op::Jmp;
+    table[opcodes::SAR_REG as usize] = Op::SarReg;
+    table[opcodes::MUL as usize] = Op::Mul;
+    table[opcodes::DIV as usize] = Op::Div;
+    table[opcodes::MULU as usize] = Op::Mulu;
+    table[opcodes::DIVU as usize] = Op::Divu;
+    table[opcodes::OR as usize] = Op::Or;
+    table[opcodes::AND as usize] = Op::And;
+    table[opcodes::XOR as usize] = Op::Xor;
+    table[opcodes::NOT as usize] = Op::Not;
+
+    table[opcodes::MOV_IMM as usize] = Op::MovImm;
+    table[opcodes::ADDI_SHORT as usize] = Op::AddImm;
+    table[opcodes::SETF as usize] = Op::Setf;
+    table[opcodes::CMP_IMM as usize] = Op::CmpImm;
+    table[opcodes::SHL_IMM as usize] = Op::ShlImm;
+    table[opcodes::SHR_IMM as usize] = Op::ShrImm;
+    table[opcodes::CLI as usize] = Op::Cli;
+    table[opcodes::SAR_IMM as usize] = Op::SarImm;
+    table[opcodes::TRAP as usize] = Op::Trap;
+    table[opcodes::RETI as usize] = Op::Reti;
+    table[opcodes::HALT as usize] = Op::Halt;
+    table[opcodes::LDSR as usize] = Op::Ldsr;
+    table[opcodes::STSR as usize] = Op::Stsr;
+    table[opcodes::SEI as usize] = Op::Sei;
+
+    let mut opcode = opcodes::BCOND_START;
+    while opcode <= opcodes::BCOND_END {
+        table[opcode as usize] = Op::Bcond;
+        opcode += 1;
+    }
+
+    table[opcodes::JR as usize] = Op::Jr;
+    table[opcodes::JAL as usize] = Op::Jal;
+
+    table[opcodes::MOVEA as usize] = Op::Movea;
+    table[opcodes::ADDI_LONG as usize] = Op::Addi;
+    table[opcodes::ORI as usize] = Op::Ori;
+    table[opcodes::ANDI as usize] = Op::Andi;
+    table[opcodes::XORI as usize] = Op::Xori;
+    table[opcodes::MOVHI as usize] = Op::Movhi;
+
+    table[opcodes::LD_BYTE as usize] = Op::LdB;
+    table[opcodes::LD_HALFWORD as usize] = Op::LdH;
+    table[opcodes::LD_WORD as usize] = Op::LdW;
+    table[opcodes::ST_BYTE as usize] = Op::StB;
+    table[opcodes::ST_HALFWORD as usize] = Op::StH;
+    table[opcodes::ST_WORD as usize] = Op::StW;
+    table[opcodes::IN_BYTE as usize] = Op::InB;
+    table[opcodes::IN_HALFWORD as usize] = Op::InH;
+    table[opcodes::IN_WORD as usize] = Op::InW;
+    table[opcodes::OUT_BYTE as usize] = Op::OutB;
+    table[opcodes::OUT_HALFWORD as usize] = Op::OutH;
+    table[opcodes::OUT_WORD as usize] = Op::OutW;
+    table[opcodes::CAXI as usize] = Op::Caxi;
+
+    table
+};
+
+const BIT_STRING_TABLE: [Op; 32] = {
+    let mut table = [Op::Illegal; 32];
+
+    table[bit_string::SCH0BSU as usize] = Op::Sch0bsu;
+    table[bit_string::SCH0BSD as usize] = Op::Sch0bsd;
+    table[bit_string::SCH1BSU as usize] = Op::Sch1bsu;
+    table[bit_string::SCH1BSD as usize] = Op::Sch1bsd;
+    table[bit_string::ORBSU as usize] = Op::Orbsu;
+    table[bit_string::ANDBSU as usize] = Op::Andbsu;
+    table[bit_string::XORBSU as usize] = Op::Xorbsu;
+    table[bit_string::MOVBSU as usize] = Op::Movbsu;
+    table[bit_string::ORNBSU as usize] = Op::Ornbsu;
+    table[bit_string::ANDNBSU as usize] = Op::Andnbsu;
+    table[bit_string::XORNBSU as usize] = Op::Xornbsu;
+    table[bit_string::NOTBSU as usize] = Op::Notbsu;
+
+    table
+};
+
+const EXTENDED_TABLE: [Op; 64] = {
+    let mut table = [Op::Illegal; 64];
+
+    table[extended::CMPF_S as usize] = Op::CmpfS;
+    table[extended::CVT_WS as usize] = Op::CvtWs;
+    table[extended::CVT_SW as usize] = Op::CvtSw;
+    table[extended::ADDF_S as usize] = Op::AddfS;
+    table[extended::SUBF_S as usize] = Op::SubfS;
+    table[extended::MULF_S as usize] = Op::MulfS;
+    table[extended::DIVF_S as usize] = Op::DivfS;
+    table[extended::TRNC_SW as usize] = Op::TrncSw;
+    table[extended::XB as usize] = Op::Xb;
+    table[extended::XH as usize] = Op::Xh;
+    table[extended::REV as usize] = Op::Rev;
+    table[extended::MPYHW as usize] = Op::Mpyhw;
+
+    table
+};
+
+// A decoded instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instr {
+    pub op: Op,
+    pub reg1: usize, // reg1 field. Holds the system register ID for LDSR/STSR
+    pub reg2: usize, // reg2 field
+    pub imm: u32,    // Immediate or displacement, already sign or zero extended (and shifted, for MOVHI) as the instruction expects
+    pub cond: u16,   // Condition code for Bcond and SETF
+    pub len: u32,    // Length in bytes (2 or 4)
+    pub raw: u32,    // Raw encoding. The first halfword is in the upper 16 bits for 32-bit instructions
+}
+
+impl Instr {
+    pub fn format(&self) -> Format {
+        self.op.format()
+    }
+
+    // Target of a branch instruction (Bcond, JR, JAL) located at "pc"
+    pub fn branch_target(&self, pc: u32) -> u32 {
+        pc.wrapping_add(self.imm) & !1
+    }
+}
+
+// Length in bytes of the instruction whose first halfword is "first".
+// Opcodes from 0b101000 onwards (formats IV-VII) are 32 bits long, including the reserved ones in that range
+pub fn instr_length(first: u16) -> u32 {
+    if first >> 10 >= opcodes::MOVEA { 4 } else { 2 }
+}
+
+// Decode an instruction. "second" is the next halfword in memory, and is ignored for 16-bit instructions
+pub fn decode(first: u16, second: u16) -> Instr {
+    let opcode = first >> 10;
+    let reg1 = first as usize & 0x1F;
+    let reg2 = (first as usize >> 5) & 0x1F;
+    let imm5 = first as u32 & 0x1F;
+
+    let op = match opcode {
+        opcodes::BIT_STRING => BIT_STRING_TABLE[reg1],
+        opcodes::FORMAT_VII => EXTENDED_TABLE[second as usize >> 10],
+        _ => OPCODE_TABLE[opcode as usize],
+    };
+
+    let len = instr_length(first);
+    let raw = if len == 4 { (first as u32) << 16 | second as u32 } else { first as u32 };
+    let mut instr = Instr { op, reg1, reg2, imm: 0, cond: 0, len, raw };
+
+    match op.format() {
+        Format::I | Format::VII => {}
+
+        Format::II => {
+            instr.imm = match op {
+                Op::MovImm | Op::AddImm | Op::CmpImm => ((imm5 as i32) << 27 >> 27) as u32, // sign extend immediate
+                _ => imm5,
+            };
+            instr.cond = first & 0xF;
+        }
+
+        Format::III => {
+            instr.cond = (first >> 9) & 0xF;
+            instr.imm = ((first as i32) << 23 >> 23) as u32; // sign extend displacement
+        }
+
+        Format::IV => {
+            let disp = (first as u32 & 0x3FF) << 16 | second as u32;
+            instr.imm = ((disp as i32) << 6 >> 6) as u32; // sign extend displacement
+        }
+
+        Format::V => {
+            instr.imm = match op {
+                Op::Movhi => (second as u32) << 16,
+                Op::Ori | Op::Andi | Op::Xori => second as u32, // Logical ops zero extend
+                _ => second as i16 as u32,
+            };
+        }
+
+        Format::VI => instr.imm = second as i16 as u32,
+    }
+
+    instr
+}
@@ -1,132 +1,222 @@
-use super::opcodes;
-use crate::bus::Bus;
-use crate::cpu::Cpu;
-
-const BCOND_MNEMONICS: &[&str] = &[
-    "bv", "bc", "be", "bnh", "bn", "br", "blt", "ble", "bnv", "bnc", "bne", "bh", "bp", "nop",
-    "bge", "bgt",
-];
-
-pub fn disassemble(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let opcode = instr >> 10; // Top 6 instruction bits decide the type of instruction
-
-    match opcode {
-        opcodes::BCOND_START..=opcodes::BCOND_END => disassemble_bcond(cpu, bus, instr, pc),
-        opcodes::JMP => disassemble_jmp(cpu, bus, instr, pc),
-        opcodes::JR => disassemble_jr(cpu, bus, instr, pc),
-        
-        opcodes::MOV_IMM => disassemble_mov_imm(cpu, bus, instr, pc),
-        opcodes::MOV_REG => disassemble_mov_reg(cpu, bus, instr, pc),
-        opcodes::MOVEA => disassemble_movea(cpu, bus, instr, pc),
-        opcodes::MOVHI => disassemble_movhi(cpu, bus, instr, pc),
-
-        opcodes::ADD_REG => disassemble_add_reg(cpu, bus, instr, pc),
-        opcodes::ADDI_SHORT => disassemble_addi_short(cpu, bus, instr, pc),
-        opcodes::CMP_REG => disassemble_cmp_reg(cpu, bus, instr, pc),
-        
-        opcodes::LD_BYTE => disassemble_ld(cpu, bus, instr, pc, "b".to_string()),
-        opcodes::ST_BYTE => disassemble_st(cpu, bus, instr, pc, "b".to_string()),
-        opcodes::LD_HALFWORD => disassemble_ld(cpu, bus, instr, pc, "h".to_string()),
-        opcodes::ST_HALFWORD => disassemble_st(cpu, bus, instr, pc, "h".to_string()),
-        opcodes::LD_WORD => disassemble_ld(cpu, bus, instr, pc, "w".to_string()),
-        opcodes::ST_WORD => disassemble_st(cpu, bus, instr, pc, "w".to_string()),
-        _ => panic!("[Disassembler]: Unrecognized instruction {:04X}", instr),
-    }
-}
+use super::decoder::{Format, Instr, Op, BCOND_MNEMONICS};
+use super::system_regs;
+use crate::symbols::Symbols;
+use std::fmt;
 
-pub fn disassemble_mov_imm(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let reg2_index = (instr >> 5) & 0x1F;
-    let imm = bus.read16(cpu.regs.pc) as i16 as u32;
+/*
+    Instructions are disassembled into a structured Disassembly (mnemonic, operands, length and branch target),
+    which can then be rendered as text in one of two syntaxes:
 
-    format!("mov r{}, {:#010X}", reg2_index, imm)
-}
+    NEC: the syntax of the official V810 documentation. Lowercase, decimal immediates and displacements,
+    except for the zero extended immediates of logic ops and MOVHI, which are shown in hex.
+    "add -1, r10", "ori 0xFF00, r6, r7", "ld.w -4[r3], r6", "ldsr r7, psw"
 
-pub fn disassemble_mov_reg(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let reg2_index = (instr >> 5) & 0x1F;
-    let reg1_index = instr & 0x1F;
+    Mednafen: the style of Mednafen's debugger. Uppercase mnemonics and system registers, every immediate and displacement in hex,
+    and BL/BNL for the carry conditions.
+    "ADD -0x1, r10", "ORI 0xFF00, r6, r7", "LD.W -0x4[r3], r6", "LDSR r7, PSW"
 
-    format!("mov r{}, r{}", reg2_index, reg1_index)
-}
+    Reserved encodings come out as .dh directives holding the raw halfwords of the instruction.
 
-pub fn disassemble_movea(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let reg1_index = instr & 0x1F;
-    let reg2_index = (instr >> 5) & 0x1F;
-    let imm = bus.read16(*pc) as i16 as i32;
+    Given a symbol map, branch targets get the symbol they're in appended objdump style: "jal 0x07000020 <fact>".
+*/
 
-    format!("movea r{}, r{} + {:#010X}", reg2_index, reg1_index, imm)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syntax {
+    Nec,
+    Mednafen,
 }
 
-pub fn disassemble_movhi(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let reg1_index = instr & 0x1F;
-    let reg2_index = (instr >> 5) & 0x1F;
-    let imm = (bus.read16(*pc) as u32) << 16;
-
-    format!("movhi r{}, r{} + 0x{:08X}", reg2_index, reg1_index, imm)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Reg(usize),                        // General purpose register
+    SystemReg(usize),                  // System register ID, for LDSR and STSR
+    Imm(i32),                          // Sign extended immediate (or a small unsigned one, like shift amounts and trap vectors)
+    UnsignedImm(u32),                  // Zero extended immediate, always shown in hex
+    Memory { disp: i32, base: usize }, // disp[base] memory operand
+    Indirect(usize),                   // [reg], the target of JMP
+    Target(u32),                       // Branch target address
+    Data(u16),                         // Raw halfword of a .dh directive
 }
 
-pub fn disassemble_add_imm(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let reg2_index = (instr >> 5) & 0x1F;
-    let imm = bus.read16(cpu.regs.pc) as i16 as i32;
-
-    format!("add r{}, {:#010X}", reg2_index, imm)
+impl Operand {
+    pub fn render(&self, syntax: Syntax) -> String {
+        match (*self, syntax) {
+            (Operand::Reg(reg), _) => format!("r{}", reg),
+            (Operand::SystemReg(id), _) => {
+                let name = system_regs::name(id as u16).map_or_else(|| format!("sr{}", id), str::to_string);
+                if syntax == Syntax::Mednafen { name.to_uppercase() } else { name }
+            }
+            (Operand::Imm(imm), Syntax::Nec) => imm.to_string(),
+            (Operand::Imm(imm), Syntax::Mednafen) => signed_hex(imm),
+            (Operand::UnsignedImm(imm), _) => format!("{:#X}", imm),
+            (Operand::Memory { disp, base }, Syntax::Nec) => format!("{}[r{}]", disp, base),
+            (Operand::Memory { disp, base }, Syntax::Mednafen) => format!("{}[r{}]", signed_hex(disp), base),
+            (Operand::Indirect(reg), _) => format!("[r{}]", reg),
+            (Operand::Target(addr), _) => format!("{:#010X}", addr),
+            (Operand::Data(halfword), _) => format!("{:#06X}", halfword),
+        }
+    }
 }
 
-pub fn disassemble_add_reg(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let reg2_index = (instr >> 5) & 0x1F;
-    let reg1_index = instr & 0x1F;
-
-    format!("add r{}, r{}", reg2_index, reg1_index)
+fn signed_hex(val: i32) -> String {
+    if val < 0 {
+        format!("-{:#X}", val.unsigned_abs())
+    } else {
+        format!("{:#X}", val)
+    }
 }
 
-pub fn disassemble_addi_short(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let reg2_index = (instr as usize >> 5) & 0x1F;
-    let imm = ((instr as i32) << 27 >> 27) as u32;
-
-    format!("addi r{}, {:#010X}", reg2_index, imm)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Disassembly {
+    pub mnemonic: &'static str, // NEC mnemonic, or ".dh" for reserved encodings
+    pub operands: Vec<Operand>,
+    pub len: u32,                   // Length of the instruction in bytes
+    pub branch_target: Option<u32>, // Set for instructions branching to a fixed address: Bcond (except NOP), JR and JAL
 }
 
-pub fn disassemble_cmp_reg(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let reg2_index = (instr >> 5) & 0x1F;
-    let reg1_index = instr & 0x1F;
-
-    format!("cmp r{}, r{}", reg2_index, reg1_index)
-}
+impl Disassembly {
+    pub fn render(&self, syntax: Syntax) -> String {
+        let mnemonic = match (syntax, self.mnemonic) {
+            (Syntax::Nec, mnemonic) => mnemonic.to_string(),
+            (Syntax::Mednafen, "bc") => "BL".to_string(),
+            (Syntax::Mednafen, "bnc") => "BNL".to_string(),
+            (Syntax::Mednafen, mnemonic) if mnemonic.starts_with('.') => mnemonic.to_string(), // Directives stay lowercase
+            (Syntax::Mednafen, mnemonic) => mnemonic.to_uppercase(),
+        };
+
+        if self.operands.is_empty() {
+            return mnemonic;
+        }
+
+        let operands: Vec<String> = self.operands.iter().map(|operand| operand.render(syntax)).collect();
+        format!("{} {}", mnemonic, operands.join(", "))
+    }
 
-pub fn disassemble_bcond(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let cond = instr >> 9 & 0xF;
-    let disp = (instr as i32) << 23 >> 23; // Displacement
-    let addr = pc.wrapping_sub(2).wrapping_add(disp as u32);
-    format!("{} {:08X}", BCOND_MNEMONICS[cond as usize], addr)
+    // Same as render, with the branch target's symbol appended when it has one
+    pub fn render_with_symbols(&self, syntax: Syntax, symbols: &Symbols) -> String {
+        let text = self.render(syntax);
+        match self.branch_target.and_then(|target| symbols.describe(target)) {
+            Some(symbol) => format!("{} <{}>", text, symbol),
+            None => text,
+        }
+    }
 }
 
-pub fn disassemble_jmp(cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let reg1_index = instr & 0x1F;
-    format!("jmp r{}", reg1_index)
+// Renders in NEC syntax
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.render(Syntax::Nec))
+    }
 }
 
-pub fn disassemble_jr (cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32) -> String {
-    let mut offset = (instr as u32 & 0x3FF) << 16;
-    offset |= bus.read16(*pc) as u32;
-
-    offset = ((offset as i32) << 6 >> 6) as u32;
-    let addr = pc.wrapping_sub(2).wrapping_add(offset) & !1; // Calculate the new PC,
-    format!("jr {:08X}", addr)
+// Disassemble an already decoded instruction located at "pc"
+pub fn disassemble(instr: &Instr, pc: u32) -> Disassembly {
+    use Operand::*;
+
+    let op = instr.op;
+    let imm = instr.imm as i32;
+    let mut mnemonic = op.mnemonic();
+
+    let operands = match instr.format() {
+        // Unrecognized instructions are shown as raw data
+        _ if op == Op::Illegal => {
+            mnemonic = ".dh";
+            if instr.len == 4 {
+                vec![Data((instr.raw >> 16) as u16), Data(instr.raw as u16)]
+            } else {
+                vec![Data(instr.raw as u16)]
+            }
+        }
+
+        Format::I => match op {
+            Op::Jmp => vec![Indirect(instr.reg1)],
+            _ => vec![Reg(instr.reg1), Reg(instr.reg2)],
+        },
+
+        Format::II => match op {
+            Op::Cli | Op::Sei | Op::Reti | Op::Halt => vec![],
+            Op::Sch0bsu | Op::Sch0bsd | Op::Sch1bsu | Op::Sch1bsd |
+            Op::Orbsu | Op::Andbsu | Op::Xorbsu | Op::Movbsu |
+            Op::Ornbsu | Op::Andnbsu | Op::Xornbsu | Op::Notbsu => vec![],
+            Op::Trap => vec![Imm(imm)],
+            Op::Setf => vec![Imm(instr.cond as i32), Reg(instr.reg2)],
+            Op::Ldsr => vec![Reg(instr.reg2), SystemReg(instr.reg1)],
+            Op::Stsr => vec![SystemReg(instr.reg1), Reg(instr.reg2)],
+            _ => vec![Imm(imm), Reg(instr.reg2)],
+        },
+
+        Format::III => {
+            mnemonic = BCOND_MNEMONICS[instr.cond as usize];
+            match instr.cond {
+                0xD => vec![], // NOP
+                _ => vec![Target(instr.branch_target(pc))],
+            }
+        }
+
+        Format::IV => vec![Target(instr.branch_target(pc))],
+
+        Format::V => {
+            let imm = match op {
+                Op::Movhi => UnsignedImm(instr.imm >> 16),
+                Op::Ori | Op::Andi | Op::Xori => UnsignedImm(instr.imm),
+                _ => Imm(imm),
+            };
+            vec![imm, Reg(instr.reg1), Reg(instr.reg2)]
+        }
+
+        Format::VI => {
+            let memory = Memory { disp: imm, base: instr.reg1 };
+            match op {
+                Op::StB | Op::StH | Op::StW | Op::OutB | Op::OutH | Op::OutW => vec![Reg(instr.reg2), memory],
+                _ => vec![memory, Reg(instr.reg2)], // Loads, inputs and CAXI
+            }
+        }
+
+        Format::VII => match op {
+            Op::Xb | Op::Xh => vec![Reg(instr.reg2)],
+            _ => vec![Reg(instr.reg1), Reg(instr.reg2)],
+        },
+    };
+
+    let branch_target = match op {
+        Op::Bcond if instr.cond == 0xD => None,
+        Op::Bcond | Op::Jr | Op::Jal => Some(instr.branch_target(pc)),
+        _ => None,
+    };
+
+    Disassembly { mnemonic, operands, len: instr.len, branch_target }
 }
 
-pub fn disassemble_ld (cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32, suffix: String) -> String {
-    let reg1_index = instr as usize & 0x1F;
-    let reg2_index = (instr as usize >> 5) & 0x1F;
-
-    let offset = bus.read16(*pc) as i16 as u32;
-
-    format!("ld.{} r{}, [r{} + {:08X}]", suffix, reg2_index, reg1_index, offset)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instrs::decoder;
+
+    const PC: u32 = 0x07000000;
+
+    #[test]
+    fn render_both_syntaxes() {
+        // First halfword, second halfword, NEC syntax, Mednafen syntax. One instruction of every format first
+        let cases: &[(u16, u16, &str, &str)] = &[
+            (0x04E6, 0x0000, "add r6, r7", "ADD r6, r7"),
+            (0x455F, 0x0000, "add -1, r10", "ADD -0x1, r10"),
+            (0x8210, 0x0000, "bc 0x07000010", "BL 0x07000010"),
+            (0xAC00, 0x0020, "jal 0x07000020", "JAL 0x07000020"),
+            (0xB0E6, 0xFF00, "ori 0xFF00, r6, r7", "ORI 0xFF00, r6, r7"),
+            (0xCCC3, 0xFFFC, "ld.w -4[r3], r6", "LD.W -0x4[r3], r6"),
+            (0xF8E6, 0x1000, "addf.s r6, r7", "ADDF.S r6, r7"),
+            // Special cases
+            (0x70E5, 0x0000, "ldsr r7, psw", "LDSR r7, PSW"),
+            (0xBCE0, 0x1234, "movhi 0x1234, r0, r7", "MOVHI 0x1234, r0, r7"),
+            (0x9BFE, 0x0000, "nop", "NOP"),
+            (0x6C00, 0x0000, ".dh 0x6C00", ".dh 0x6C00"),
+            (0xF800, 0xFC00, ".dh 0xF800, 0xFC00", ".dh 0xF800, 0xFC00"),
+        ];
+
+        for &(first, second, nec, mednafen) in cases {
+            let disassembly = disassemble(&decoder::decode(first, second), PC);
+            assert_eq!(disassembly.render(Syntax::Nec), nec);
+            assert_eq!(disassembly.render(Syntax::Mednafen), mednafen);
+        }
+    }
 }
-
-pub fn disassemble_st (cpu: &Cpu, bus: &Bus, instr: u16, pc: &mut u32, suffix: String) -> String {
-    let reg1_index = instr as usize & 0x1F;
-    let reg2_index = (instr as usize >> 5) & 0x1F;
-
-    let offset = bus.read16(*pc) as i16 as u32;
-
-    format!("st.{} r{}, [r{} + {:08X}]", suffix, reg2_index, reg1_index, offset)
-}
\ No newline at end of file
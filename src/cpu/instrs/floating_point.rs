@@ -0,0 +1,458 @@
+use crate::cpu::exceptions::codes;
+use crate::cpu::Cpu;
+
+// Possible outcomes of a floating-point operation that the hardware traps on.
+// The result register is left untouched when any of these happen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FpException {
+    ReservedOperand,  // An operand was a NaN, an infinity or a denormal
+    Overflow,         // The result was too large to be represented as a float
+    DivideByZero,     // DIVF.S with a divisor of 0 and a non-zero dividend
+    InvalidOperation, // 0 / 0, or a float -> int conversion that doesn't fit in 32 bits
+}
+
+// Floats with an exponent of 0xFF (Infinities and NaNs) or denormals are "reserved operands" on the V810.
+// Zero (positive or negative) is a normal operand.
+fn is_reserved(val: f32) -> bool {
+    val.is_nan() || val.is_infinite() || val.is_subnormal()
+}
+
+impl Cpu {
+    // (discard) reg2 - reg1, as floats
+    // Cycles: 10
+    // Flags affected: Zero, Sign, Carry, Overflow, FRO
+    // Sub-opcode: 0b000000
+    pub fn cmpf_s(&mut self, reg2_index: usize, reg1_index: usize) -> u32 {
+        let reg2 = f32::from_bits(self.regs.gprs[reg2_index]);
+        let reg1 = f32::from_bits(self.regs.gprs[reg1_index]);
+
+        if is_reserved(reg1) || is_reserved(reg2) {
+            self.fp_exception(FpException::ReservedOperand);
+        } else {
+            self.regs.psw.set_zero(reg2 == reg1);
+            self.regs.psw.set_sign(reg2 < reg1);
+            self.regs.psw.set_carry(reg2 < reg1);
+            self.regs.psw.set_overflow(false);
+        }
+
+        10
+    }
+
+    // reg2 = (float) (signed) reg1
+    // Cycles: 16
+    // Flags affected: Zero, Sign, Carry, Overflow, FPR
+    // Sub-opcode: 0b000010
+    pub fn cvt_ws(&mut self, reg2_index: usize, reg1_index: usize) -> u32 {
+        let reg1 = self.regs.gprs[reg1_index] as i32;
+        let res = reg1 as f32;
+
+        if res as f64 != reg1 as f64 { // Integers above 2^24 can lose precision during the conversion
+            self.regs.psw.set_fpr(true);
+        }
+
+        self.set_float_flags(res);
+        self.regs.gprs[reg2_index] = res.to_bits();
+
+        16
+    }
+
+    // reg2 = (signed) reg1, rounded to the nearest integer (ties to even)
+    // Cycles: 14
+    // Flags affected: Zero, Sign, Overflow, FRO, FIV, FPR
+    // Sub-opcode: 0b000011
+    pub fn cvt_sw(&mut self, reg2_index: usize, reg1_index: usize) -> u32 {
+        let reg1 = f32::from_bits(self.regs.gprs[reg1_index]);
+        let rounded = reg1.round_ties_even() as f64;
+
+        self.float_to_int(reg2_index, reg1, rounded);
+
+        14
+    }
+
+    // reg2 = (signed) reg1, rounded towards zero
+    // Cycles: 14
+    // Flags affected: Zero, Sign, Overflow, FRO, FIV, FPR
+    // Sub-opcode: 0b001011
+    pub fn trnc_sw(&mut self, reg2_index: usize, reg1_index: usize) -> u32 {
+        let reg1 = f32::from_bits(self.regs.gprs[reg1_index]);
+        self.float_to_int(reg2_index, reg1, reg1.trunc() as f64);
+
+        14
+    }
+
+    // Shared by CVT.SW and TRNC.SW. "res" is the already rounded value of "val", which still needs to be range-checked
+    fn float_to_int(&mut self, reg2_index: usize, val: f32, res: f64) {
+        if is_reserved(val) {
+            self.fp_exception(FpException::ReservedOperand);
+            return;
+        }
+
+        if !(-2147483648.0..2147483648.0).contains(&res) { // Result doesn't fit in a signed 32-bit integer
+            self.fp_exception(FpException::InvalidOperation);
+            return;
+        }
+
+        if res != val as f64 {
+            self.regs.psw.set_fpr(true);
+        }
+
+        let res = res as i32 as u32;
+        self.regs.psw.set_sign_and_zero(res);
+        self.regs.psw.set_overflow(false);
+        self.regs.gprs[reg2_index] = res;
+    }
+
+    // reg2 = reg2 + reg1, as floats
+    // Cycles: 28
+    // Flags affected: Zero, Sign, Carry, Overflow, FRO, FOV, FUD, FPR
+    // Sub-opcode: 0b000100
+    pub fn addf_s(&mut self, reg2_index: usize, reg1_index: usize) -> u32 {
+        self.float_arith(reg2_index, reg1_index, |a, b| {
+            let res = a + b;
+            // Error-free transformation (TwoSum) to check whether the sum was rounded
+            let b_virtual = res - a;
+            let err = (a - (res - b_virtual)) + (b - b_virtual);
+            Ok((res, err != 0.0))
+        });
+
+        28
+    }
+
+    // reg2 = reg2 - reg1, as floats
+    // Cycles: 28
+    // Flags affected: Zero, Sign, Carry, Overflow, FRO, FOV, FUD, FPR
+    // Sub-opcode: 0b000101
+    pub fn subf_s(&mut self, reg2_index: usize, reg1_index: usize) -> u32 {
+        self.float_arith(reg2_index, reg1_index, |a, b| {
+            let b = -b;
+            let res = a + b;
+            let b_virtual = res - a;
+            let err = (a - (res - b_virtual)) + (b - b_virtual);
+            Ok((res, err != 0.0))
+        });
+
+        28
+    }
+
+    // reg2 = reg2 * reg1, as floats
+    // Cycles: 30
+    // Flags affected: Zero, Sign, Carry, Overflow, FRO, FOV, FUD, FPR
+    // Sub-opcode: 0b000110
+    pub fn mulf_s(&mut self, reg2_index: usize, reg1_index: usize) -> u32 {
+        self.float_arith(reg2_index, reg1_index, |a, b| {
+            let res = a * b;
+            let exact = a as f64 * b as f64; // The product of 2 floats always fits in a double
+            Ok((res, res as f64 != exact))
+        });
+
+        30
+    }
+
+    // reg2 = reg2 / reg1, as floats
+    // Cycles: 44
+    // Flags affected: Zero, Sign, Carry, Overflow, FRO, FIV, FZD, FOV, FUD, FPR
+    // Sub-opcode: 0b000111
+    pub fn divf_s(&mut self, reg2_index: usize, reg1_index: usize) -> u32 {
+        self.float_arith(reg2_index, reg1_index, |a, b| {
+            if b == 0.0 {
+                return Err(if a == 0.0 { FpException::InvalidOperation } else { FpException::DivideByZero });
+            }
+
+            let res = a / b;
+            Ok((res, res as f64 * b as f64 != a as f64))
+        });
+
+        44
+    }
+
+    // Shared by the floating point arithmetic instructions.
+    // "op" takes (reg2, reg1) and returns the rounded result and whether it's inexact
+    fn float_arith<F>(&mut self, reg2_index: usize, reg1_index: usize, op: F)
+    where F: Fn(f32, f32) -> Result<(f32, bool), FpException> {
+        let reg2 = f32::from_bits(self.regs.gprs[reg2_index]);
+        let reg1 = f32::from_bits(self.regs.gprs[reg1_index]);
+
+        if is_reserved(reg1) || is_reserved(reg2) {
+            self.fp_exception(FpException::ReservedOperand);
+            return;
+        }
+
+        let (mut res, inexact) = match op(reg2, reg1) {
+            Ok(res) => res,
+            Err(exception) => {
+                self.fp_exception(exception);
+                return;
+            }
+        };
+
+        if res.is_infinite() {
+            self.regs.psw.set_fpr(true);
+            self.fp_exception(FpException::Overflow);
+            return;
+        }
+
+        if inexact {
+            self.regs.psw.set_fpr(true);
+        }
+
+        // Results too small to be a normal float (including non-zero results that got rounded to 0) are flushed to zero
+        if res.is_subnormal() || (res == 0.0 && inexact) {
+            self.regs.psw.set_fud(true);
+            res = 0.0;
+        }
+
+        self.set_float_flags(res);
+        self.regs.gprs[reg2_index] = res.to_bits();
+    }
+
+    // Sets the integer flags according to a floating point result. Carry mirrors the sign and overflow is always cleared
+    fn set_float_flags(&mut self, res: f32) {
+        self.regs.psw.set_zero(res == 0.0);
+        self.regs.psw.set_sign(res < 0.0);
+        self.regs.psw.set_carry(res < 0.0);
+        self.regs.psw.set_overflow(false);
+    }
+
+    // Sets the PSW flag corresponding to a floating point exception and raises it.
+    // The return address is the faulting instruction, which is always 4 bytes long
+    fn fp_exception(&mut self, exception: FpException) {
+        let code = match exception {
+            FpException::ReservedOperand => { self.regs.psw.set_fro(true); codes::FP_RESERVED_OPERAND },
+            FpException::Overflow => { self.regs.psw.set_fov(true); codes::FP_OVERFLOW },
+            FpException::DivideByZero => { self.regs.psw.set_fzd(true); codes::FP_ZERO_DIVISION },
+            FpException::InvalidOperation => { self.regs.psw.set_fiv(true); codes::FP_INVALID_OPERATION },
+        };
+
+        self.raise_exception(code, self.regs.pc.wrapping_sub(4));
+    }
+
+    // Swaps the 2 lower bytes of reg2
+    // Cycles: 6
+    // Flags affected: none
+    // Sub-opcode: 0b001000
+    pub fn xb(&mut self, reg2_index: usize) -> u32 {
+        let reg2 = self.regs.gprs[reg2_index];
+        self.regs.gprs[reg2_index] = (reg2 & 0xFFFF0000) | ((reg2 >> 8) & 0xFF) | ((reg2 & 0xFF) << 8);
+
+        6
+    }
+
+    // Swaps the 2 halfwords of reg2
+    // Cycles: 1
+    // Flags affected: none
+    // Sub-opcode: 0b001001
+    pub fn xh(&mut self, reg2_index: usize) -> u32 {
+        self.regs.gprs[reg2_index] = self.regs.gprs[reg2_index].rotate_left(16);
+
+        1
+    }
+
+    // reg2 = reg1 with its bits in reverse order
+    // Cycles: 22
+    // Flags affected: none
+    // Sub-opcode: 0b001010
+    pub fn rev(&mut self, reg2_index: usize, reg1_index: usize) -> u32 {
+        self.regs.gprs[reg2_index] = self.regs.gprs[reg1_index].reverse_bits();
+
+        22
+    }
+
+    // reg2 = reg2 * (sign extend) (lower 17 bits of reg1)
+    // Cycles: 9
+    // Flags affected: none
+    // Sub-opcode: 0b001100
+    pub fn mpyhw(&mut self, reg2_index: usize, reg1_index: usize) -> u32 {
+        let reg1 = (self.regs.gprs[reg1_index] as i32) << 15 >> 15; // Sign extend the lower 17 bits
+        let reg2 = self.regs.gprs[reg2_index] as i32;
+
+        self.regs.gprs[reg2_index] = reg2.wrapping_mul(reg1) as u32;
+
+        9
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC: u32 = 0x07000000; // Address of the instruction
+
+    const ONE: u32 = 0x3F800000;
+    const TWO: u32 = 0x40000000;
+    const THREE: u32 = 0x40400000;
+    const NAN: u32 = 0x7FC00000;
+    const INFINITY: u32 = 0x7F800000;
+    const DENORMAL: u32 = 0x00000001;
+
+    // Runs "op r7, r6" (or "op r6" for XB and XH) at "PC" with r6 = reg2 and r7 = reg1, starting with every flag cleared
+    fn run(op: &str, reg2: u32, reg1: u32) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.regs.pc = PC + 4; // Already fetched
+        cpu.regs.gprs[6] = reg2;
+        cpu.regs.gprs[7] = reg1;
+
+        match op {
+            "cmpf.s" => cpu.cmpf_s(6, 7),
+            "cvt.ws" => cpu.cvt_ws(6, 7),
+            "cvt.sw" => cpu.cvt_sw(6, 7),
+            "addf.s" => cpu.addf_s(6, 7),
+            "subf.s" => cpu.subf_s(6, 7),
+            "mulf.s" => cpu.mulf_s(6, 7),
+            "divf.s" => cpu.divf_s(6, 7),
+            "trnc.sw" => cpu.trnc_sw(6, 7),
+            "xb" => cpu.xb(6),
+            "xh" => cpu.xh(6),
+            "rev" => cpu.rev(6, 7),
+            "mpyhw" => cpu.mpyhw(6, 7),
+            _ => unreachable!(),
+        };
+        cpu
+    }
+
+    // Exception code raised by the instruction, if any
+    fn exception(cpu: &Cpu) -> Option<u16> {
+        cpu.pending_exception.map(|(code, return_pc)| {
+            assert_eq!(return_pc, PC); // Returns to the instruction itself
+            code
+        })
+    }
+
+    // PSW flags, from bit 0: Z, S, OV, CY, FPR, FUD, FOV, FZD, FIV, FRO
+    fn flags(cpu: &Cpu) -> u32 {
+        cpu.regs.psw.raw() & 0x3FF
+    }
+
+    #[test]
+    fn reserved_operands() {
+        let cases = [("addf.s", NAN, ONE), ("subf.s", ONE, INFINITY), ("mulf.s", DENORMAL, TWO), ("divf.s", ONE, NAN), ("cmpf.s", ONE, DENORMAL)];
+        for &(op, reg2, reg1) in &cases {
+            let cpu = run(op, reg2, reg1);
+            assert_eq!(exception(&cpu), Some(codes::FP_RESERVED_OPERAND), "{}", op);
+            assert_eq!(flags(&cpu), 1 << 9, "{}", op); // FRO
+            assert_eq!(cpu.regs.gprs[6], reg2, "{}", op); // Left untouched
+        }
+
+        for &op in &["cvt.sw", "trnc.sw"] {
+            let cpu = run(op, 5, INFINITY);
+            assert_eq!(exception(&cpu), Some(codes::FP_RESERVED_OPERAND), "{}", op);
+            assert_eq!(flags(&cpu), 1 << 9, "{}", op);
+            assert_eq!(cpu.regs.gprs[6], 5, "{}", op);
+        }
+    }
+
+    #[test]
+    fn divide_by_zero() {
+        let cpu = run("divf.s", ONE, 0);
+        assert_eq!(exception(&cpu), Some(codes::FP_ZERO_DIVISION));
+        assert_eq!(flags(&cpu), 1 << 7); // FZD
+        assert_eq!(cpu.regs.gprs[6], ONE);
+
+        // 0 / 0 is an invalid operation instead
+        let cpu = run("divf.s", 0, 0x80000000);
+        assert_eq!(exception(&cpu), Some(codes::FP_INVALID_OPERATION));
+        assert_eq!(flags(&cpu), 1 << 8); // FIV
+        assert_eq!(cpu.regs.gprs[6], 0);
+    }
+
+    #[test]
+    fn invalid_conversions() {
+        for &op in &["cvt.sw", "trnc.sw"] {
+            let cpu = run(op, 5, 0x4F32D05E); // 3e9
+            assert_eq!(exception(&cpu), Some(codes::FP_INVALID_OPERATION), "{}", op);
+            assert_eq!(flags(&cpu), 1 << 8, "{}", op); // FIV
+            assert_eq!(cpu.regs.gprs[6], 5, "{}", op);
+        }
+
+        // -2^31 fits
+        let cpu = run("trnc.sw", 5, 0xCF000000);
+        assert_eq!(exception(&cpu), None);
+        assert_eq!(cpu.regs.gprs[6], 0x80000000);
+        assert_eq!(flags(&cpu), 1 << 1); // S
+    }
+
+    #[test]
+    fn overflow() {
+        let cpu = run("mulf.s", 0x7F61B1E6, 0x41200000); // 3e38 * 10
+        assert_eq!(exception(&cpu), Some(codes::FP_OVERFLOW));
+        assert_eq!(flags(&cpu), 1 << 6 | 1 << 4); // FOV and FPR
+        assert_eq!(cpu.regs.gprs[6], 0x7F61B1E6);
+    }
+
+    #[test]
+    fn underflow_flushes_to_zero() {
+        let cpu = run("mulf.s", 0x0DA24260, 0x2EDBE6FF); // 1e-30 * 1e-10
+        assert_eq!(exception(&cpu), None); // Underflows don't raise exceptions
+        assert_eq!(cpu.regs.gprs[6], 0);
+        assert_eq!(flags(&cpu), 1 << 5 | 1 << 4 | 1); // FUD, FPR and Z
+    }
+
+    #[test]
+    fn inexact_results() {
+        let cpu = run("divf.s", ONE, THREE);
+        assert_eq!(exception(&cpu), None); // Neither do inexact results
+        assert_eq!(cpu.regs.gprs[6], 0x3EAAAAAB);
+        assert_eq!(flags(&cpu), 1 << 4); // FPR
+
+        let cpu = run("divf.s", 0x40C00000, THREE); // 6 / 3
+        assert_eq!(cpu.regs.gprs[6], TWO);
+        assert_eq!(flags(&cpu), 0);
+
+        let cpu = run("addf.s", 0x4B800000, ONE); // 2^24 + 1
+        assert_eq!(cpu.regs.gprs[6], 0x4B800000);
+        assert_eq!(flags(&cpu), 1 << 4);
+
+        // Integers past 2^24 lose precision when converted
+        let cpu = run("cvt.ws", 0, 16777217);
+        assert_eq!(cpu.regs.gprs[6], 0x4B800000);
+        assert_eq!(flags(&cpu), 1 << 4);
+
+        let cpu = run("cvt.ws", 0, -3i32 as u32);
+        assert_eq!(cpu.regs.gprs[6], 0xC0400000);
+        assert_eq!(flags(&cpu), 0b1010); // S and CY
+    }
+
+    #[test]
+    fn float_to_int_rounding() {
+        let cases = [
+            ("cvt.sw", 0x40200000, 2, true),   // 2.5 rounds to even
+            ("cvt.sw", 0x40600000, 4, true),   // 3.5 too
+            ("cvt.sw", THREE, 3, false),
+            ("trnc.sw", 0xC02CCCCD, -2, true), // -2.7 towards zero
+        ];
+        for &(op, reg1, res, inexact) in &cases {
+            let cpu = run(op, 0, reg1);
+            assert_eq!(cpu.regs.gprs[6], res as u32, "{} {:#X}", op, reg1);
+            assert_eq!(cpu.regs.psw.fpr(), inexact, "{} {:#X}", op, reg1);
+            assert_eq!(cpu.regs.psw.sign(), res < 0, "{} {:#X}", op, reg1);
+        }
+    }
+
+    #[test]
+    fn cmpf_flags() {
+        // reg2, reg1, Z S OV CY
+        let cases = [(ONE, TWO, 0b1010), (TWO, ONE, 0), (ONE, ONE, 0b0001), (0x80000000, 0, 0b0001), (0xC0000000, ONE, 0b1010)];
+        for &(reg2, reg1, expected) in &cases {
+            let cpu = run("cmpf.s", reg2, reg1);
+            assert_eq!(flags(&cpu), expected, "{:#X} vs {:#X}", reg2, reg1);
+            assert_eq!(cpu.regs.gprs[6], reg2); // Discarded
+        }
+    }
+
+    #[test]
+    fn nintendo_instructions() {
+        let cases = [
+            ("xb", 0x12345678, 0, 0x12347856),
+            ("xh", 0x12345678, 0, 0x56781234),
+            ("rev", 0, 0x12345678, 0x1E6A2C48),
+            ("rev", 0, 1, 0x80000000),
+            ("mpyhw", 3, 0x0001FFFF, -3i32 as u32),      // The lower 17 bits of reg1 are -1
+            ("mpyhw", 3, 0xFFFF0002, -196602i32 as u32), // 0x10002 sign extends to -65534
+            ("mpyhw", 7, 0x7FFE0005, 35),                // Bits above the lower 17 are ignored
+        ];
+        for &(op, reg2, reg1, res) in &cases {
+            let cpu = run(op, reg2, reg1);
+            assert_eq!(cpu.regs.gprs[6], res, "{} {:#X}, {:#X}", op, reg2, reg1);
+            assert_eq!(flags(&cpu), 0, "{}", op); // No flags affected
+        }
+    }
+}
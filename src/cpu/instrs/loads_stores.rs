@@ -1,3 +1,4 @@
+use super::decoder::Instr;
 use crate::bus::Bus;
 use crate::cpu::Cpu;
 
@@ -9,75 +10,142 @@ impl Cpu {
     //  5 cycles	When used in an isolated context.
     // Flags affected: none
     // Opcode: 0b000110
-    pub fn ld_byte (&mut self, bus: &Bus, instr: u16) {
-        let reg1_index = instr as usize & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
+    pub fn ld_byte (&mut self, bus: &Bus, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
 
-        let offset = self.consume_halfword(bus) as i16 as u32; // sign extend offset
+        let offset = instr.imm;
         let addr = self.regs.gprs[reg1_index].wrapping_add(offset);
 
         self.regs.gprs[reg2_index] = bus.read8(addr) as i8 as u32; // read byte, sign extend it
+
+        self.load_cycles()
     }
 
-    pub fn ld_halfword (&mut self, bus: &Bus, instr: u16) {
-        let reg1_index = instr as usize & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
+    pub fn ld_halfword (&mut self, bus: &Bus, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
 
-        let offset = self.consume_halfword(bus) as i16 as u32; // sign extend offset
+        let offset = instr.imm;
         let addr = self.regs.gprs[reg1_index].wrapping_add(offset);
 
         self.regs.gprs[reg2_index] = bus.read16(addr) as i16 as u32; // read halfword, sign extend it
+
+        self.load_cycles()
     }
 
-    pub fn ld_word (&mut self, bus: &Bus, instr: u16) {
-        let reg1_index = instr as usize & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
+    pub fn ld_word (&mut self, bus: &Bus, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
 
-        let offset = self.consume_halfword(bus) as i16 as u32; // sign extend offset
+        let offset = instr.imm;
         let addr = self.regs.gprs[reg1_index].wrapping_add(offset);
 
         self.regs.gprs[reg2_index] = bus.read32(addr); // read word
+
+        self.load_cycles()
     }
 
-    pub fn st_byte (&mut self, bus: &mut Bus, instr: u16) {
-        let reg1_index = instr as usize & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
+    pub fn st_byte (&mut self, bus: &mut Bus, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
 
-        let offset = self.consume_halfword(bus) as i16 as u32; // sign extend offset
+        let offset = instr.imm;
         let addr = self.regs.gprs[reg1_index].wrapping_add(offset);
 
         bus.write8(addr, self.regs.gprs[reg2_index] as u8);
+
+        self.store_cycles()
     }
 
-    pub fn st_halfword (&mut self, bus: &mut Bus, instr: u16) {
-        let reg1_index = instr as usize & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
+    pub fn st_halfword (&mut self, bus: &mut Bus, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
 
-        let offset = self.consume_halfword(bus) as i16 as u32; // sign extend offset
+        let offset = instr.imm;
         let addr = self.regs.gprs[reg1_index].wrapping_add(offset);
 
         bus.write16(addr, self.regs.gprs[reg2_index] as u16);
+
+        self.store_cycles()
     }
 
-    pub fn st_word (&mut self, bus: &mut Bus, instr: u16) {
-        let reg1_index = instr as usize & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
+    pub fn st_word (&mut self, bus: &mut Bus, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
 
-        let offset = self.consume_halfword(bus) as i16 as u32; // sign extend offset
+        let offset = instr.imm;
         let addr = self.regs.gprs[reg1_index].wrapping_add(offset);
 
         bus.write32(addr, self.regs.gprs[reg2_index]);
+
+        self.store_cycles()
+    }
+
+    // reg2 = (zero extend) [reg1 + (sign extend) offset]
+    // IN instructions are meant for I/O ports, but they access the same bus as loads. Unlike loads, they zero extend.
+    // Cycles: Same as LD
+    // Flags affected: none
+    // Opcode: 0b111000
+    pub fn in_byte(&mut self, bus: &Bus, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
+
+        let offset = instr.imm;
+        let addr = self.regs.gprs[reg1_index].wrapping_add(offset);
+
+        self.regs.gprs[reg2_index] = bus.read8(addr) as u32; // read byte, zero extend it
+
+        self.load_cycles()
     }
 
-    pub fn in_byte(&mut self, bus: &Bus, instr: u16) {
-        
+    pub fn in_halfword(&mut self, bus: &Bus, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
+
+        let offset = instr.imm;
+        let addr = self.regs.gprs[reg1_index].wrapping_add(offset);
+
+        self.regs.gprs[reg2_index] = bus.read16(addr) as u32; // read halfword, zero extend it
+
+        self.load_cycles()
     }
 
-    pub fn in_halfword(&mut self, bus: &Bus, instr: u16) {
-        
+    pub fn in_word(&mut self, bus: &Bus, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
+
+        let offset = instr.imm;
+        let addr = self.regs.gprs[reg1_index].wrapping_add(offset);
+
+        self.regs.gprs[reg2_index] = bus.read32(addr); // read word
+
+        self.load_cycles()
     }
 
-    pub fn in_word(&mut self, bus: &Bus, instr: u16) {
-        
+    // Compare and exchange interlocked. tmp = (word) [reg1 + disp], (discard) reg2 - tmp.
+    // If equal, [reg1 + disp] = r30, otherwise tmp is written back unchanged. Either way, reg2 = tmp
+    // Cycles: 26
+    // Flags affected: Zero, Sign, Carry, Overflow
+    // Opcode: 0b111010
+    pub fn caxi(&mut self, bus: &mut Bus, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
+
+        let offset = instr.imm;
+        let addr = self.regs.gprs[reg1_index].wrapping_add(offset);
+
+        let reg2 = self.regs.gprs[reg2_index];
+        let tmp = bus.read32(addr);
+
+        let (res, overflow) = (reg2 as i32).overflowing_sub(tmp as i32);
+        self.regs.psw.set_sign_and_zero(res as u32);
+        self.regs.psw.set_carry(tmp > reg2); // Set carry if the subtraction borrowed.
+        self.regs.psw.set_overflow(overflow);
+
+        bus.write32(addr, if tmp == reg2 { self.regs.gprs[30] } else { tmp });
+        self.regs.gprs[reg2_index] = tmp;
+
+        26
     }
-}
\ No newline at end of file
+}
@@ -1,8 +1,58 @@
-use crate::bus::Bus;
+use super::decoder::Instr;
+use crate::cpu::exceptions::codes;
 use crate::cpu::Cpu;
 
 impl Cpu {
-    pub fn sei (&mut self) {
+    pub fn sei (&mut self) -> u32 {
         self.regs.psw.set_irqs_disabled(true);
+
+        12
+    }
+
+    // interrupts disabled = false
+    // Cycles: 12
+    // Flags affected: ID
+    // Opcode: 0b010110
+    pub fn cli (&mut self) -> u32 {
+        self.regs.psw.set_irqs_disabled(false);
+
+        12
+    }
+
+    // Raise exception 0xFFA0 + vector. The return address is the next instruction
+    // Cycles: 15
+    // Flags affected: EP, ID, AE
+    // Opcode: 0b011000
+    pub fn trap (&mut self, instr: Instr) -> u32 {
+        let vector = instr.imm as u16;
+        self.raise_exception(codes::TRAP_BASE + vector, self.regs.pc);
+
+        15
+    }
+
+    // Return from a duplexed exception (if NP is set) or from a regular exception/interrupt
+    // Cycles: 10
+    // Flags affected: All (PSW is restored)
+    // Opcode: 0b011001
+    pub fn reti (&mut self) -> u32 {
+        if self.regs.psw.nmi_pending() {
+            self.regs.pc = self.regs.fepc;
+            self.regs.psw.set_raw(self.regs.fepsw);
+        } else {
+            self.regs.pc = self.regs.eipc;
+            self.regs.psw.set_raw(self.regs.eipsw);
+        }
+
+        10
+    }
+
+    // Stop executing instructions until an interrupt is accepted. The interrupt returns to the next instruction
+    // Cycles: Indefinite
+    // Flags affected: none
+    // Opcode: 0b011010
+    pub fn halt (&mut self) -> u32 {
+        self.halted = true;
+
+        1
     }
-}
\ No newline at end of file
+}
@@ -1,54 +1,212 @@
+use super::decoder::Instr;
+use super::system_regs;
 use crate::bus::Bus;
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CHCW_ICE};
+
+const PSW_MASK: u32 = 0x000FF3FF; // Bits 10, 11 and 20-31 of PSW (and EIPSW/FEPSW) are reserved and always 0
+const PIR_VALUE: u32 = 0x00005346; // Processor ID register. Identifies the CPU as a V810 (NEC uPD70732)
+const TKCW_VALUE: u32 = 0x000000E0; // Task control word. Fixed FPU rounding and exception settings on the VB
+const SR30_VALUE: u32 = 0x00000004; // Undocumented system register 30, which always reads as 4
+
+const CHCW_ICC: u32 = 1 << 0; // Cache clear
+const CHCW_ICD: u32 = 1 << 4; // Cache dump
+const CHCW_ICR: u32 = 1 << 5; // Cache restore
 
 impl Cpu {
     // reg2 = reg1 + (imm << 16)
     // Cycles: 1
     // Flags affected: none
     // Opcode: 0b101111
-    pub fn movhi(&mut self, bus: &mut Bus, instr: u16) {
-        let reg1_index = instr as usize & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
-        let offset = (self.consume_halfword(bus) as u32) << 16;
+    pub fn movhi(&mut self, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
+        let offset = instr.imm;
 
         self.regs.gprs[reg2_index] = self.regs.gprs[reg1_index].wrapping_add(offset);
+
+        1
     }
 
     // reg2 = reg1 + (sign extend) imm
     // Cycles: 1
     // Flags affected: none
     // Opcode: 0b101111
-    pub fn movea(&mut self, bus: &mut Bus, instr: u16) {
-        let reg1_index = instr as usize & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
-        let offset = self.consume_halfword(bus) as i16 as u32; // Fetch immediate and sign extend ti
+    pub fn movea(&mut self, instr: Instr) -> u32 {
+        let reg1_index = instr.reg1;
+        let reg2_index = instr.reg2;
+        let offset = instr.imm;
 
         self.regs.gprs[reg2_index] = self.regs.gprs[reg1_index].wrapping_add(offset);
+
+        1
     }
 
-    pub fn mov_imm(&mut self, bus: &mut Bus, instr: u16) {
-        let reg2_index = (instr >> 5 & 0x1F) as usize;
-        let imm = ((instr as i32) << 27 >> 27) as u32; // sign extend immediate
+    pub fn mov_imm(&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let imm = instr.imm;
 
         self.regs.gprs[reg2_index] = imm;
+
+        1
     }
 
-    pub fn mov_reg(&mut self, bus: &mut Bus, instr: u16) {
-        let reg2_index = (instr >> 5 & 0x1F) as usize;
-        let reg1_index = (instr & 0x1F) as usize;
+    pub fn mov_reg(&mut self, instr: Instr) -> u32 {
+        let reg2_index = instr.reg2;
+        let reg1_index = instr.reg1;
 
         self.regs.gprs[reg2_index] = self.regs.gprs[reg1_index];
+
+        1
     }
 
-    pub fn ldsr(&mut self, bus: &mut Bus, instr: u16) {
-        let system_reg_id = instr & 0x1F;
-        let reg2_index = (instr as usize >> 5) & 0x1F;
+    // systemReg = reg2. Writes to read-only registers (ECR, PIR, TKCW, SR30) and reserved IDs are ignored
+    // Cycles: 8
+    // Flags affected: All if the destination is PSW, none otherwise
+    // Opcode: 0b011100
+    pub fn ldsr(&mut self, bus: &mut Bus, instr: Instr) -> u32 {
+        let system_reg_id = instr.reg1 as u16;
+        let reg2_index = instr.reg2;
         let reg2 = self.regs.gprs[reg2_index];
 
         match system_reg_id {
-            5 => { self.regs.psw.set_raw(reg2); println!("Wrote {:08X} to PSW", reg2) },
-            24 => println!("Unimplemented write to Cache Control! Value {:08X}", reg2),
-            _ => panic!("LDSR with unimplemented system register. Register id: {}", system_reg_id)
+            system_regs::EIPC => self.regs.eipc = reg2 & !1,
+            system_regs::EIPSW => self.regs.eipsw = reg2 & PSW_MASK,
+            system_regs::FEPC => self.regs.fepc = reg2 & !1,
+            system_regs::FEPSW => self.regs.fepsw = reg2 & PSW_MASK,
+            system_regs::PSW => self.regs.psw.set_raw(reg2 & PSW_MASK),
+            system_regs::CHCW => self.write_chcw(bus, reg2),
+            system_regs::ADTRE => self.regs.adtre = reg2 & !1,
+            system_regs::SR29 => self.regs.sr29 = reg2,
+            system_regs::SR31 => self.regs.sr31 = (reg2 as i32).wrapping_abs() as u32,
+            _ => {}
         }
+
+        8
+    }
+
+    // reg2 = systemReg. Reserved IDs read as 0
+    // Cycles: 8
+    // Flags affected: none
+    // Opcode: 0b011101
+    pub fn stsr(&mut self, instr: Instr) -> u32 {
+        let system_reg_id = instr.reg1 as u16;
+        let reg2_index = instr.reg2;
+
+        self.regs.gprs[reg2_index] = self.system_reg(system_reg_id);
+
+        8
+    }
+
+    // Value of a system register, as STSR reads it
+    pub fn system_reg(&self, id: u16) -> u32 {
+        match id {
+            system_regs::EIPC => self.regs.eipc,
+            system_regs::EIPSW => self.regs.eipsw,
+            system_regs::FEPC => self.regs.fepc,
+            system_regs::FEPSW => self.regs.fepsw,
+            system_regs::ECR => self.regs.ecr,
+            system_regs::PSW => self.regs.psw.raw(),
+            system_regs::PIR => PIR_VALUE,
+            system_regs::TKCW => TKCW_VALUE,
+            system_regs::CHCW => self.regs.chcw,
+            system_regs::ADTRE => self.regs.adtre,
+            system_regs::SR29 => self.regs.sr29,
+            system_regs::SR30 => SR30_VALUE,
+            system_regs::SR31 => self.regs.sr31,
+            _ => 0,
+        }
+    }
+
+    // Set a system register directly, without the masking and side effects of LDSR. For debuggers.
+    // Registers with a fixed value can't be written
+    pub fn set_system_reg(&mut self, id: u16, val: u32) {
+        match id {
+            system_regs::EIPC => self.regs.eipc = val,
+            system_regs::EIPSW => self.regs.eipsw = val,
+            system_regs::FEPC => self.regs.fepc = val,
+            system_regs::FEPSW => self.regs.fepsw = val,
+            system_regs::ECR => self.regs.ecr = val,
+            system_regs::PSW => self.regs.psw.set_raw(val),
+            system_regs::CHCW => self.regs.chcw = val,
+            system_regs::ADTRE => self.regs.adtre = val,
+            system_regs::SR29 => self.regs.sr29 = val,
+            system_regs::SR31 => self.regs.sr31 = val,
+            _ => {}
+        }
+    }
+
+    // Only the cache enable bit is stored. The other bits trigger cache operations, described in cache.rs.
+    // A clear happens before a dump or restore. If both ICD and ICR are set, the cache is only dumped
+    fn write_chcw(&mut self, bus: &mut Bus, val: u32) {
+        self.regs.chcw = val & CHCW_ICE;
+
+        if val & CHCW_ICC != 0 {
+            let count = (val >> 8) as usize & 0xFFF;
+            let start = (val >> 20) as usize & 0xFFF;
+            self.cache.clear(start, count);
+        }
+
+        let addr = val & 0xFFFFFF00;
+        if val & CHCW_ICD != 0 {
+            self.cache.dump(bus, addr);
+        } else if val & CHCW_ICR != 0 {
+            self.cache.restore(bus, addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: u32 = 0x07000000;
+    const WRAM: u32 = 0x05000000;
+    const DUMP: u32 = 0x05001000; // Where the cache gets dumped to be inspected
+
+    // A CPU with all 128 cache entries filled from the start of ROM
+    fn filled_cache() -> (Cpu, Bus) {
+        let bus = Bus::new("ROMs/ScreenDemo1.vb");
+        let mut cpu = Cpu::new();
+        for addr in (ROM..ROM + 0x400).step_by(4) {
+            cpu.cache.fetch(&bus, addr);
+        }
+        (cpu, bus)
+    }
+
+    // Indices of the cache entries with both words valid, as a dump shows them
+    fn valid_entries(cpu: &Cpu, bus: &mut Bus) -> Vec<u32> {
+        cpu.cache.dump(bus, DUMP);
+        (0..128).filter(|&i| bus.read32(DUMP + 0x400 + i * 4) >> 22 & 3 == 3).collect()
+    }
+
+    #[test]
+    fn partial_clear() {
+        let (mut cpu, mut bus) = filled_cache();
+        cpu.write_chcw(&mut bus, 5 << 20 | 3 << 8 | CHCW_ICC); // 3 entries (CEC), starting from entry 5 (CEN)
+
+        let expected: Vec<u32> = (0..128).filter(|i| !(5..8).contains(i)).collect();
+        assert_eq!(valid_entries(&cpu, &mut bus), expected);
+        assert_eq!(cpu.regs.chcw, 0);
+    }
+
+    #[test]
+    fn dump_wins_over_restore() {
+        let (mut cpu, mut bus) = filled_cache();
+        for addr in (WRAM..WRAM + 0x600).step_by(4) {
+            bus.write32(addr, 0xDEADBEEF); // Restoring from this would leave no entry fully valid
+        }
+
+        cpu.write_chcw(&mut bus, WRAM | CHCW_ICR | CHCW_ICD | CHCW_ICE);
+
+        assert_eq!(bus.read32(WRAM + 8), bus.read32(ROM + 8)); // Data of entry 1
+        assert_eq!(bus.read32(WRAM + 0x404), ROM >> 10 | 3 << 22); // Tag and valid bits of entry 1
+        assert_eq!(valid_entries(&cpu, &mut bus).len(), 128);
+        assert_eq!(cpu.regs.chcw, CHCW_ICE);
+
+        // On its own, ICR restores what was dumped
+        cpu.write_chcw(&mut bus, 128 << 8 | CHCW_ICC);
+        assert!(valid_entries(&cpu, &mut bus).is_empty());
+        cpu.write_chcw(&mut bus, WRAM | CHCW_ICR);
+        assert_eq!(valid_entries(&cpu, &mut bus).len(), 128);
     }
 }
@@ -0,0 +1,259 @@
+use super::instrs::decoder::Instr;
+use super::instrs::disassembler::{self, Syntax};
+use super::Regs;
+use crate::symbols::Symbols;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/*
+    Instruction trace logging.
+
+    After every instruction, a Tracer is given the address and instruction that ran along with the registers from before and after it,
+    and logs a line for it, either to a file (or any other writer) or to a ring buffer that keeps the last N lines around.
+
+    Which instructions get logged can be narrowed down with filters, all of which have to pass for a line to be logged:
+    - An address range, to only trace some part of the code
+    - A window of instruction numbers, counting every instruction run since tracing started, logged or not
+    - A trigger address. Nothing is logged until the instruction there runs for the first time
+
+    Lines come in one of two formats:
+    Full: instruction number, address, raw halfwords, NEC disassembly, then every register that changed and the PSW
+    "        42  07000010  BC20 0700  movhi 0x700, r0, r1            r1=07000000 psw=00008000"
+
+    With symbols loaded, a column with the symbol of the address goes after it, and branch targets get theirs appended:
+    "         8  07000022  fact+0x2              DFE3 0000  st.w r31, 0[r3]                  psw=00008008"
+
+    Mednafen: address and disassembly in the style of Mednafen's trace logs, so that traces can be diffed against it
+    "07000010: MOVHI 0x700, r0, r1"
+    Symbols are left out of these to keep them comparable.
+
+    Steps spent halted don't run an instruction, so they don't show up in the trace.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    Full,
+    Mednafen,
+}
+
+enum Output {
+    Writer(Box<dyn Write>),
+    RingBuffer { lines: VecDeque<String>, capacity: usize },
+}
+
+pub struct Tracer {
+    output: Output,
+    pub format: TraceFormat,
+    pub pc_range: Option<(u32, u32)>,     // Only log instructions whose address is within start..=end
+    pub count_window: Option<(u64, u64)>, // Only log instructions numbered first..=last, starting from 0
+    pub trigger: Option<u32>,             // Don't log anything until the instruction at this address runs. Cleared once it does
+    pub error: Option<io::Error>,         // Set if writing a line failed, which stops all further logging
+    count: u64,                           // Number of instructions run since tracing started
+}
+
+impl Tracer {
+    fn new(output: Output) -> Tracer {
+        Tracer {
+            output,
+            format: TraceFormat::Full,
+            pc_range: None,
+            count_window: None,
+            trigger: None,
+            error: None,
+            count: 0,
+        }
+    }
+
+    // Log to a file, replacing its contents
+    pub fn to_file(path: &str) -> io::Result<Tracer> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(Output::Writer(Box::new(BufWriter::new(file)))))
+    }
+
+    pub fn to_writer(writer: Box<dyn Write>) -> Tracer {
+        Tracer::new(Output::Writer(writer))
+    }
+
+    // Keep only the last "capacity" lines in memory. Read them with lines
+    pub fn ring_buffer(capacity: usize) -> Tracer {
+        Tracer::new(Output::RingBuffer { lines: VecDeque::with_capacity(capacity), capacity })
+    }
+
+    // Number of instructions run since tracing started
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // Lines held in the ring buffer, oldest first. Always empty when logging to a writer
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        let lines = match &self.output {
+            Output::RingBuffer { lines, .. } => Some(lines.iter().map(String::as_str)),
+            Output::Writer(_) => None,
+        };
+        lines.into_iter().flatten()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.output {
+            Output::Writer(writer) => writer.flush(),
+            Output::RingBuffer { .. } => Ok(()),
+        }
+    }
+
+    // Called after every instruction, with the registers from before and after it ran
+    pub fn log(&mut self, pc: u32, instr: &Instr, before: &Regs, after: &Regs, symbols: &Symbols) {
+        let index = self.count;
+        self.count += 1;
+
+        if self.trigger == Some(pc) {
+            self.trigger = None;
+        }
+
+        let in_range = self.pc_range.map_or(true, |(start, end)| (start..=end).contains(&pc));
+        let in_window = self.count_window.map_or(true, |(first, last)| (first..=last).contains(&index));
+        if self.trigger.is_some() || !in_range || !in_window || self.error.is_some() {
+            return;
+        }
+
+        let line = match self.format {
+            TraceFormat::Full => full_line(index, pc, instr, before, after, symbols),
+            TraceFormat::Mednafen => format!("{:08X}: {}", pc, disassembler::disassemble(instr, pc).render(Syntax::Mednafen)),
+        };
+
+        match &mut self.output {
+            Output::Writer(writer) => {
+                if let Err(error) = writeln!(writer, "{}", line) {
+                    self.error = Some(error);
+                }
+            }
+
+            Output::RingBuffer { lines, capacity } => {
+                if lines.len() == *capacity {
+                    lines.pop_front();
+                }
+                if *capacity != 0 {
+                    lines.push_back(line);
+                }
+            }
+        }
+    }
+}
+
+fn full_line(index: u64, pc: u32, instr: &Instr, before: &Regs, after: &Regs, symbols: &Symbols) -> String {
+    let raw = if instr.len == 4 {
+        format!("{:04X} {:04X}", instr.raw >> 16, instr.raw & 0xFFFF)
+    } else {
+        format!("{:04X}", instr.raw)
+    };
+    let disassembly = disassembler::disassemble(instr, pc).render_with_symbols(Syntax::Nec, symbols);
+    let location = if symbols.is_empty() { String::new() } else { format!("{:<20}  ", symbols.describe(pc).unwrap_or_default()) };
+
+    let mut changes: Vec<String> = (1..32)
+        .filter(|&reg| before.gprs[reg] != after.gprs[reg])
+        .map(|reg| format!("r{}={:08X}", reg, after.gprs[reg]))
+        .collect();
+
+    let system_regs = [
+        ("eipc", before.eipc, after.eipc),
+        ("eipsw", before.eipsw, after.eipsw),
+        ("fepc", before.fepc, after.fepc),
+        ("fepsw", before.fepsw, after.fepsw),
+        ("ecr", before.ecr, after.ecr),
+        ("chcw", before.chcw, after.chcw),
+        ("adtre", before.adtre, after.adtre),
+        ("sr29", before.sr29, after.sr29),
+        ("sr31", before.sr31, after.sr31),
+    ];
+    changes.extend(system_regs.iter().filter(|(_, old, new)| old != new).map(|(name, _, new)| format!("{}={:08X}", name, new)));
+    changes.push(format!("psw={:08X}", after.psw.raw()));
+
+    format!("{:>10}  {:08X}  {}{:<9}  {:<32} {}", index, pc, location, raw, disassembly, changes.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::instrs::decoder;
+    use crate::cpu::Cpu;
+
+    // Logs "movhi 0x700, r0, r1" as if it ran at each of "pcs"
+    fn log_movhi(tracer: &mut Tracer, pcs: &[u32], symbols: &Symbols) {
+        let instr = decoder::decode(0xBC20, 0x0700);
+        let before = Cpu::new().regs;
+        let mut after = before.clone();
+        after.gprs[1] = 0x07000000;
+
+        for &pc in pcs {
+            tracer.log(pc, &instr, &before, &after, symbols);
+        }
+    }
+
+    fn logged(tracer: &Tracer) -> Vec<&str> {
+        tracer.lines().collect()
+    }
+
+    #[test]
+    fn full_format() {
+        let mut tracer = Tracer::ring_buffer(10);
+        log_movhi(&mut tracer, &[0x07000010], &Symbols::new());
+        assert_eq!(logged(&tracer), ["         0  07000010  BC20 0700  movhi 0x700, r0, r1              r1=07000000 psw=00008000"]);
+
+        // With symbols, JAL targets get theirs too
+        let symbols = Symbols::parse_text("07000000 main\n07000020 fact").unwrap();
+        let mut tracer = Tracer::ring_buffer(10);
+        log_movhi(&mut tracer, &[0x07000010], &symbols);
+
+        let jal = decoder::decode(0xAFFF, 0xFFF0); // jal -0x10
+        let mut after = Cpu::new().regs;
+        after.gprs[31] = 0x07000034;
+        tracer.log(0x07000030, &jal, &Cpu::new().regs, &after, &symbols);
+
+        assert_eq!(logged(&tracer), [
+            "         0  07000010  main+0x10             BC20 0700  movhi 0x700, r0, r1              r1=07000000 psw=00008000",
+            "         1  07000030  fact+0x10             AFFF FFF0  jal 0x07000020 <fact>            r31=07000034 psw=00008000",
+        ]);
+    }
+
+    #[test]
+    fn mednafen_format() {
+        let mut tracer = Tracer::ring_buffer(10);
+        tracer.format = TraceFormat::Mednafen;
+        let symbols = Symbols::parse_text("07000000 main").unwrap(); // Ignored
+        log_movhi(&mut tracer, &[0x07000010], &symbols);
+
+        assert_eq!(logged(&tracer), ["07000010: MOVHI 0x700, r0, r1"]);
+    }
+
+    #[test]
+    fn filters() {
+        let pcs = [0x07000000, 0x07000004, 0x07000008, 0x0700000C, 0x07000004, 0x07000008];
+        let log = |setup: fn(&mut Tracer)| {
+            let mut tracer = Tracer::ring_buffer(10);
+            tracer.format = TraceFormat::Mednafen;
+            setup(&mut tracer);
+            log_movhi(&mut tracer, &pcs, &Symbols::new());
+
+            assert_eq!(tracer.count(), pcs.len() as u64); // Everything counts, logged or not
+            tracer.lines().map(|line| line[..8].to_string()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(log(|tracer| tracer.pc_range = Some((0x07000004, 0x07000008))), ["07000004", "07000008", "07000004", "07000008"]);
+        assert_eq!(log(|tracer| tracer.count_window = Some((1, 2))), ["07000004", "07000008"]);
+        assert_eq!(log(|tracer| tracer.trigger = Some(0x0700000C)), ["0700000C", "07000004", "07000008"]);
+        assert_eq!(log(|tracer| tracer.trigger = Some(0x07000010)), Vec::<String>::new());
+
+        // Filters combine
+        let combined = log(|tracer| {
+            tracer.trigger = Some(0x07000008);
+            tracer.pc_range = Some((0x07000004, 0x07000008));
+            tracer.count_window = Some((0, 4));
+        });
+        assert_eq!(combined, ["07000008", "07000004"]);
+
+        // The ring buffer keeps the last lines
+        let mut tracer = Tracer::ring_buffer(2);
+        log_movhi(&mut tracer, &pcs, &Symbols::new());
+        assert_eq!(logged(&tracer).iter().map(|line| &line[12..20]).collect::<Vec<_>>(), ["07000004", "07000008"]);
+    }
+}
@@ -0,0 +1,398 @@
+/*
+    Minimal ELF reader, for the executables gccvb and VUEngine produce before turning them into ROMs.
+
+    Only what the emulator needs is read: the program headers (segments) to load it, the section headers and the symbol table.
+    Files have to be 32-bit little endian, like anything built for the V810. The machine type isn't checked,
+    as different toolchains have used different values for it.
+*/
+
+pub const PT_LOAD: u32 = 1;
+
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_NOBITS: u32 = 8; // Sections like .bss, which take up no space in the file
+
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+pub const STT_FILE: u8 = 4;
+
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+pub const STB_WEAK: u8 = 2;
+
+pub const SHN_UNDEF: u16 = 0;
+pub const SHN_LORESERVE: u16 = 0xFF00; // Section indices from here on are special (absolute, common...) rather than real sections
+
+const HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const SECTION_HEADER_SIZE: usize = 40;
+const SYMBOL_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: u32,   // PT_*
+    pub offset: u32, // Where the segment's contents are in the file
+    pub vaddr: u32,  // Address the code expects the segment at while running
+    pub paddr: u32,  // Address the segment is loaded at, which differs for data copied from ROM to RAM at boot
+    pub file_size: u32,
+    pub mem_size: u32, // Past file_size, the segment is zero filled
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub addr: u32,
+    pub offset: u32, // Where the section's contents are in the file
+    pub size: u32,
+    pub link: u32, // For symbol tables, the index of the section holding their names
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,   // Address, for symbols defined in a section
+    pub size: u32,    // 0 if unknown
+    pub kind: u8,     // STT_*
+    pub binding: u8,  // STB_*
+    pub section: u16, // Index of the section the symbol is defined in, or SHN_UNDEF or a special index
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+}
+
+// Whether "data" starts like an ELF file
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7FELF")
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, String> {
+        if !is_elf(data) {
+            return Err("not an ELF file".to_string());
+        }
+        if data.len() < HEADER_SIZE {
+            return Err("the ELF header is cut short".to_string());
+        }
+        if data[4] != 1 || data[5] != 1 {
+            return Err("only 32-bit little endian ELF files are supported".to_string());
+        }
+
+        let entry = read32(data, 24)?;
+        let program_headers = read32(data, 28)? as usize;
+        let section_headers = read32(data, 32)? as usize;
+        let program_header_size = read16(data, 42)? as usize;
+        let segment_count = read16(data, 44)? as usize;
+        let section_header_size = read16(data, 46)? as usize;
+        let section_count = read16(data, 48)? as usize;
+        let names_index = read16(data, 50)? as usize;
+
+        if segment_count != 0 && program_header_size < PROGRAM_HEADER_SIZE {
+            return Err("invalid program header size".to_string());
+        }
+        if section_count != 0 && section_header_size < SECTION_HEADER_SIZE {
+            return Err("invalid section header size".to_string());
+        }
+
+        let mut segments = vec![];
+        for index in 0..segment_count {
+            let header = program_headers + index * program_header_size;
+            segments.push(Segment {
+                kind: read32(data, header)?,
+                offset: read32(data, header + 4)?,
+                vaddr: read32(data, header + 8)?,
+                paddr: read32(data, header + 12)?,
+                file_size: read32(data, header + 16)?,
+                mem_size: read32(data, header + 20)?,
+            });
+        }
+
+        let mut sections = vec![];
+        let mut name_offsets = vec![];
+        for index in 0..section_count {
+            let header = section_headers + index * section_header_size;
+            name_offsets.push(read32(data, header)? as usize);
+            sections.push(Section {
+                name: String::new(),
+                kind: read32(data, header + 4)?,
+                addr: read32(data, header + 12)?,
+                offset: read32(data, header + 16)?,
+                size: read32(data, header + 20)?,
+                link: read32(data, header + 24)?,
+            });
+        }
+
+        let mut elf = Elf { data, entry, segments, sections };
+
+        // Section names live in a string table section of their own. Index 0 means there's none, even with sections
+        if let Some(names) = elf.sections.get(names_index).filter(|_| names_index != 0) {
+            let names = elf.section_data(names)?;
+            let named: Result<Vec<String>, String> = name_offsets.iter().map(|&offset| read_string(names, offset)).collect();
+            for (section, name) in elf.sections.iter_mut().zip(named?) {
+                section.name = name;
+            }
+        }
+
+        Ok(elf)
+    }
+
+    // Contents of a segment in the file, which can be shorter than the segment itself
+    pub fn segment_data(&self, segment: &Segment) -> Result<&'a [u8], String> {
+        slice(self.data, segment.offset as usize, segment.file_size as usize)
+            .ok_or_else(|| format!("segment at {:08X} goes past the end of the file", segment.paddr))
+    }
+
+    // Contents of a section in the file. Empty for sections that take up no space in it
+    pub fn section_data(&self, section: &Section) -> Result<&'a [u8], String> {
+        if section.kind == SHT_NOBITS {
+            return Ok(&[]);
+        }
+        slice(self.data, section.offset as usize, section.size as usize)
+            .ok_or_else(|| format!("section {} goes past the end of the file", section.name))
+    }
+
+    // Every symbol of the symbol table, including the undefined ones. Empty for stripped files
+    pub fn symbols(&self) -> Result<Vec<Symbol>, String> {
+        let mut symbols = vec![];
+
+        for table in self.sections.iter().filter(|section| section.kind == SHT_SYMTAB) {
+            let entries = self.section_data(table)?;
+            let names = match self.sections.get(table.link as usize) {
+                Some(names) => self.section_data(names)?,
+                None => return Err(format!("symbol table {} has no string table", table.name)),
+            };
+
+            for entry in entries.chunks_exact(SYMBOL_SIZE) {
+                let info = entry[12];
+                symbols.push(Symbol {
+                    name: read_string(names, read32(entry, 0)? as usize)?,
+                    value: read32(entry, 4)?,
+                    size: read32(entry, 8)?,
+                    kind: info & 0xF,
+                    binding: info >> 4,
+                    section: read16(entry, 14)?,
+                });
+            }
+        }
+
+        Ok(symbols)
+    }
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Option<&[u8]> {
+    data.get(offset..offset.checked_add(len)?)
+}
+
+fn read16(data: &[u8], offset: usize) -> Result<u16, String> {
+    let bytes = slice(data, offset, 2).ok_or("unexpected end of file")?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read32(data: &[u8], offset: usize) -> Result<u32, String> {
+    let bytes = slice(data, offset, 4).ok_or("unexpected end of file")?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Null terminated string at "offset" in a string table
+fn read_string(table: &[u8], offset: usize) -> Result<String, String> {
+    let bytes = table.get(offset..).ok_or("string out of bounds of its string table")?;
+    let len = bytes.iter().position(|&byte| byte == 0).ok_or("unterminated string")?;
+    Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const TEXT: u16 = 1; // Index of the .text section in the files build_elf makes
+
+    // Offsets of the fields the tests break, in the ELF header and in section headers
+    const PROGRAM_HEADER_SIZE_FIELD: usize = 42;
+    const SECTION_HEADER_SIZE_FIELD: usize = 46;
+    const NAMES_INDEX_FIELD: usize = 50;
+    const SECTION_SIZE_FIELD: usize = 20;
+    const STRTAB_INDEX: usize = 3;
+
+    // ELF file with one PT_LOAD segment per (vaddr, paddr, contents), and a .text, .symtab, .strtab and .shstrtab section.
+    // The symbols are (name, value, size, kind, binding, section), after the null symbol every symbol table starts with
+    pub(crate) fn build_elf(segments: &[(u32, u32, &[u8])], symbols: &[(&str, u32, u32, u8, u8, u16)]) -> Vec<u8> {
+        let mut symtab = vec![0; SYMBOL_SIZE];
+        let mut strtab = vec![0];
+        for &(name, value, size, kind, binding, section) in symbols {
+            for word in [strtab.len() as u32, value, size] {
+                symtab.extend_from_slice(&u32::to_le_bytes(word));
+            }
+            symtab.extend_from_slice(&[binding << 4 | kind, 0]);
+            symtab.extend_from_slice(&u16::to_le_bytes(section));
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        let shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0";
+
+        let contents_offset = HEADER_SIZE + PROGRAM_HEADER_SIZE * segments.len();
+        let contents_size: usize = segments.iter().map(|(_, _, contents)| contents.len()).sum();
+        let symtab_offset = contents_offset + contents_size;
+        let strtab_offset = symtab_offset + symtab.len();
+        let shstrtab_offset = strtab_offset + strtab.len();
+        let section_headers = (shstrtab_offset + shstrtab.len() + 3) & !3;
+
+        let mut data = b"\x7FELF\x01\x01\x01\0\0\0\0\0\0\0\0\0".to_vec();
+        for half in [2, 36] {
+            data.extend_from_slice(&u16::to_le_bytes(half)); // Type (executable) and machine
+        }
+        for word in [1, 0x07000000, HEADER_SIZE as u32, section_headers as u32, 0] {
+            data.extend_from_slice(&u32::to_le_bytes(word)); // Version, entry, program headers, section headers, flags
+        }
+        for half in [HEADER_SIZE, PROGRAM_HEADER_SIZE, segments.len(), SECTION_HEADER_SIZE, 5, 4] {
+            data.extend_from_slice(&u16::to_le_bytes(half as u16)); // Header sizes and counts, and the section name table index
+        }
+
+        let mut offset = contents_offset;
+        for &(vaddr, paddr, contents) in segments {
+            let size = contents.len() as u32;
+            for word in [PT_LOAD, offset as u32, vaddr, paddr, size, size, 5, 2] {
+                data.extend_from_slice(&u32::to_le_bytes(word));
+            }
+            offset += contents.len();
+        }
+        for (_, _, contents) in segments {
+            data.extend_from_slice(contents);
+        }
+        data.extend_from_slice(&symtab);
+        data.extend_from_slice(&strtab);
+        data.extend_from_slice(shstrtab);
+        data.resize(section_headers, 0);
+
+        // Name, type, address, offset, size and link of each section
+        let sections = [
+            [0, 0, 0, 0, 0, 0],
+            [1, 1, 0x07000000, contents_offset as u32, 0, 0],
+            [7, SHT_SYMTAB, 0, symtab_offset as u32, symtab.len() as u32, STRTAB_INDEX as u32],
+            [15, SHT_STRTAB, 0, strtab_offset as u32, strtab.len() as u32, 0],
+            [23, SHT_STRTAB, 0, shstrtab_offset as u32, shstrtab.len() as u32, 0],
+        ];
+        for [name, kind, addr, offset, size, link] in sections {
+            for word in [name, kind, 0, addr, offset, size, link, 0, 4, 0] {
+                data.extend_from_slice(&u32::to_le_bytes(word));
+            }
+        }
+        data
+    }
+
+    fn write16(data: &mut [u8], offset: usize, val: u16) {
+        data[offset..offset + 2].copy_from_slice(&val.to_le_bytes());
+    }
+
+    fn write32(data: &mut [u8], offset: usize, val: u32) {
+        data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+    }
+
+    // Offset of a field in the header of section "index"
+    fn section_field(data: &[u8], index: usize, field: usize) -> usize {
+        read32(data, 32).unwrap() as usize + index * SECTION_HEADER_SIZE + field
+    }
+
+    fn error(data: &[u8]) -> String {
+        Elf::parse(data).err().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let code = [0x11; 6];
+        let file = build_elf(&[(0x07000000, 0x07000000, &code), (0x05000000, 0x07000006, &[0x22; 2])], &[("main", 0x07000000, 6, STT_FUNC, STB_GLOBAL, TEXT)]);
+        let elf = Elf::parse(&file).unwrap();
+
+        assert_eq!(elf.entry, 0x07000000);
+        assert_eq!(elf.segments.len(), 2);
+        assert_eq!((elf.segments[1].kind, elf.segments[1].vaddr, elf.segments[1].paddr), (PT_LOAD, 0x05000000, 0x07000006));
+        assert_eq!(elf.segment_data(&elf.segments[0]), Ok(&code[..]));
+
+        let names: Vec<&str> = elf.sections.iter().map(|section| section.name.as_str()).collect();
+        assert_eq!(names, ["", ".text", ".symtab", ".strtab", ".shstrtab"]);
+
+        let symbols = elf.symbols().unwrap();
+        assert_eq!(symbols.len(), 2); // With the null symbol
+        assert_eq!(symbols[1], Symbol { name: "main".to_string(), value: 0x07000000, size: 6, kind: STT_FUNC, binding: STB_GLOBAL, section: TEXT });
+
+        // Without a section name table, sections are left unnamed
+        let mut file = file;
+        write16(&mut file, NAMES_INDEX_FIELD, 0);
+        assert!(Elf::parse(&file).unwrap().sections.iter().all(|section| section.name.is_empty()));
+    }
+
+    #[test]
+    fn truncated_header() {
+        let file = build_elf(&[], &[]);
+
+        assert_eq!(error(&file[..HEADER_SIZE - 1]), "the ELF header is cut short");
+        assert_eq!(error(&file[..4]), "the ELF header is cut short");
+        assert_eq!(error(&file[..3]), "not an ELF file");
+        assert_eq!(error(b""), "not an ELF file");
+
+        // Section headers past the end of the file
+        assert_eq!(error(&file[..file.len() - SECTION_HEADER_SIZE]), "unexpected end of file");
+    }
+
+    #[test]
+    fn header_sizes() {
+        let segments: &[(u32, u32, &[u8])] = &[(0x07000000, 0x07000000, &[0; 4])];
+
+        let mut file = build_elf(segments, &[]);
+        write16(&mut file, PROGRAM_HEADER_SIZE_FIELD, PROGRAM_HEADER_SIZE as u16 - 1);
+        assert_eq!(error(&file), "invalid program header size");
+
+        let mut file = build_elf(segments, &[]);
+        write16(&mut file, SECTION_HEADER_SIZE_FIELD, SECTION_HEADER_SIZE as u16 - 1);
+        assert_eq!(error(&file), "invalid section header size");
+
+        // Sizes don't matter without any header of that kind
+        let mut file = build_elf(&[], &[]);
+        write16(&mut file, PROGRAM_HEADER_SIZE_FIELD, 0);
+        assert!(Elf::parse(&file).is_ok());
+    }
+
+    #[test]
+    fn segment_past_end_of_file() {
+        let mut file = build_elf(&[(0x07000000, 0x07000000, &[0; 4])], &[]);
+        let len = file.len() as u32;
+        write32(&mut file, HEADER_SIZE + 16, len); // File size, from an offset past the headers
+        let elf = Elf::parse(&file).unwrap();
+
+        assert_eq!(elf.segment_data(&elf.segments[0]), Err("segment at 07000000 goes past the end of the file".to_string()));
+
+        // Offsets so large that adding the size overflows
+        let mut file = build_elf(&[(0x07000000, 0x07000000, &[0; 4])], &[]);
+        write32(&mut file, HEADER_SIZE + 4, u32::MAX);
+        let elf = Elf::parse(&file).unwrap();
+        assert!(elf.segment_data(&elf.segments[0]).is_err());
+    }
+
+    #[test]
+    fn bad_string_offsets() {
+        let symbols = &[("main", 0x07000000, 0, STT_FUNC, STB_GLOBAL, TEXT)];
+
+        // Name of a section out of bounds of the section name table
+        let mut file = build_elf(&[], symbols);
+        let name = section_field(&file, 1, 0);
+        write32(&mut file, name, 0x1000);
+        assert_eq!(error(&file), "string out of bounds of its string table");
+
+        // Name of a symbol out of bounds of the string table
+        let mut file = build_elf(&[], symbols);
+        let symtab = Elf::parse(&file).unwrap().sections[2].offset as usize;
+        write32(&mut file, symtab + SYMBOL_SIZE, 0x1000);
+        assert_eq!(Elf::parse(&file).unwrap().symbols(), Err("string out of bounds of its string table".to_string()));
+
+        // String table cut short before the terminator of the last name
+        let mut file = build_elf(&[], symbols);
+        let size = section_field(&file, STRTAB_INDEX, SECTION_SIZE_FIELD);
+        write32(&mut file, size, 5); // The leading null and "main"
+        assert_eq!(Elf::parse(&file).unwrap().symbols(), Err("unterminated string".to_string()));
+    }
+}
@@ -0,0 +1,517 @@
+use crate::bus::{self, WatchKind, Watchpoint};
+use crate::cpu::instrs::system_regs;
+use crate::vb::{Stop, VirtualBoy};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/*
+    GDB remote serial protocol stub.
+
+    Serves GDB connections over TCP, giving them access to the CPU registers and memory, and control over execution
+    through the debugger API in vb.rs. Supported:
+    - Registers: r0-r31 (0-31), the system registers by ID (32-63) and PC (64), described to GDB by a target description XML
+    - Memory reads and writes (m/M). Reads stop at the first region that isn't emulated, and writes to one of them or to ROM give errors
+    - Software and hardware breakpoints (Z0/Z1), which both become emulator breakpoints, so memory never gets patched
+    - Write, read and access watchpoints (Z2/Z3/Z4)
+    - Continue and single-step (c/s), and interrupting a running target with Ctrl-C
+    - No-ack mode, detaching and killing
+
+    In strict mode, illegal opcodes stop the target with SIGILL.
+*/
+
+const RUN_CHUNK: u64 = 100_000; // Cycles run between checks for an interrupt from GDB
+const REG_COUNT: usize = 65;
+const PC_REG: usize = 64;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+// How a session ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum End {
+    Detached, // GDB detached or disconnected. The emulator is left as it is
+    Killed,
+}
+
+// Wait for GDB to connect to "listener", then serve it until the session ends
+pub fn serve(vb: &mut VirtualBoy, listener: &TcpListener) -> io::Result<End> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+
+    let mut stub = Stub {
+        vb,
+        conn: Connection { stream, input: VecDeque::new(), no_ack: false },
+        swbreak: false,
+    };
+
+    match stub.run() {
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(End::Detached),
+        result => result,
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    input: VecDeque<u8>, // Bytes received while checking for interrupts, that haven't been handled yet
+    no_ack: bool,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        if let Some(byte) = self.input.pop_front() {
+            return Ok(byte);
+        }
+
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            _ => Ok(byte[0]),
+        }
+    }
+
+    // Wait for the next packet and return its contents, acknowledging it unless in no-ack mode
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            if self.read_byte()? != b'$' {
+                continue; // Acks, and interrupts while already stopped
+            }
+
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let sum = [self.read_byte()?, self.read_byte()?];
+
+            if !self.no_ack {
+                let valid = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok()) == Some(checksum(&data));
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+                if !valid {
+                    continue;
+                }
+            }
+
+            return Ok(String::from_utf8_lossy(&data).into_owned());
+        }
+    }
+
+    // Send a packet, resending it until GDB acknowledges it unless in no-ack mode
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Whether GDB sent an interrupt (Ctrl-C) while the target was running
+    fn interrupted(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 64];
+        self.stream.set_nonblocking(true)?;
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(len) if buf[..len].contains(&0x03) => Ok(true),
+            Ok(len) => {
+                self.input.extend(&buf[..len]);
+                Ok(false)
+            }
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+struct Stub<'a> {
+    vb: &'a mut VirtualBoy,
+    conn: Connection,
+    swbreak: bool, // Whether GDB understands swbreak stop reasons
+}
+
+impl Stub<'_> {
+    fn run(&mut self) -> io::Result<End> {
+        loop {
+            let packet = self.conn.read_packet()?;
+
+            let reply = match packet.chars().next() {
+                Some('D') => {
+                    self.conn.write_packet("OK")?;
+                    return Ok(End::Detached);
+                }
+                Some('k') => return Ok(End::Killed),
+                Some('c') => self.resume(&packet[1..], false)?,
+                Some('s') => self.resume(&packet[1..], true)?,
+                _ => self.command(&packet).unwrap_or_else(|| "E01".to_string()),
+            };
+
+            self.conn.write_packet(&reply)?;
+            if packet == "QStartNoAckMode" {
+                self.conn.no_ack = true;
+            }
+        }
+    }
+
+    // Handle every packet that doesn't resume execution or end the session. Returns None for malformed packets
+    fn command(&mut self, packet: &str) -> Option<String> {
+        let kind = packet.get(..1)?;
+        let args = &packet[1..];
+
+        let reply = match kind {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..REG_COUNT).map(|index| hex_u32(self.read_reg(index).unwrap_or(0))).collect(),
+            "G" => {
+                for index in 0..REG_COUNT {
+                    let val = args.get(index * 8..index * 8 + 8).and_then(parse_u32)?;
+                    self.write_reg(index, val);
+                }
+                "OK".to_string()
+            }
+            "p" => hex_u32(self.read_reg(parse_hex(args)? as usize)?),
+            "P" => {
+                let (index, val) = args.split_once('=')?;
+                let index = parse_hex(index)? as usize;
+                if index >= REG_COUNT {
+                    return None;
+                }
+                self.write_reg(index, parse_u32(val)?);
+                "OK".to_string()
+            }
+            "m" => {
+                let (addr, len) = args.split_once(',')?;
+                self.read_memory(parse_hex(addr)?, parse_hex(len)?.min(0x1000))
+            }
+            "M" => {
+                let (range, data) = args.split_once(':')?;
+                let (addr, len) = range.split_once(',')?;
+                let bytes = parse_bytes(data)?;
+                if bytes.len() != parse_hex(len)? as usize {
+                    return None;
+                }
+                self.write_memory(parse_hex(addr)?, &bytes)
+            }
+            "Z" | "z" => self.breakpoint(kind == "Z", args)?,
+            "H" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(), // Unsupported
+        };
+
+        Some(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            self.swbreak = packet.contains("swbreak+");
+            return "PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+".to_string();
+        }
+
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let range = args.split_once(',').and_then(|(offset, len)| Some((parse_hex(offset)? as usize, parse_hex(len)? as usize)));
+            let (offset, len) = match range {
+                Some(range) => range,
+                None => return "E01".to_string(),
+            };
+
+            let xml = target_xml();
+            let chunk = xml.get(offset.min(xml.len())..(offset + len).min(xml.len())).unwrap_or("");
+            let more = offset + len < xml.len();
+            return format!("{}{}", if more { 'm' } else { 'l' }, escape(chunk));
+        }
+
+        match packet {
+            "qAttached" => "1".to_string(),
+            "QStartNoAckMode" => "OK".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // Z/z type,addr,kind
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = parse_hex(fields.next()?)?;
+        let len = parse_hex(fields.next()?)?;
+
+        let watch_kind = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.vb.add_breakpoint(addr);
+                } else {
+                    self.vb.remove_breakpoint(addr);
+                }
+                return Some("OK".to_string());
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Some(String::new()),
+        };
+
+        let watchpoint = Watchpoint { start: addr, end: addr.wrapping_add(len.max(1) - 1), kind: watch_kind, value: None };
+        if insert {
+            self.vb.add_watchpoint(watchpoint);
+        } else {
+            self.vb.remove_watchpoint(&watchpoint);
+        }
+        Some("OK".to_string())
+    }
+
+    // c/s [addr]. Runs until something stops the CPU, and returns the stop reply
+    fn resume(&mut self, args: &str, step: bool) -> io::Result<String> {
+        if !args.is_empty() {
+            match parse_hex(args) {
+                Some(addr) => self.vb.cpu_mut().regs.pc = addr & !1,
+                None => return Ok("E01".to_string()),
+            }
+        }
+
+        loop {
+            let (_, stop) = if step { self.vb.step_into() } else { self.vb.debug_run(RUN_CHUNK) };
+
+            return Ok(match stop {
+                Stop::OutOfCycles if self.conn.interrupted()? => format!("S{:02x}", SIGINT),
+                Stop::OutOfCycles => continue,
+                Stop::Done => format!("S{:02x}", SIGTRAP),
+                Stop::IllegalOpcode(_) => format!("S{:02x}", SIGILL),
+                Stop::Breakpoint(_) if self.swbreak => format!("T{:02x}swbreak:;", SIGTRAP),
+                Stop::Breakpoint(_) => format!("S{:02x}", SIGTRAP),
+                Stop::Watchpoint(hit) => {
+                    let reason = match hit.watchpoint.kind {
+                        WatchKind::Write => "watch",
+                        WatchKind::Read => "rwatch",
+                        WatchKind::Access => "awatch",
+                    };
+                    format!("T{:02x}{}:{:x};", SIGTRAP, reason, hit.watchpoint.start)
+                }
+            });
+        }
+    }
+
+    fn read_reg(&self, index: usize) -> Option<u32> {
+        let cpu = self.vb.cpu();
+        match index {
+            0..=31 => Some(cpu.regs.gprs[index]),
+            32..=63 => Some(cpu.system_reg((index - 32) as u16)),
+            PC_REG => Some(cpu.regs.pc),
+            _ => None,
+        }
+    }
+
+    // Registers are written as they are, without the side effects or masking of LDSR. r0 and read-only system registers can't be written
+    fn write_reg(&mut self, index: usize, val: u32) {
+        let cpu = self.vb.cpu_mut();
+        match index {
+            1..=31 => cpu.regs.gprs[index] = val,
+            32..=63 => cpu.set_system_reg((index - 32) as u16, val),
+            PC_REG => cpu.regs.pc = val & !1,
+            _ => {}
+        }
+    }
+
+    // Reads stop at the first address in a region the bus doesn't implement
+    fn read_memory(&self, addr: u32, len: u32) -> String {
+        let bus = self.vb.bus();
+        let data: String = (0..len)
+            .map(|offset| addr.wrapping_add(offset))
+            .take_while(|&addr| bus::is_readable(addr))
+            .map(|addr| format!("{:02x}", bus.peek8(addr)))
+            .collect();
+
+        if data.is_empty() && len != 0 { "E14".to_string() } else { data }
+    }
+
+    fn write_memory(&mut self, addr: u32, bytes: &[u8]) -> String {
+        let writable = (0..bytes.len() as u32).all(|offset| bus::is_writable(addr.wrapping_add(offset)));
+        if !writable {
+            return "E14".to_string();
+        }
+
+        let bus = self.vb.bus_mut();
+        for (offset, &byte) in bytes.iter().enumerate() {
+            bus.write8(addr.wrapping_add(offset as u32), byte);
+        }
+        "OK".to_string()
+    }
+}
+
+fn target_xml() -> String {
+    let mut regs = String::new();
+    for index in 0..32 {
+        let kind = match index {
+            3 => "data_ptr",
+            31 => "code_ptr",
+            _ => "int",
+        };
+        regs += &format!("    <reg name=\"r{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>\n", index, kind, index);
+    }
+    for id in 0..32 {
+        let name = system_regs::name(id).map_or_else(|| format!("sr{}", id), str::to_string);
+        regs += &format!("    <reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\" group=\"system\"/>\n", name, 32 + id);
+    }
+    regs += &format!("    <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>\n", PC_REG);
+
+    format!(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <architecture>v810</architecture>\n  <feature name=\"org.gnu.gdb.v810.core\">\n{}  </feature>\n</target>\n",
+        regs
+    )
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+// Escape the characters that can't appear as they are in binary packet data
+fn escape(data: &str) -> String {
+    let mut escaped = String::with_capacity(data.len());
+    for c in data.chars() {
+        if matches!(c, '#' | '$' | '}' | '*') {
+            escaped.push('}');
+            escaped.push((c as u8 ^ 0x20) as char);
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+// Register values are sent as little endian bytes
+fn hex_u32(val: u32) -> String {
+    val.to_le_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_u32(s: &str) -> Option<u32> {
+    let bytes = parse_bytes(s)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn parse_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vb;
+    use std::thread;
+    use std::time::Duration;
+
+    const PROGRAM: &str = "
+        .org 0x07000000
+        main:
+            ldsr r0, psw
+            mov 0x05000000, r20
+            mov 5, r10
+        loop: ; 0700000C
+            add 1, r11
+            st.w r11, 0[r20]
+            br loop
+    ";
+
+    // Just enough of a GDB client to send packets and read the replies
+    struct Client(TcpStream);
+
+    impl Client {
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.0.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send(&mut self, data: &str) -> String {
+            write!(self.0, "${}#{:02x}", data, checksum(data.as_bytes())).unwrap();
+            while self.read_byte() != b'$' {} // Ack
+
+            let mut reply = vec![];
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => reply.push(byte),
+                }
+            }
+            let sum = [self.read_byte(), self.read_byte()];
+            assert_eq!(std::str::from_utf8(&sum).unwrap(), format!("{:02x}", checksum(&reply)));
+
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn session() {
+        let mut vb = vb::tests::assemble(PROGRAM);
+        let code: String = (0..4).map(|offset| format!("{:02x}", vb.bus().peek8(0x07000000 + offset))).collect();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let script = [
+            ("qSupported:multiprocess+;swbreak+", "PacketSize=4000;qXfer:features:read+;swbreak+;QStartNoAckMode+".to_string()),
+            ("m7000000,4", code),
+            ("m3000000,4", "E14".to_string()), // Unmapped
+            ("M5000100,2:abcd", "OK".to_string()),
+            ("m5000100,2", "abcd".to_string()),
+            ("M1000000,1:5a", "OK".to_string()), // VSU
+            ("m1000000,1", "5a".to_string()),
+            ("M7000000,1:00", "E14".to_string()), // ROM
+            ("Z0,700000c,2", "OK".to_string()),
+            ("c", "T05swbreak:;".to_string()),
+            ("p40", "0c000007".to_string()),
+            ("p14", "00000005".to_string()), // r20
+            ("s", "S05".to_string()),
+            ("p40", "0e000007".to_string()),
+            ("Z2,5000000,4", "OK".to_string()),
+            ("c", "T05watch:5000000;".to_string()),
+            ("p40", "12000007".to_string()),
+            ("m5000000,4", "01000000".to_string()),
+            ("z2,5000000,4", "OK".to_string()),
+            ("c", "T05swbreak:;".to_string()),
+            ("p0b", "01000000".to_string()), // r11
+        ];
+
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            let mut client = Client(stream);
+
+            // Registers right after reset: PSW (sr5) and ECR (sr4) have their reset values, and PC is at the reset vector
+            let regs = client.send("g");
+            assert_eq!(regs.len(), REG_COUNT * 8);
+            assert_eq!(&regs[36 * 8..38 * 8], "f0ff000000800000");
+            assert_eq!(&regs[PC_REG * 8..], "f0ffffff");
+
+            for (packet, expected) in &script {
+                assert_eq!(&client.send(packet), expected, "reply to {}", packet);
+            }
+            client.0.write_all(b"$k#6b").unwrap();
+        });
+
+        let end = serve(&mut vb, &listener);
+        client.join().unwrap();
+        assert_eq!(end.unwrap(), End::Killed);
+        assert_eq!(vb.cpu().regs.gprs[11], 1);
+    }
+}
@@ -0,0 +1,14 @@
+#![warn(clippy::all)]
+#![allow(clippy::verbose_bit_mask, clippy::new_without_default)]
+
+#[macro_use]
+extern crate bitfield;
+
+pub mod bus;
+pub mod cpu;
+pub mod elf;
+pub mod gdb;
+pub mod mem;
+pub mod symbols;
+mod vb;
+pub use vb::{Stop, VirtualBoy};
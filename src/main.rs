@@ -1,18 +1,45 @@
 #![warn(clippy::all)]
-#![allow(clippy::verbose_bit_mask, clippy::new_without_default)]
 
-#[macro_use]
-extern crate bitfield;
+use hewwo::VirtualBoy;
 
-// Todo: Remove this from here
-pub mod bus;
-pub mod cpu;
-pub mod mem;
-mod vb;
-pub use vb::VirtualBoy;
+/*
+    Runs a ROM forever.
+
+    Usage: hewwo [ROM] [--cached]
+
+    The ROM defaults to the screen demo. --cached runs it in the cached interpreter (see src/cpu/block_cache.rs),
+    which gives the same results faster.
+*/
+
+const DEFAULT_ROM: &str = "ROMs/ScreenDemo1.vb";
+
+fn parse_args() -> Result<(String, bool), String> {
+    let mut rom = None;
+    let mut cached = false;
+
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--cached" => cached = true,
+            _ if rom.is_none() => rom = Some(arg),
+            _ => return Err(format!("unexpected argument \"{}\"", arg)),
+        }
+    }
+
+    Ok((rom.unwrap_or_else(|| DEFAULT_ROM.to_string()), cached))
+}
 
 fn main() {
-    let mut vb = VirtualBoy::new("ROMs/ScreenDemo1.vb");
+    let (rom, cached) = match parse_args() {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("hewwo: {}", error);
+            eprintln!("Usage: hewwo [ROM] [--cached]");
+            std::process::exit(1);
+        }
+    };
+
+    let mut vb = VirtualBoy::new(&rom);
+    vb.set_cached_interpreter(cached);
 
     loop {
         vb.step();
@@ -1,3 +1,21 @@
+use crate::elf::{self, Elf};
+use crate::symbols::Symbols;
+
+/*
+    ROMs can be loaded from raw images (.vb files) or straight from the ELF files the homebrew toolchains build them from.
+
+    ELF segments are placed at their load (physical) address, which for initialized data is its copy in ROM rather than
+    where it ends up in WRAM. Segments can go in ROM or WRAM, and loading fails if any would have to go anywhere else.
+    As ROM is mirrored all over its region, the ROM is made as small as it can be while still fitting every segment at its address
+    in one of the mirrors, at a power of two size. That way, vectors linked at the end of the region (0x07FFFDE0 and up)
+    end up at the end of the ROM, where the CPU finds them. Space between segments is padded with 0xFF.
+*/
+
+const ROM_REGION_SIZE: usize = 0x1000000;
+pub const MIN_ROM_SIZE: usize = 4; // So that a 32-bit read never needs more than the ROM's mirroring
+const WRAM_SIZE: usize = 0x10000;
+
+#[derive(Clone)]
 pub struct Memory {
     // main. non-IO memory
     pub rom: Vec<u8>,
@@ -6,21 +24,173 @@ pub struct Memory {
     pub vsu_memory_stub: Vec<u8>,
     pub misc_hw_memory_stub: Vec<u8>,
     pub rom_mask: usize, // Mask to handle ROM read mirroring
+    pub symbols: Symbols, // Symbols of the ELF file the ROM was loaded from. Empty for raw ROM images
 }
 
 impl Memory {
     pub fn new(rom_path: &str) -> Memory {
-        let rom = std::fs::read(&rom_path).expect("couldn't find the specified ROM file");
-        assert!(rom.len().is_power_of_two(), "the specified ROM's size is not a power of two");
+        Memory::load(rom_path).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    // Same as new, but returns an error instead of panicking when the ROM can't be used. Takes a raw ROM image or an ELF file
+    pub fn load(rom_path: &str) -> Result<Memory, String> {
+        let data = std::fs::read(rom_path).map_err(|_| "couldn't find the specified ROM file".to_string())?;
+        if elf::is_elf(&data) {
+            Memory::from_elf(&data)
+        } else {
+            Memory::from_rom(data)
+        }
+    }
+
+    // Use a raw ROM image, like the contents of a .vb file
+    pub fn from_rom(rom: Vec<u8>) -> Result<Memory, String> {
+        if !rom.len().is_power_of_two() {
+            return Err("the specified ROM's size is not a power of two".to_string());
+        }
+        if rom.len() < MIN_ROM_SIZE {
+            return Err(format!("the specified ROM is smaller than {} bytes", MIN_ROM_SIZE));
+        }
         let rom_mask = rom.len() - 1;
 
-        Memory { 
+        Ok(Memory { 
             rom,
             ram: vec![0; 0x10000], 
             vip_memory_stub: vec![0;  0x80000],
             vsu_memory_stub: vec![0; 0x800],
             misc_hw_memory_stub: vec![0; 0x40],
-            rom_mask 
+            rom_mask,
+            symbols: Symbols::new(),
+        })
+    }
+
+    // Load the segments of an ELF file at their load addresses, into ROM or WRAM, like objcopy would before padding the ROM
+    pub fn from_elf(data: &[u8]) -> Result<Memory, String> {
+        let elf = Elf::parse(data)?;
+        let mut rom_segments = vec![]; // Offset in the ROM region and contents
+        let mut wram_segments = vec![]; // Offset in WRAM and contents
+        let mut unmappable = vec![];
+
+        // Segments with nothing in the file, like .bss, are left to be cleared by the game
+        for segment in elf.segments.iter().filter(|segment| segment.kind == elf::PT_LOAD && segment.file_size != 0) {
+            let contents = elf.segment_data(segment)?;
+            let offset = segment.paddr as usize & 0xFFFFFF;
+
+            match segment.paddr >> 24 & 7 {
+                7 if offset + contents.len() <= ROM_REGION_SIZE => rom_segments.push((offset, contents)),
+                5 if (offset & 0xFFFF) + contents.len() <= WRAM_SIZE => wram_segments.push((offset & 0xFFFF, contents)),
+                _ => unmappable.push(format!("{:08X}-{:08X}", segment.paddr, segment.paddr.wrapping_add(segment.file_size - 1))),
+            }
+        }
+
+        if !unmappable.is_empty() {
+            return Err(format!("the ELF file has segments outside of ROM and WRAM: {}", unmappable.join(", ")));
+        }
+        if rom_segments.is_empty() {
+            return Err("the ELF file has nothing to load into ROM".to_string());
         }
+
+        let rom_size = rom_size(&rom_segments).ok_or("the ELF file has overlapping segments in ROM")?;
+        let mut rom = vec![0xFF; rom_size];
+        for (offset, contents) in rom_segments {
+            let start = offset & (rom_size - 1);
+            rom[start..start + contents.len()].copy_from_slice(contents);
+        }
+
+        let mut memory = Memory::from_rom(rom)?;
+        for (offset, contents) in wram_segments {
+            memory.ram[offset..offset + contents.len()].copy_from_slice(contents);
+        }
+        memory.symbols = Symbols::from_elf(data)?;
+
+        Ok(memory)
+    }
+}
+
+// Smallest power of two ROM size that fits every segment (offset in the ROM region and contents) into its mirror of the ROM
+// without any two overlapping, or None if they overlap even without mirroring
+fn rom_size(segments: &[(usize, &[u8])]) -> Option<usize> {
+    let largest = segments.iter().map(|(_, contents)| contents.len()).max().unwrap_or(1);
+    let mut size = largest.next_power_of_two().max(MIN_ROM_SIZE);
+
+    while size <= ROM_REGION_SIZE {
+        let mut ranges: Vec<(usize, usize)> = segments.iter().map(|&(offset, contents)| (offset & (size - 1), contents.len())).collect();
+        ranges.sort_unstable();
+
+        let fits = ranges.iter().all(|&(start, len)| start + len <= size);
+        let overlaps = ranges.windows(2).any(|pair| pair[0].0 + pair[0].1 > pair[1].0);
+        if fits && !overlaps {
+            return Some(size);
+        }
+        size *= 2;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::tests::{build_elf, TEXT};
+    use crate::elf::{STB_GLOBAL, STT_FUNC};
+
+    #[test]
+    fn rom_size() {
+        let code = [0; 0x70];
+        let vectors = [0; 0x10];
+        let big = [0; 0x100];
+
+        assert_eq!(super::rom_size(&[(0, &[0; 3])]), Some(4)); // Padded to a power of two
+        assert_eq!(super::rom_size(&[(0, &[0; 1])]), Some(4)); // And to the smallest ROM size
+        assert_eq!(super::rom_size(&[(0, &code), (0xFFFFF0, &vectors)]), Some(0x80)); // Vectors in the last bytes of the smallest mirror
+        assert_eq!(super::rom_size(&[(0, &big), (0xFFFFF0, &vectors)]), Some(0x200)); // Mirrors grow until the vectors fit
+        assert_eq!(super::rom_size(&[(0x100, &vectors), (0, &vectors)]), Some(0x200));
+        assert_eq!(super::rom_size(&[(0, &code), (0x60, &vectors)]), None); // Overlapping
+        assert_eq!(super::rom_size(&[(0, &big), (0x1000000 - 0x80, &big)]), None); // Overlapping in every mirror
+    }
+
+    #[test]
+    fn from_elf() {
+        let code = [0x11; 0x30];
+        let data = [0x22; 4];
+        let vectors = [0x33; 0x10];
+        let file = build_elf(
+            &[
+                (0x07000000, 0x07000000, &code),
+                (0x05000010, 0x07000040, &data), // Initialized data, copied from ROM to WRAM at boot
+                (0x05000020, 0x05000020, &data),
+                (0xFFFFFFF0, 0x07FFFFF0, &vectors),
+            ],
+            &[("main", 0x07000000, 0x30, STT_FUNC, STB_GLOBAL, TEXT)],
+        );
+
+        let memory = Memory::from_elf(&file).unwrap();
+        assert_eq!(memory.rom.len(), 0x80);
+        assert_eq!(&memory.rom[..0x30], &code);
+        assert_eq!(&memory.rom[0x30..0x40], &[0xFF; 0x10]); // Padding
+        assert_eq!(&memory.rom[0x40..0x44], &data);
+        assert_eq!(&memory.rom[0x70..], &vectors);
+        assert_eq!(&memory.ram[0x10..0x14], &[0; 4]);
+        assert_eq!(&memory.ram[0x20..0x24], &data);
+        assert_eq!(memory.symbols.name_at(0x07000000), Some("main"));
+    }
+
+    #[test]
+    fn from_elf_errors() {
+        let error = |segments: &[(u32, u32, &[u8])]| Memory::from_elf(&build_elf(segments, &[])).err().unwrap();
+        let word: &[u8] = &[0; 4];
+
+        assert_eq!(
+            error(&[(0x07000000, 0x07000000, word), (0x06000000, 0x06000000, word), (0x07FFFFFE, 0x07FFFFFE, word)]),
+            "the ELF file has segments outside of ROM and WRAM: 06000000-06000003, 07FFFFFE-08000001"
+        );
+        assert_eq!(
+            error(&[(0x07000000, 0x07000000, word), (0x0500FFFE, 0x0500FFFE, word)]),
+            "the ELF file has segments outside of ROM and WRAM: 0500FFFE-05010001"
+        );
+        assert_eq!(error(&[(0x05000000, 0x05000000, word)]), "the ELF file has nothing to load into ROM");
+        assert_eq!(
+            error(&[(0x07000000, 0x07000000, word), (0x07000002, 0x07000002, word)]),
+            "the ELF file has overlapping segments in ROM"
+        );
     }
 }
@@ -0,0 +1,288 @@
+use crate::elf::{self, Elf};
+use std::collections::{BTreeMap, HashMap};
+
+/*
+    Symbol maps, to show addresses as "function+0x12" instead of raw numbers and to refer to code by name.
+
+    They can be loaded from an ELF file built by gccvb or VUEngine, or from a text file with one "address name" pair per line,
+    addresses in hex. The output of nm ("address type name") works too. Blank lines and lines starting with # or ; are skipped.
+
+    From ELF files, functions, objects and untyped labels defined in a section are kept. When several names share an address,
+    functions win over objects, which win over labels, and global names win over local ones. In text files, the first name wins.
+    Every name can be looked up either way.
+
+    Addresses are matched on the 27 bits the bus decodes, so a symbol also covers its mirrors above 0x07FFFFFF
+    (like the interrupt handlers at 0xFFFFFE00, which are the end of ROM). An address belongs to the closest symbol at or below it
+    in the same memory region, as long as it's within the symbol's size when that's known.
+*/
+
+const ADDR_MASK: u32 = 0x07FFFFFF;
+
+#[derive(Clone, Debug)]
+struct Symbol {
+    name: String,
+    size: u32, // 0 if unknown
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    by_addr: BTreeMap<u32, Symbol>, // By address, with the upper 5 bits masked off
+    by_name: HashMap<String, u32>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    // Load an ELF or text symbol file, telling them apart by their contents
+    pub fn load(path: &str) -> Result<Symbols, String> {
+        let data = std::fs::read(path).map_err(|error| format!("couldn't read {}: {}", path, error))?;
+        let symbols = if elf::is_elf(&data) {
+            Symbols::from_elf(&data)
+        } else {
+            let text = String::from_utf8(data).map_err(|_| "neither an ELF file nor a text file".to_string())?;
+            Symbols::parse_text(&text)
+        };
+        symbols.map_err(|error| format!("{}: {}", path, error))
+    }
+
+    pub fn from_elf(data: &[u8]) -> Result<Symbols, String> {
+        let mut candidates: Vec<elf::Symbol> = Elf::parse(data)?
+            .symbols()?
+            .into_iter()
+            .filter(|symbol| !symbol.name.is_empty())
+            .filter(|symbol| symbol.section != elf::SHN_UNDEF && symbol.section < elf::SHN_LORESERVE)
+            .filter(|symbol| matches!(symbol.kind, elf::STT_FUNC | elf::STT_OBJECT | elf::STT_NOTYPE))
+            .collect();
+
+        // Earlier names win, so the preferred ones go first. The sort is stable, so ties keep the order of the file
+        let kind_rank = |kind| match kind {
+            elf::STT_FUNC => 0,
+            elf::STT_OBJECT => 1,
+            _ => 2,
+        };
+        let binding_rank = |binding| if binding == elf::STB_LOCAL { 1 } else { 0 };
+        candidates.sort_by_key(|symbol| (kind_rank(symbol.kind), binding_rank(symbol.binding)));
+
+        let mut symbols = Symbols::new();
+        for symbol in candidates {
+            symbols.insert(symbol.value, &symbol.name, symbol.size);
+        }
+        Ok(symbols)
+    }
+
+    pub fn parse_text(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (addr, name) = match fields[..] {
+                [addr, name] => (addr, name),
+                [addr, kind, name] if kind.len() == 1 => (addr, name), // nm
+                _ => return Err(format!("line {}: expected \"address name\"", index + 1)),
+            };
+
+            let addr = parse_hex(addr).ok_or_else(|| format!("line {}: invalid address \"{}\"", index + 1, addr))?;
+            symbols.insert(addr, name, 0);
+        }
+
+        Ok(symbols)
+    }
+
+    // Add a symbol, unless its address or name is already taken. "size" is 0 if unknown
+    pub fn insert(&mut self, addr: u32, name: &str, size: u32) {
+        self.by_addr.entry(addr & ADDR_MASK).or_insert_with(|| Symbol { name: name.to_string(), size });
+        self.by_name.entry(name.to_string()).or_insert(addr);
+    }
+
+    // Number of names
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    // Address of a symbol, as it was given
+    pub fn address(&self, name: &str) -> Option<u32> {
+        self.by_name.get(name).copied()
+    }
+
+    // Name of the symbol starting exactly at "addr"
+    pub fn name_at(&self, addr: u32) -> Option<&str> {
+        self.by_addr.get(&(addr & ADDR_MASK)).map(|symbol| symbol.name.as_str())
+    }
+
+    // Symbol "addr" belongs to, and how far into it it is
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let addr = addr & ADDR_MASK;
+        let (&start, symbol) = self.by_addr.range(..=addr).next_back()?;
+        let offset = addr - start;
+
+        let same_region = start >> 24 == addr >> 24;
+        let within = symbol.size == 0 || offset < symbol.size;
+        if same_region && within { Some((&symbol.name, offset)) } else { None }
+    }
+
+    // "name" or "name+0x12", if "addr" belongs to a symbol
+    pub fn describe(&self, addr: u32) -> Option<String> {
+        self.lookup(addr).map(|(name, offset)| if offset == 0 { name.to_string() } else { format!("{}+{:#X}", name, offset) })
+    }
+
+    // Address of "name", "name+offset" or a plain hex address. Names take precedence over addresses that look the same,
+    // and offsets are in hex
+    pub fn resolve(&self, location: &str) -> Result<u32, String> {
+        let location = location.trim();
+        if let Some(addr) = self.address(location) {
+            return Ok(addr);
+        }
+
+        if let Some((name, offset)) = location.rsplit_once('+') {
+            if let (Some(addr), Some(offset)) = (self.address(name.trim()), parse_hex(offset.trim())) {
+                return Ok(addr.wrapping_add(offset));
+            }
+        }
+
+        parse_hex(location).ok_or_else(|| format!("unknown symbol \"{}\"", location))
+    }
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+    u32::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::tests::{build_elf, TEXT};
+    use crate::elf::{SHN_UNDEF, STB_GLOBAL, STB_LOCAL, STB_WEAK, STT_FILE, STT_FUNC, STT_NOTYPE, STT_OBJECT, STT_SECTION};
+
+    const SHN_ABS: u16 = 0xFFF1;
+    const SHN_COMMON: u16 = 0xFFF2;
+
+    #[test]
+    fn from_elf() {
+        let file = build_elf(&[], &[
+            ("crt0.s", 0, 0, STT_FILE, STB_LOCAL, SHN_ABS),
+            (".text", 0x07000000, 0, STT_SECTION, STB_LOCAL, TEXT),
+            // Functions win over labels, and global names over local ones, wherever they are in the file
+            ("_start", 0x07000000, 0, STT_NOTYPE, STB_GLOBAL, TEXT),
+            ("start_local", 0x07000000, 0, STT_NOTYPE, STB_LOCAL, TEXT),
+            ("main_local", 0x07000000, 0x20, STT_FUNC, STB_LOCAL, TEXT),
+            ("main", 0x07000000, 0x20, STT_FUNC, STB_GLOBAL, TEXT),
+            // Objects win over labels
+            ("buffer_label", 0x05000000, 0, STT_NOTYPE, STB_GLOBAL, TEXT),
+            ("buffer_local", 0x05000000, 0x10, STT_OBJECT, STB_LOCAL, TEXT),
+            ("buffer", 0x05000000, 0x10, STT_OBJECT, STB_GLOBAL, TEXT),
+            // Weak names count as global
+            ("loop", 0x07000040, 0, STT_NOTYPE, STB_LOCAL, TEXT),
+            ("helper", 0x07000040, 8, STT_FUNC, STB_WEAK, TEXT),
+            // On a tie, the first name wins
+            ("first", 0x07000080, 4, STT_FUNC, STB_GLOBAL, TEXT),
+            ("second", 0x07000080, 4, STT_FUNC, STB_GLOBAL, TEXT),
+            // Dropped: undefined, absolute and common symbols
+            ("puts", 0, 0, STT_FUNC, STB_GLOBAL, SHN_UNDEF),
+            ("__stack", 0x0500FFFC, 0, STT_NOTYPE, STB_GLOBAL, SHN_ABS),
+            ("common_var", 4, 4, STT_OBJECT, STB_GLOBAL, SHN_COMMON),
+        ]);
+        let symbols = Symbols::from_elf(&file).unwrap();
+
+        assert_eq!(symbols.len(), 11);
+        assert_eq!(symbols.name_at(0x07000000), Some("main"));
+        assert_eq!(symbols.name_at(0x05000000), Some("buffer"));
+        assert_eq!(symbols.name_at(0x07000040), Some("helper"));
+        assert_eq!(symbols.name_at(0x07000080), Some("first"));
+        assert_eq!(symbols.name_at(0), None);
+
+        // The names that lost still lead to their address
+        for name in ["_start", "start_local", "main_local"] {
+            assert_eq!(symbols.address(name), Some(0x07000000), "{}", name);
+        }
+        assert_eq!(symbols.address("buffer_label"), Some(0x05000000));
+        assert_eq!(symbols.address("second"), Some(0x07000080));
+        for name in ["crt0.s", ".text", "puts", "__stack", "common_var"] {
+            assert_eq!(symbols.address(name), None, "{}", name);
+        }
+
+        // Sizes come from the winning symbol
+        assert_eq!(symbols.lookup(0x0700001E), Some(("main", 0x1E)));
+        assert_eq!(symbols.lookup(0x07000020), None);
+        assert_eq!(symbols.lookup(0x07000044), Some(("helper", 4)));
+        assert_eq!(symbols.lookup(0x07000048), None);
+        assert_eq!(symbols.lookup(0x0500000F), Some(("buffer", 0xF)));
+        assert_eq!(symbols.lookup(0x0500FFFC), None);
+
+        assert_eq!(symbols.resolve("start_local+0x10"), Ok(0x07000010));
+        assert_eq!(symbols.resolve("helper+4"), Ok(0x07000044));
+        assert_eq!(symbols.resolve("puts"), Err("unknown symbol \"puts\"".to_string()));
+        assert_eq!(symbols.resolve("__stack"), Err("unknown symbol \"__stack\"".to_string()));
+    }
+
+
+    #[test]
+    fn parse_text() {
+        let symbols = Symbols::parse_text(
+            "
+            # comment
+            ; comment too
+            07000000 main
+            0x07000020 T fact
+            05000100 table
+            07000000 alias
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.address("fact"), Some(0x07000020));
+        assert_eq!(symbols.address("alias"), Some(0x07000000));
+        assert_eq!(symbols.name_at(0x07000000), Some("main")); // The first name wins
+
+        assert_eq!(Symbols::parse_text("07000000").unwrap_err(), "line 1: expected \"address name\"");
+        assert_eq!(Symbols::parse_text("\n07000000 TT main").unwrap_err(), "line 2: expected \"address name\"");
+        assert_eq!(Symbols::parse_text("main 07000000").unwrap_err(), "line 1: invalid address \"main\"");
+    }
+
+    #[test]
+    fn lookup() {
+        let mut symbols = Symbols::new();
+        symbols.insert(0x07000000, "main", 0x20);
+        symbols.insert(0x07000040, "label", 0);
+        symbols.insert(0x05000000, "buffer", 0);
+
+        assert_eq!(symbols.lookup(0x07000000), Some(("main", 0)));
+        assert_eq!(symbols.lookup(0x0700001F), Some(("main", 0x1F)));
+        assert_eq!(symbols.lookup(0x07000020), None); // Past the end of main
+        assert_eq!(symbols.lookup(0x070FFFFF), Some(("label", 0xFFFBF))); // No size, so up to the end of the region
+        assert_eq!(symbols.lookup(0xFFF00000), Some(("label", 0xEFFFC0))); // Mirror of ROM
+        assert_eq!(symbols.lookup(0x06000000), None); // buffer is the closest symbol, but in another region
+        assert_eq!(symbols.lookup(0x04FFFFFF), None); // Before every symbol
+
+        assert_eq!(symbols.describe(0xFF000012), Some("main+0x12".to_string()));
+        assert_eq!(symbols.describe(0xFFFFFFF0), Some("label+0xFFFFB0".to_string()));
+    }
+
+    #[test]
+    fn resolve() {
+        let mut symbols = Symbols::new();
+        symbols.insert(0x07000100, "main", 0);
+        symbols.insert(0x05000000, "beef", 0); // Also a valid hex number
+
+        assert_eq!(symbols.resolve("main"), Ok(0x07000100));
+        assert_eq!(symbols.resolve(" main + 0x12 "), Ok(0x07000112));
+        assert_eq!(symbols.resolve("main+12"), Ok(0x07000112));
+        assert_eq!(symbols.resolve("beef"), Ok(0x05000000));
+        assert_eq!(symbols.resolve("0xbeef"), Ok(0xBEEF));
+        assert_eq!(symbols.resolve("07000000"), Ok(0x07000000));
+        assert_eq!(symbols.resolve("main+zz"), Err("unknown symbol \"main+zz\"".to_string()));
+        assert_eq!(symbols.resolve("nothing"), Err("unknown symbol \"nothing\"".to_string()));
+    }
+}
@@ -1,20 +1,1019 @@
-use crate::bus::Bus;
+use crate::bus::{Bus, WatchHit, Watchpoint};
 use crate::cpu::Cpu;
+use crate::cpu::idle::IdleSkipper;
+use crate::cpu::instrs::decoder::{self, Op};
+use crate::cpu::trace::Tracer;
+use crate::symbols::Symbols;
+#[cfg(feature = "dynarec")]
+use crate::cpu::dynarec::Dynarec;
+use std::collections::BTreeSet;
+
+/*
+    Debugger API.
+
+    Breakpoints stop execution right before the instruction at their address runs, and watchpoints (see bus.rs)
+    right after the instruction that made the watched access. They're only checked by the debugger's run methods
+    (debug_run, step_into, step_over and run_until_return), which report why they stopped instead of panicking,
+    so that tools embedding the emulator can pause on them. Those methods step through everything one instruction at a time
+    in the interpreter, without idle skipping or the dynarec, so that nothing gets missed.
+
+    Continuing from the breakpoint the last run stopped at runs the instruction there instead of stopping at it again,
+    and so does stepping. A run that starts at a breakpoint it didn't stop at stops right away.
+    Stepping over a JAL runs until execution comes back to the instruction after it, with the stack pointer at or above where
+    it was, so that recursive calls returning to the same address don't stop it early.
+    Running until return counts calls (JAL) and returns (JMP [lp], or RETI) to stop after the return from the current function.
+
+    With a symbol map loaded (see symbols.rs), breakpoints can be set by name, and traces show where each instruction is.
+*/
+
+// Why one of the debugger's run methods stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(u32),      // Execution reached a breakpoint. The instruction at that address hasn't run yet
+    Watchpoint(WatchHit), // The last instruction made an access matching a watchpoint
+    Done,                 // The step, step over or run until return finished
+    OutOfCycles,          // Ran for the requested number of cycles without stopping
+    IllegalOpcode(u32),   // Strict mode stopped at an illegal opcode at this address. See set_strict_mode
+}
+
+#[derive(Clone, Copy)]
+enum Goal {
+    Continue,
+    StepInto,
+    StepOver { return_pc: u32, sp: u32 },
+    Return,
+}
 
 pub struct VirtualBoy {
     cpu: Cpu,
     bus: Bus,
+    idle_skipper: Option<IdleSkipper>,
+    tracer: Option<Tracer>,
+    symbols: Symbols,
+    breakpoints: BTreeSet<u32>,
+    stopped_at: Option<u32>, // Breakpoint the last debugger run stopped at
+    #[cfg(feature = "dynarec")]
+    dynarec: Option<Dynarec>,
 }
 
 impl VirtualBoy {
     pub fn new(rom_path: &str) -> VirtualBoy {
+        VirtualBoy::load(rom_path).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    // Same as new, but returns an error instead of panicking when the ROM can't be used.
+    // Takes a raw ROM image or an ELF file, whose symbols get loaded along with it
+    pub fn load(rom_path: &str) -> Result<VirtualBoy, String> {
+        Ok(VirtualBoy::from_bus(Bus::load(rom_path)?))
+    }
+
+    // For ROMs that don't come from a file, like assembled test programs (see Memory::from_rom)
+    pub fn from_bus(bus: Bus) -> VirtualBoy {
+        let symbols = bus.symbols().clone();
         VirtualBoy {
             cpu: Cpu::new(),
-            bus: Bus::new(rom_path),
+            bus,
+            idle_skipper: None,
+            tracer: None,
+            symbols,
+            breakpoints: BTreeSet::new(),
+            stopped_at: None,
+            #[cfg(feature = "dynarec")]
+            dynarec: None,
+        }
+    }
+
+    // In strict mode, illegal opcodes stop the emulator instead of raising an exception: run returns early,
+    // the debugger's run methods stop with IllegalOpcode, and illegal_opcode reports the address until strict mode is turned off
+    pub fn set_strict_mode(&mut self, strict: bool) {
+        self.cpu.strict_mode = strict;
+        if !strict {
+            self.cpu.illegal_opcode = None;
+        }
+    }
+
+    // Address of the illegal opcode strict mode stopped at, if it did
+    pub fn illegal_opcode(&self) -> Option<u32> {
+        self.cpu.illegal_opcode
+    }
+
+    // In cached interpreter mode, code is decoded once into basic blocks that get replayed, which is much faster.
+    // Results are identical to the regular interpreter
+    pub fn set_cached_interpreter(&mut self, cached: bool) {
+        self.cpu.cached_interpreter = cached;
+        self.cpu.blocks.clear();
+    }
+
+    // Skip over time spent halted or spinning in idle loops in run, instead of stepping through it.
+    // Results are identical to stepping through everything
+    pub fn set_idle_skipping(&mut self, enabled: bool) {
+        self.idle_skipper = if enabled { Some(IdleSkipper::new()) } else { None };
+    }
+
+    // Run code through the x86-64 dynamic recompiler instead of the interpreter
+    #[cfg(feature = "dynarec")]
+    pub fn set_dynarec(&mut self, enabled: bool) {
+        self.dynarec = if enabled { Some(Dynarec::new()) } else { None };
+    }
+
+    // Check the dynarec against the interpreter after every instruction, panicking on any difference. Needs the dynarec to be on
+    #[cfg(feature = "dynarec")]
+    pub fn set_dynarec_verification(&mut self, enabled: bool) {
+        let dynarec = self.dynarec.as_mut().expect("the dynarec is disabled");
+        dynarec.set_verification(&self.cpu, &self.bus, enabled);
+    }
+
+    #[cfg(feature = "dynarec")]
+    pub fn dynarec(&self) -> Option<&Dynarec> {
+        self.dynarec.as_ref()
+    }
+
+    // Log every instruction run from now on to a tracer (see cpu/trace.rs), or stop tracing with None.
+    // Returns the previous tracer. While tracing, the dynarec falls back to the interpreter and idle skipping is paused,
+    // so that no instruction is missed
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        if let Some(idle_skipper) = &mut self.idle_skipper {
+            idle_skipper.reset(); // It doesn't see what runs while tracing
+        }
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    // Replace the symbol map used for traces and breakpoints set by name, which starts out with the symbols of the ELF file
+    // the ROM was loaded from, if it was
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    // Run a single CPU instruction, or a whole block of them with the dynarec.
+    // Returns the number of CPU cycles (at 20MHz) taken
+    pub fn step(&mut self) -> u32 {
+        if self.tracer.is_some() {
+            return self.interpreter_step();
+        }
+
+        #[cfg(feature = "dynarec")]
+        if let Some(dynarec) = &mut self.dynarec {
+            return dynarec.step(&mut self.cpu, &mut self.bus);
+        }
+
+        self.cpu.step(&mut self.bus)
+    }
+
+    // Run a single instruction in the interpreter, even with the dynarec on, and trace it
+    fn interpreter_step(&mut self) -> u32 {
+        let before = self.tracer.as_ref().map(|_| self.cpu.regs.clone());
+
+        #[cfg(feature = "dynarec")]
+        let cycles = match &mut self.dynarec {
+            Some(dynarec) => dynarec.step_interpreter(&mut self.cpu, &mut self.bus),
+            None => self.cpu.step(&mut self.bus),
+        };
+        #[cfg(not(feature = "dynarec"))]
+        let cycles = self.cpu.step(&mut self.bus);
+
+        if let (Some(tracer), Some(before), Some((pc, instr))) = (&mut self.tracer, before, self.cpu.last_instr) {
+            tracer.log(pc, &instr, &before, &self.cpu.regs, &self.symbols);
+        }
+
+        cycles
+    }
+
+    // Run for at least "cycles" CPU cycles, which is the time left until the next hardware event.
+    // Instructions aren't split, so this can go over by the length of the last step. Returns the number of cycles run,
+    // which is less than requested if strict mode stopped at an illegal opcode
+    pub fn run(&mut self, cycles: u64) -> u64 {
+        let mut elapsed = 0;
+
+        while elapsed < cycles && self.cpu.illegal_opcode.is_none() {
+            let pc = self.cpu.regs.pc;
+            elapsed += self.step() as u64;
+
+            // Skipped instructions would be missing from the trace
+            if let (Some(idle_skipper), None) = (&mut self.idle_skipper, &self.tracer) {
+                elapsed = idle_skipper.fast_forward(&mut self.cpu, &self.bus, pc, elapsed, cycles);
+            }
+        }
+
+        elapsed
+    }
+}
+
+impl VirtualBoy {
+    // Returns false if there already was a breakpoint at "addr"
+    pub fn add_breakpoint(&mut self, addr: u32) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    // Returns false if there was no breakpoint at "addr"
+    pub fn remove_breakpoint(&mut self, addr: u32) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    // Add a breakpoint at a symbol, a symbol plus a hex offset ("update+0x1C") or a hex address. Returns its address
+    pub fn add_breakpoint_at(&mut self, location: &str) -> Result<u32, String> {
+        let addr = self.symbols.resolve(location)?;
+        self.breakpoints.insert(addr);
+        Ok(addr)
+    }
+
+    // Breakpoint addresses, in ascending order
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.bus.add_watchpoint(watchpoint);
+    }
+
+    // Returns false if there was no such watchpoint
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        self.bus.remove_watchpoint(watchpoint)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        self.bus.watchpoints()
+    }
+
+    // Run until a breakpoint or watchpoint is hit, for up to "cycles" cycles. Returns the cycles run and why it stopped
+    pub fn debug_run(&mut self, cycles: u64) -> (u64, Stop) {
+        self.run_until(Goal::Continue, cycles)
+    }
+
+    // Run a single instruction. Stops with Done, unless the instruction hit a watchpoint
+    pub fn step_into(&mut self) -> (u64, Stop) {
+        self.run_until(Goal::StepInto, u64::MAX)
+    }
+
+    // Like step_into, except that calls made with JAL get run through until they return
+    pub fn step_over(&mut self, cycles: u64) -> (u64, Stop) {
+        let pc = match self.next_instr_pc() {
+            Some(pc) => pc,
+            None => return self.step_into(),
+        };
+
+        let first = self.bus.peek16(pc);
+        let second = if decoder::instr_length(first) == 4 { self.bus.peek16(pc.wrapping_add(2)) } else { 0 };
+        let instr = decoder::decode(first, second);
+        if instr.op != Op::Jal {
+            return self.step_into();
+        }
+
+        let goal = Goal::StepOver { return_pc: pc.wrapping_add(instr.len), sp: self.cpu.regs.gprs[3] };
+        self.run_until(goal, cycles)
+    }
+
+    // Run until the current function returns to its caller
+    pub fn run_until_return(&mut self, cycles: u64) -> (u64, Stop) {
+        self.run_until(Goal::Return, cycles)
+    }
+
+    fn run_until(&mut self, goal: Goal, cycles: u64) -> (u64, Stop) {
+        let mut elapsed = 0;
+        let mut depth = 0; // Calls and exceptions entered minus returns from them, when running until return
+        let mut first = true;
+        let resuming_from = self.stopped_at.take();
+
+        loop {
+            let handling = exception_level(&self.cpu);
+            let pc = self.next_instr_pc();
+            if exception_level(&self.cpu) > handling {
+                depth += 1; // Took an interrupt
+            }
+
+            // Steps and continuing from a breakpoint always run their first instruction
+            let skip = first && (!matches!(goal, Goal::Continue) || pc == resuming_from);
+            match pc {
+                Some(pc) if !skip && self.breakpoints.contains(&pc) => {
+                    self.stopped_at = Some(pc);
+                    return (elapsed, Stop::Breakpoint(pc));
+                }
+                _ => first = false,
+            }
+
+            let handling = exception_level(&self.cpu);
+            self.bus.take_watch_hit(); // Forget about accesses made outside of instructions
+            elapsed += self.interpreter_step() as u64;
+
+            if let Some(hit) = self.bus.take_watch_hit() {
+                return (elapsed, Stop::Watchpoint(hit));
+            }
+            if let Some(pc) = self.cpu.illegal_opcode {
+                return (elapsed, Stop::IllegalOpcode(pc));
+            }
+
+            match self.cpu.last_instr {
+                Some((_, instr)) if instr.op == Op::Jal => depth += 1,
+                Some((_, instr)) if (instr.op == Op::Jmp && instr.reg1 == 31) || instr.op == Op::Reti => depth -= 1,
+                _ => {}
+            }
+            if exception_level(&self.cpu) > handling {
+                depth += 1; // The instruction raised an exception
+            }
+
+            let done = match goal {
+                Goal::Continue => false,
+                Goal::StepInto => true,
+                Goal::StepOver { return_pc, sp } => self.cpu.regs.pc == return_pc && self.cpu.regs.gprs[3] >= sp,
+                Goal::Return => depth < 0,
+            };
+
+            if done {
+                return (elapsed, Stop::Done);
+            }
+            if elapsed >= cycles {
+                return (elapsed, Stop::OutOfCycles);
+            }
+        }
+    }
+
+    // Take whatever interrupt or address trap the next step would take before running an instruction,
+    // so that the PC holds the address of the next instruction to run. Returns None if the CPU is halted.
+    // Stepping afterwards does the same thing it would have done without this
+    fn next_instr_pc(&mut self) -> Option<u32> {
+        self.cpu.check_interrupts();
+        if self.cpu.halted {
+            return None;
         }
+
+        self.cpu.check_address_trap(&mut self.bus);
+        Some(self.cpu.regs.pc)
     }
+}
+
+// How many levels of exception handling the CPU is in: none, an exception or interrupt (PSW.EP), or a duplexed one (PSW.NP)
+fn exception_level(cpu: &Cpu) -> u32 {
+    cpu.regs.psw.exception_pending() as u32 + cpu.regs.psw.nmi_pending() as u32
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::bus::WatchKind;
+    use crate::cpu::exceptions;
+    use crate::cpu::instrs::assembler;
+    use crate::mem::Memory;
+
+    // Assemble a program starting at "main", with a reset vector jumping to it, and its labels as symbols.
+    // Used by the tests of other modules too
+    pub(crate) fn assemble(source: &str) -> VirtualBoy {
+        let source = format!("{}\n.org 0xFFFFFFF0\n    mov main, r1\n    jmp [r1]\n", source);
+        let assembly = assembler::assemble(&source).unwrap_or_else(|error| panic!("{}", error));
+        let rom = assembly.to_rom().unwrap_or_else(|error| panic!("{}", error));
+
+        let mut vb = VirtualBoy::from_bus(Bus::from_memory(Memory::from_rom(rom).unwrap()));
+        let mut symbols = Symbols::new();
+        for (name, &addr) in &assembly.labels {
+            symbols.insert(addr, name, 0);
+        }
+        vb.set_symbols(symbols);
+        vb
+    }
+
+    // Address of a label of the program
+    fn label(vb: &VirtualBoy, name: &str) -> u32 {
+        vb.symbols().address(name).unwrap()
+    }
+
+    const ILLEGAL: &str = "
+        .org 0x07000000
+        main:
+            ldsr r0, psw ; Clear PSW.NP, so that exceptions aren't fatal
+            mov 1, r10
+            .dh 0x6C00 ; Reserved opcode
+            mov 2, r10
+        loop:
+            br loop
+    ";
+
+    #[test]
+    fn strict_mode_stops_at_illegal_opcodes() {
+        let mut vb = assemble(ILLEGAL);
+        vb.set_strict_mode(true);
+
+        assert!(vb.run(1000) < 1000);
+        assert_eq!(vb.illegal_opcode(), Some(0x07000004));
+        assert_eq!(vb.cpu().regs.pc, 0x07000004);
+        assert_eq!(vb.cpu().regs.gprs[10], 1);
+        assert_eq!(vb.debug_run(1000), (1, Stop::IllegalOpcode(0x07000004)));
+
+        // Without strict mode, the opcode raises an exception instead
+        vb.set_strict_mode(false);
+        assert_eq!(vb.illegal_opcode(), None);
+        vb.step();
+        assert_eq!(vb.cpu().regs.pc, 0xFFFFFF90);
+        assert_eq!(vb.cpu().regs.eipc, 0x07000004);
+    }
+
+    #[test]
+    fn interrupt_levels_are_masked() {
+        let mut vb = assemble("
+            .org 0x07000000
+            main:
+                mov 0x20000, r1 ; PSW.I = 2
+                ldsr r1, psw
+            loop:
+                add 1, r10
+                br loop
+
+            .org 0xFFFFFE10 ; Timer (level 1)
+                add 1, r20
+                reti
+            .org 0xFFFFFE30 ; Communication (level 3)
+                add 1, r21
+                reti
+            .org 0xFFFFFE40 ; VIP (level 4)
+                add 1, r22
+                reti
+        ");
+        vb.run(100);
+        vb.cpu_mut().request_irq(exceptions::irq_levels::TIMER);
+        vb.run(100);
+        assert_eq!(vb.cpu().regs.gprs[20], 0); // Below PSW.I
+
+        // The interrupt gets taken before the next instruction, which is the first one of its handler
+        vb.cpu_mut().request_irq(exceptions::irq_levels::VIP);
+        let pc = vb.cpu().regs.pc;
+        vb.step();
+        let regs = &vb.cpu().regs;
+        assert_eq!((regs.pc, regs.gprs[22]), (0xFFFFFE42, 1));
+        assert_eq!(regs.ecr & 0xFFFF, 0xFE40);
+        assert_eq!(regs.eipc, pc);
+        assert_eq!(regs.eipsw >> 16, 2);
+        assert_eq!(regs.psw.i(), 5);
+        assert!(regs.psw.exception_pending() && regs.psw.irqs_disabled());
+
+        // Nothing else gets in while it's being handled, and the previous level comes back with RETI
+        vb.cpu_mut().clear_irq(exceptions::irq_levels::VIP);
+        vb.cpu_mut().request_irq(exceptions::irq_levels::COMMUNICATION);
+        vb.step();
+        assert_eq!(vb.cpu().regs.pc, pc);
+        assert_eq!(vb.cpu().regs.psw.i(), 2);
+
+        vb.step();
+        let regs = &vb.cpu().regs;
+        assert_eq!((regs.pc, regs.gprs[21]), (0xFFFFFE32, 1));
+        assert_eq!(regs.psw.i(), 4);
+        assert_eq!(regs.gprs[20], 0);
+    }
+
+    // A zero division, another one in its handler (duplexed), and a third one in the duplexed exception handler (fatal)
+    #[test]
+    fn duplexed_and_fatal_exceptions() {
+        let mut vb = assemble("
+            .org 0x07000000
+            main:
+                ldsr r0, psw
+                mov 0, r1
+                mov 7, r2
+            divide:
+                div r1, r2
+                halt
+
+            .org 0xFFFFFF80 ; Zero division
+                div r1, r2
+            .org 0xFFFFFFD0 ; Duplexed exception
+                div r1, r2
+        ");
+        let divide = label(&vb, "divide");
+
+        while vb.cpu().regs.pc != divide {
+            vb.step();
+        }
+        vb.step();
+        let regs = &vb.cpu().regs;
+        assert_eq!(regs.pc, 0xFFFFFF80);
+        assert_eq!(regs.ecr, 0x0000FF80);
+        assert_eq!(regs.eipc, divide);
+        assert_eq!(regs.gprs[2], 7); // Left untouched
+        assert!(regs.psw.exception_pending());
+
+        vb.step();
+        let regs = &vb.cpu().regs;
+        assert_eq!(regs.pc, 0xFFFFFFD0);
+        assert_eq!(regs.ecr, 0xFF80FF80);
+        assert_eq!(regs.fepc, 0xFFFFFF80);
+        assert_eq!(regs.eipc, divide);
+        assert!(regs.psw.nmi_pending());
+        let psw = regs.psw.raw();
+
+        // Fatal: the CPU stops, after dumping the exception code, PSW and PC at address 0
+        vb.step();
+        assert!(vb.cpu().halted);
+        let dump: Vec<u32> = (0..3).map(|index| vb.bus().peek32(index * 4)).collect();
+        assert_eq!(dump, [0xFFFFFF80, psw, 0xFFFFFFD0]);
+        assert_eq!(vb.run(1000), 1000);
+        assert!(vb.cpu().halted);
+    }
+
+    #[test]
+    fn address_trap() {
+        let mut vb = assemble("
+            .org 0x07000000
+            main:
+                mov target, r1
+                ldsr r1, adtre
+                mov 0x2000, r1 ; PSW.AE
+                ldsr r1, psw
+                mov 1, r10
+            target:
+                add 1, r11
+                halt
+
+            .org 0xFFFFFFC0 ; Address trap
+                add 1, r12
+                stsr psw, r13
+                stsr eipsw, r1 ; Clear AE on the way back so that the trapped instruction can run
+                mov 0xFFFFDFFF, r2
+                and r2, r1
+                ldsr r1, eipsw
+                reti
+        ");
+
+        run_to_halt(&mut vb);
+        let regs = &vb.cpu().regs;
+        assert_eq!(regs.gprs[10..=12], [1, 1, 1]);
+        assert_eq!(regs.gprs[13] & 0x2000, 0); // Taking the trap clears AE
+        assert_eq!(regs.eipc, label(&vb, "target"));
+        assert_eq!(regs.ecr & 0xFFFF, 0xFFC0);
+    }
+
+    const CALLS: &str = "
+        .org 0x07000000
+        main:
+            ldsr r0, psw
+            mov 0x0500F000, sp
+            mov 0x05000000, r20
+        call:
+            jal outer
+        after_call:
+            halt
+
+        outer:
+            add -4, sp
+            st.w lp, 0[sp]
+            jal inner
+        between:
+            jal inner
+            ld.w 0[sp], lp
+            add 4, sp
+            jmp [lp]
+
+        inner:
+            add 1, r10
+            st.w r10, 0[r20]
+            jmp [lp]
+    ";
+
+    #[test]
+    fn step_over_runs_calls_through() {
+        let mut vb = assemble(CALLS);
+        let call = vb.add_breakpoint_at("call").unwrap();
+        assert_eq!(vb.debug_run(1000).1, Stop::Breakpoint(call));
+
+        assert_eq!(vb.step_over(1000).1, Stop::Done);
+        assert_eq!(vb.cpu().regs.pc, label(&vb, "after_call"));
+        assert_eq!(vb.cpu().regs.gprs[10], 2);
+
+        // Anything besides JAL is a single step
+        assert_eq!(vb.step_over(1000).1, Stop::Done);
+        assert!(vb.cpu().halted);
+    }
+
+    #[test]
+    fn run_until_return_skips_nested_calls() {
+        let mut vb = assemble(CALLS);
+        let outer = vb.add_breakpoint_at("outer").unwrap();
+        let inner = vb.add_breakpoint_at("inner").unwrap();
+        assert_eq!(vb.debug_run(1000).1, Stop::Breakpoint(outer));
+        assert_eq!(vb.debug_run(1000).1, Stop::Breakpoint(inner));
+
+        // Back in outer, right after the first call
+        assert_eq!(vb.run_until_return(1000).1, Stop::Done);
+        assert_eq!(vb.cpu().regs.pc, label(&vb, "between"));
+        assert_eq!(vb.cpu().regs.gprs[10], 1);
+
+        // outer returns after calling inner again, which doesn't count as returning from outer
+        vb.remove_breakpoint(inner);
+        assert_eq!(vb.run_until_return(1000).1, Stop::Done);
+        assert_eq!(vb.cpu().regs.pc, label(&vb, "after_call"));
+        assert_eq!(vb.cpu().regs.gprs[10], 2);
+    }
+
+    #[test]
+    fn watchpoints_can_wait_for_a_value() {
+        let mut vb = assemble(CALLS);
+        let watchpoint = Watchpoint { start: 0x05000000, end: 0x05000003, kind: WatchKind::Write, value: Some(2) };
+        vb.add_watchpoint(watchpoint);
+
+        // The first write stores 1, which doesn't match
+        let hit = match vb.debug_run(1000).1 {
+            Stop::Watchpoint(hit) => hit,
+            stop => panic!("stopped with {:?}", stop),
+        };
+        assert_eq!(hit, WatchHit { watchpoint, addr: 0x05000000, size: 4, value: 2, write: true });
+        assert_eq!(vb.cpu().regs.gprs[10], 2);
+        assert_eq!(vb.cpu().last_instr.unwrap().0, label(&vb, "inner") + 2); // Right after the store
+
+        assert!(vb.remove_watchpoint(&watchpoint));
+        assert_eq!(vb.debug_run(1000).1, Stop::OutOfCycles);
+        assert!(vb.cpu().halted);
+    }
+
+    #[cfg(feature = "dynarec")]
+    #[test]
+    fn strict_mode_stops_the_dynarec() {
+        let mut vb = assemble(ILLEGAL);
+        vb.set_strict_mode(true);
+        vb.set_dynarec(true);
+
+        assert!(vb.run(1000) < 1000);
+        assert_eq!(vb.illegal_opcode(), Some(0x07000004));
+        assert_eq!(vb.cpu().regs.pc, 0x07000004);
+        assert_eq!(vb.cpu().regs.gprs[10], 1);
+    }
+
+    const SELFTEST: &str = include_str!("../tests/programs/selftest.s");
+
+    // Returns the cycles taken
+    fn run_to_halt(vb: &mut VirtualBoy) -> u64 {
+        let mut cycles = 0;
+        while !vb.cpu().halted {
+            cycles += vb.step() as u64;
+            assert!(cycles < 1_000_000, "the program didn't finish");
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_program_passes() {
+        let mut vb = assemble(SELFTEST);
+        run_to_halt(&mut vb);
+        assert_eq!(vb.cpu().regs.gprs[11], 29); // Checks run
+        assert_eq!(vb.cpu().regs.gprs[10], 0); // Checks failed
+    }
+
+    // Integer, load/store and branch heavy loop running from ROM, with the instruction cache on
+    #[cfg(feature = "dynarec")]
+    const ROM_LOOP: &str = "
+        .org 0x07000000
+        main:
+            ldsr r0, psw
+            mov 2, r1
+            ldsr r1, chcw ; Enable the instruction cache
+            mov 0x05000000, r20
+            mov 0, r10
+            mov 1, r11
+            mov 0x12345678, r12
+            mov 40, r18
+        loop:
+            add r11, r10
+            xor r11, r12
+            shl 3, r12
+            sar 1, r12
+            mul r11, r12
+            setf 6, r13
+            movhi 0x1234, r11, r14
+            ori 0xFF, r14, r14
+            andi 0xF0F0, r14, r15
+            not r15, r16
+            addi -5, r16, r16
+            sub r11, r16
+            st.w r10, 0[r20]
+            st.h r12, 4[r20]
+            st.b r13, 6[r20]
+            ld.b 4[r20], r17
+            ld.w 0[r20], r19
+            add r17, r19
+            add 8, r20
+            add 1, r11
+            cmp r18, r11
+            blt loop
+            mov 7, r21
+            div r21, r10
+            divu r21, r12
+            jal leaf
+            halt
+        leaf:
+            mov 3, r22
+            jmp [lp]
+    ";
+
+    // Code copied to WRAM with a bit string instruction, patched while the instruction cache is off and then while it's on
+    // (which leaves stale code in the cache), and some floating point
+    const WRAM_CODE: &str = "
+        .org 0x07000000
+        main:
+            ldsr r0, psw
+            mov routine, r30
+            mov 0x05000100, r29
+            mov routine_end - routine, r28
+            shl 3, r28
+            mov 0, r27
+            mov 0, r26
+            movbsu
+            mov 0, r10
+            mov 0, r11
+            mov 5, r18
+        call_uncached:
+            jal 0x05000100
+            add -1, r18
+            bne call_uncached
+            mov patch, r24
+            ld.h 0[r24], r1
+            mov 0x05000100, r25
+            st.h r1, 0[r25]
+            jal 0x05000100
+
+            mov 2, r1
+            ldsr r1, chcw
+            mov 5, r18
+        call_cached:
+            jal 0x05000100
+            add -1, r18
+            bne call_cached
+            ld.h 2[r24], r1
+            st.h r1, 0[r25]
+            jal 0x05000100
+            ldsr r0, chcw
+            jal 0x05000100
+
+            mov 3, r1
+            cvt.ws r1, r2
+            mov 2, r3
+            cvt.ws r3, r4
+            divf.s r4, r2
+            mulf.s r2, r2
+            trnc.sw r2, r5
+            cmpf.s r4, r2
+            setf 4, r6
+            halt
+
+        .org 0x07000200
+        routine:
+            add 1, r10
+            shl 1, r10
+            xor r10, r11
+            jmp [lp]
+        routine_end:
+        patch:
+            add 5, r10
+            add -3, r10
+    ";
+
+    // Runs a program to its HALT with the regular and the cached interpreter, which have to agree on everything, timing included.
+    // Returns the cached one
+    fn check_cached_interpreter(source: &str) -> VirtualBoy {
+        let mut interpreter = assemble(source);
+        let mut cycles = 0;
+        let mut instrs = 0;
+        while !interpreter.cpu().halted {
+            cycles += interpreter.step() as u64;
+            instrs += interpreter.cpu().last_instr.is_some() as u64;
+        }
+
+        let mut cached = assemble(source);
+        cached.set_cached_interpreter(true);
+
+        assert_eq!(run_to_halt(&mut cached), cycles);
+        assert_eq!(cached.cpu().regs, interpreter.cpu().regs);
+        assert!(cached.bus().same_memory(interpreter.bus()));
+        let cache_stats = |vb: &VirtualBoy| (vb.cpu().cache.hits, vb.cpu().cache.misses);
+        assert_eq!(cache_stats(&cached), cache_stats(&interpreter));
+
+        // Every instruction ran from ROM or WRAM, so every one of them came from a block
+        assert_eq!(cached.cpu().blocks.hits, instrs);
+        assert_eq!(interpreter.cpu().blocks.hits + interpreter.cpu().blocks.misses, 0);
+        cached
+    }
+
+    #[test]
+    fn cached_interpreter_matches_interpreter() {
+        let vb = check_cached_interpreter(SELFTEST);
+        let blocks = &vb.cpu().blocks;
+        assert!(blocks.misses > 0 && blocks.hits > 10 * blocks.misses); // Blocks got replayed
+    }
+
+    #[test]
+    fn cached_interpreter_runs_modified_code() {
+        let vb = check_cached_interpreter(WRAM_CODE);
+
+        // The routine in WRAM adds 1 to r10 five times, then 5 seven times after the first patch, the last of them from
+        // the stale instruction cache after the second patch, and then -3 once the cache is off
+        let (mut r10, mut r11) = (0u32, 0u32);
+        for &add in [1; 5].iter().chain(&[5; 7]).chain(&[-3]) {
+            r10 = r10.wrapping_add(add as u32) << 1;
+            r11 ^= r10;
+        }
+        assert_eq!(vb.cpu().regs.gprs[10..=11], [r10, r11]);
+    }
+
+    // Run a program to its HALT with the interpreter, and with the dynarec on its own and checked against an interpreter after
+    // every instruction. Returns the interpreter
+    #[cfg(feature = "dynarec")]
+    fn check_dynarec(source: &str) -> VirtualBoy {
+        let mut interpreter = assemble(source);
+        let cycles = run_to_halt(&mut interpreter);
+
+        for verification in [false, true] {
+            let mut dynarec = assemble(source);
+            dynarec.set_dynarec(true);
+            dynarec.set_dynarec_verification(verification);
+
+            assert_eq!(run_to_halt(&mut dynarec), cycles);
+            assert_eq!(dynarec.cpu().regs, interpreter.cpu().regs);
+            assert!(dynarec.bus().same_memory(interpreter.bus()));
+            assert!(dynarec.dynarec().unwrap().native_steps > 0);
+        }
+        interpreter
+    }
+
+    #[cfg(feature = "dynarec")]
+    #[test]
+    fn dynarec_matches_interpreter_in_rom() {
+        check_dynarec(ROM_LOOP);
+    }
+
+    #[cfg(feature = "dynarec")]
+    #[test]
+    fn dynarec_matches_interpreter_in_wram() {
+        check_dynarec(WRAM_CODE);
+    }
+
+    #[cfg(feature = "dynarec")]
+    #[test]
+    fn dynarec_matches_interpreter_on_test_program() {
+        check_dynarec(SELFTEST);
+    }
+
+    // Logic operations keep the carry of an addition or subtraction before them, even while its flags are still pending
+    #[cfg(feature = "dynarec")]
+    #[test]
+    fn dynarec_logic_ops_keep_pending_carry() {
+        let vb = check_dynarec("
+            .org 0x07000000
+            main:
+                ldsr r0, psw
+                mov -1, r1
+                mov 1, r2
+                add r2, r1 ; Carry
+                and r2, r1
+                setf 1, r10 ; C
+                mov -1, r3
+                add 1, r3
+                ori 0, r3, r4
+                not r4, r5
+                bnc no_carry
+                mov 1, r11
+            no_carry:
+                cmp r2, r0 ; Borrow
+                xor r2, r2
+                setf 1, r12
+                halt
+        ");
+        assert_eq!(vb.cpu().regs.gprs[10..=12], [1, 1, 1]);
+    }
+
+    // Runs a scenario with idle skipping on and off, and checks that both end up in the same state after the same number of cycles.
+    // Done with both the plain and the cached interpreter
+    fn check_idle_skipping(source: &str, scenario: impl Fn(&mut VirtualBoy) -> u64) {
+        for cached in [false, true] {
+            let mut stepped = assemble(source);
+            let mut skipped = assemble(source);
+            stepped.set_cached_interpreter(cached);
+            skipped.set_cached_interpreter(cached);
+            skipped.set_idle_skipping(true);
+
+            assert_eq!(scenario(&mut skipped), scenario(&mut stepped));
+            assert_eq!(skipped.cpu().regs, stepped.cpu().regs);
+            assert_eq!(skipped.cpu().halted, stepped.cpu().halted);
+            assert!(skipped.bus().same_memory(stepped.bus()));
+
+            let cache_stats = |vb: &VirtualBoy| (vb.cpu().cache.hits, vb.cpu().cache.misses);
+            let block_stats = |vb: &VirtualBoy| (vb.cpu().blocks.hits, vb.cpu().blocks.misses);
+            assert_eq!(cache_stats(&skipped), cache_stats(&stepped));
+            assert_eq!(block_stats(&skipped), block_stats(&stepped));
+            assert!(skipped.idle_skipper.as_ref().unwrap().skipped_cycles > 40_000);
+        }
+    }
+
+    #[test]
+    fn idle_skipping_halt() {
+        let source = "
+            .org 0x07000000
+            main:
+                ldsr r0, psw ; Enable interrupts
+                mov 2, r1
+                ldsr r1, chcw
+                halt
+                add 1, r11
+                halt
+
+            .org 0xFFFFFE10 ; Timer interrupt handler
+                add 1, r10
+                reti
+        ";
+
+        check_idle_skipping(source, |vb| {
+            let mut cycles = vb.run(50_000);
+            vb.cpu_mut().request_irq(exceptions::irq_levels::TIMER);
+            cycles += vb.step() as u64;
+            vb.cpu_mut().clear_irq(exceptions::irq_levels::TIMER);
+            cycles += vb.run(50_000);
+
+            assert_eq!(vb.cpu().regs.gprs[10..=11], [1, 1]);
+            cycles
+        });
+    }
+
+    const POLLING_LOOP: &str = "
+        .org 0x07000000
+        main:
+            ldsr r0, psw
+            mov 2, r1
+            ldsr r1, chcw
+            mov 0x05000000, r20
+        wait:
+            ld.w 0[r20], r1
+            cmp r0, r1
+            be wait
+        count: ; Not an idle loop, as it writes memory
+            add r1, r10
+            st.w r10, 4[r20]
+            br count
+    ";
+
+    #[test]
+    fn idle_skipping_polling_loop() {
+        check_idle_skipping(POLLING_LOOP, |vb| {
+            let mut cycles = vb.run(50_000);
+            vb.bus_mut().write32(0x05000000, 7);
+            cycles += vb.run(1000);
+
+            assert_ne!(vb.cpu().regs.gprs[10], 0);
+            cycles
+        });
+    }
+
+    // Every instruction shows up in the trace, even in idle loops
+    #[test]
+    fn tracing_pauses_idle_skipping() {
+        let trace = |skipping: bool| {
+            let mut vb = assemble(POLLING_LOOP);
+            vb.set_idle_skipping(skipping);
+            vb.set_tracer(Some(Tracer::ring_buffer(1)));
+            let cycles = vb.run(5000);
+            (cycles, vb.tracer().unwrap().count(), vb.cpu().regs.clone())
+        };
+
+        assert_eq!(trace(true), trace(false));
+        assert!(trace(true).1 > 500);
+    }
+
+    #[test]
+    fn unmapped_accesses_are_harmless() {
+        let mut vb = assemble("
+            .org 0x07000000
+            main:
+                mov 0x03000000, r10
+                ld.w 0[r10], r11
+                mov 0x04000000, r10
+                ld.h 0[r10], r12
+                mov 0x06000000, r10
+                st.b r0, 0[r10]
+                ld.b 0[r10], r13
+                mov main, r10
+                st.w r0, 0[r10]
+                ld.w 0[r10], r14
+                halt
+        ");
 
-    pub fn step(&mut self) {
-        self.cpu.step(&mut self.bus);
+        run_to_halt(&mut vb);
+        assert_eq!(vb.cpu().regs.gprs[11..=13], [0xFFFFFFFF; 3]); // Open bus
+        assert_eq!(vb.cpu().regs.gprs[14], vb.bus().peek32(0x07000000)); // ROM can't be written
+        assert_ne!(vb.cpu().regs.gprs[14], 0);
     }
 }
@@ -0,0 +1,253 @@
+; Self-checking test program, run by the emulator's unit tests (see src/vb.rs).
+; Works a few things out and compares them with the expected results.
+; Ends in HALT with the number of checks in r11 and the number of failed ones in r10.
+
+.org 0x07000000
+main:
+    ldsr r0, psw
+    mov 0x0500F000, sp
+    mov 0, r10
+    mov 0, r11
+
+    ; 10!, recursively
+    mov 10, r6
+    jal factorial
+    mov 3628800, r7
+    jal check
+
+    ; 1 + 2 + ... + 100, in a loop
+    mov 0, r6
+    mov 100, r8
+sum_loop:
+    add r8, r6
+    add -1, r8
+    bne sum_loop
+    mov 5050, r7
+    jal check
+
+    ; Unsigned and signed division
+    mov 1000, r6
+    mov 7, r8
+    divu r8, r6
+    mov 142, r7
+    jal check
+    mov -1000, r6
+    div r8, r6
+    mov -142, r7
+    jal check
+
+    ; Copy the message to WRAM with a bit string instruction, and compare the copy
+    mov message, r30
+    mov 0x05000000, r29
+    mov message_end - message, r28
+    shl 3, r28
+    mov 0, r27
+    mov 0, r26
+    movbsu
+    mov 0x05000000, r20
+    mov message, r21
+    ld.w 0[r20], r6
+    ld.w 0[r21], r7
+    jal check
+    ld.w 4[r20], r6
+    ld.w 4[r21], r7
+    jal check
+
+    ; 3 / 2 * 4 in floating point
+    mov 3, r1
+    cvt.ws r1, r6
+    mov 2, r1
+    cvt.ws r1, r2
+    divf.s r2, r6
+    mov 4, r1
+    cvt.ws r1, r2
+    mulf.s r2, r6
+    trnc.sw r6, r6
+    mov 6, r7
+    jal check
+
+    ; 0x80000000 / -1 overflows: the quotient stays 0x80000000 with no remainder, and OV and S get set
+    mov 0x80000000, r6
+    mov -1, r8
+    mov 1, r30
+    div r8, r6
+    stsr psw, r9
+    mov 0x80000000, r7
+    jal check
+    mov r30, r6
+    mov 0, r7
+    jal check
+    andi 0xF, r9, r6
+    mov 6, r7 ; S and OV
+    jal check
+
+    ; 0x10000 * 0x10000 doesn't fit in 32 bits: the high word goes to r30 and OV gets set, along with Z for the low word
+    mov 0x10000, r6
+    mov 0x10000, r8
+    mul r8, r6
+    stsr psw, r9
+    mov r30, r6
+    mov 1, r7
+    jal check
+    andi 0xF, r9, r6
+    mov 5, r7 ; Z and OV
+    jal check
+
+    ; -3 * 5 fits, even though r30 gets the sign extension
+    mov -3, r6
+    mov 5, r8
+    mul r8, r6
+    stsr psw, r9
+    mov r30, r6
+    mov -1, r7
+    jal check
+    andi 0xF, r9, r6
+    mov 2, r7 ; S only
+    jal check
+
+    ; Reserved PSW bits read as 0, and PIR and TKCW can't be written
+    mov -1, r1
+    ldsr r1, psw
+    stsr psw, r6
+    ldsr r0, psw
+    mov 0x000FF3FF, r7
+    jal check
+    ldsr r1, pir
+    stsr pir, r6
+    mov 0x5346, r7
+    jal check
+    ldsr r1, tkcw
+    stsr tkcw, r6
+    mov 0xE0, r7
+    jal check
+
+    ; IN zero extends, where LD sign extends
+    mov 0x05000100, r20
+    mov 0xFFFF8080, r1
+    st.w r1, 0[r20]
+    in.b 0[r20], r6
+    mov 0x80, r7
+    jal check
+    in.h 0[r20], r6
+    mov 0x8080, r7
+    jal check
+    ld.h 0[r20], r6
+    mov 0xFFFF8080, r7
+    jal check
+
+    ; CAXI stores r30 when the word matches reg2, and leaves it alone otherwise. Either way, reg2 gets the old word
+    mov 5, r1
+    st.w r1, 0[r20]
+    mov 5, r8
+    mov 9, r30
+    caxi 0[r20], r8
+    stsr psw, r9
+    ld.w 0[r20], r6
+    mov 9, r7
+    jal check
+    mov r8, r6
+    mov 5, r7
+    jal check
+    andi 0xF, r9, r6
+    mov 1, r7 ; Z
+    jal check
+    mov 5, r8
+    mov 7, r30
+    caxi 0[r20], r8
+    stsr psw, r9
+    ld.w 0[r20], r6
+    mov 9, r7
+    jal check
+    mov r8, r6
+    jal check
+    andi 0xF, r9, r6
+    mov 0xA, r7 ; CY and S, from 5 - 9
+    jal check
+
+    ; Dumping the instruction cache, clearing it and restoring the dump gives back the same cache
+    mov 2, r1
+    ldsr r1, chcw ; Enable the cache, and run some code through it
+    mov 5, r6
+    jal factorial
+    mov 0x05002010, r1 ; Dump to 0x05002000, which also turns the cache off so that fetches leave it alone from now on
+    ldsr r1, chcw
+    mov 0x00008001, r1 ; Clear all 128 entries
+    ldsr r1, chcw
+    mov 0x05003010, r1 ; Dump the cleared cache to 0x05003000
+    ldsr r1, chcw
+    mov 0x05002020, r1 ; Restore the first dump
+    ldsr r1, chcw
+    mov 0x05004010, r1 ; And dump it again to 0x05004000
+    ldsr r1, chcw
+
+    mov 0x05002000, r20
+    mov 0, r6 ; Words that differ between the first and the last dump
+    mov 0x180, r12
+compare_dumps:
+    ld.w 0[r20], r1
+    ld.w 0x2000[r20], r2
+    cmp r1, r2
+    be same_word
+    add 1, r6
+same_word:
+    add 4, r20
+    add -1, r12
+    bne compare_dumps
+    mov 0, r7
+    jal check
+
+    mov 0x05002400, r20 ; Tags, with the valid bits of both words of each entry in bits 22 and 23
+    mov 0, r8
+    mov 0, r9
+    mov 128, r12
+valid_bits:
+    ld.w 0[r20], r1
+    or r1, r8
+    ld.w 0x1000[r20], r1
+    or r1, r9
+    add 4, r20
+    add -1, r12
+    bne valid_bits
+    mov 0x00C00000, r1
+    and r1, r8
+    and r1, r9
+    mov r8, r6
+    mov 0x00C00000, r7 ; The cache was in use
+    jal check
+    mov r9, r6
+    mov 0, r7 ; And it was cleared
+    jal check
+
+    halt
+
+; r6 = r6!
+factorial:
+    cmp 1, r6
+    bgt recurse
+    mov 1, r6
+    jmp [lp]
+recurse:
+    add -8, sp
+    st.w lp, 0[sp]
+    st.w r6, 4[sp]
+    add -1, r6
+    jal factorial
+    ld.w 4[sp], r7
+    mul r7, r6
+    ld.w 0[sp], lp
+    add 8, sp
+    jmp [lp]
+
+; Counts a check, which fails if r6 and r7 differ
+check:
+    add 1, r11
+    cmp r7, r6
+    be passed
+    add 1, r10
+passed:
+    jmp [lp]
+
+.org 0x07000800 ; Bit strings start at word boundaries
+message:
+    .dw 0x6C6C6548, 0x3856206F ; "Hello V8"
+message_end: